@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+// Compares two assembly programs modulo label names, comments and whitespace.
+// Jumps are equivalent when their labels mark corresponding instructions of
+// the two programs, so only genuinely different code is reported.
+
+pub struct Instruction {
+    pub line: usize,
+    pub text: String,
+}
+
+// An assembly program without comments and whitespace
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    // Index of the instruction each label marks
+    pub labels: HashMap<String, usize>,
+}
+
+impl Program {
+    pub fn parse(source: &str) -> Self {
+        let mut instructions = vec![];
+        let mut labels = HashMap::new();
+        for (line_num, line) in source.lines().enumerate() {
+            let code = line.split_once("//").map_or(line, |(code, _)| code);
+            let text: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+            if text.is_empty() {
+                continue;
+            }
+            match text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+                Some(label) => {
+                    labels.insert(label.to_owned(), instructions.len());
+                }
+                None => instructions.push(Instruction {
+                    line: line_num + 1,
+                    text,
+                }),
+            }
+        }
+        Self {
+            instructions,
+            labels,
+        }
+    }
+
+    // The instruction an A-instruction loading a label refers to
+    pub fn target(&self, i: usize) -> Option<usize> {
+        let symbol = self.instructions[i].text.strip_prefix('@')?;
+        self.labels.get(symbol).copied()
+    }
+
+    // What must be equal for instructions to correspond: label loads only
+    // have to be label loads, and their targets are compared afterwards
+    fn key(&self, i: usize) -> &str {
+        match self.target(i) {
+            Some(_) => "@(label)",
+            None => &self.instructions[i].text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+// The shortest edit script turning a into b, by Myers' algorithm
+fn shortest_edit(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let offset = n + m + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    let mut trace = vec![];
+    'search: for d in 0..=n + m {
+        // Only the diagonals the step can reach are kept
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+    // Walk back through the furthest points of every step
+    let (mut x, mut y) = (n, m);
+    let mut edits = vec![];
+    for (d, v) in trace.iter().enumerate().rev() {
+        let (d, k) = (d as isize, x - y);
+        if d == 0 {
+            while x > 0 {
+                x -= 1;
+                y -= 1;
+                edits.push(Edit::Equal(x as usize, y as usize));
+            }
+            break;
+        }
+        let idx = (k + d) as usize;
+        let prev_k = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + d) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Equal(x as usize, y as usize));
+        }
+        if x == prev_x {
+            edits.push(Edit::Insert(prev_y as usize));
+        } else {
+            edits.push(Edit::Delete(prev_x as usize));
+        }
+        (x, y) = (prev_x, prev_y);
+    }
+    edits.reverse();
+    edits
+}
+
+// A run of instructions that differ between the programs
+#[derive(Default)]
+pub struct Hunk {
+    pub removed: Vec<(usize, String)>,
+    pub added: Vec<(usize, String)>,
+}
+
+pub fn diff(a_source: &str, b_source: &str) -> Vec<Hunk> {
+    let (a, b) = (Program::parse(a_source), Program::parse(b_source));
+    let a_keys: Vec<&str> = (0..a.instructions.len()).map(|i| a.key(i)).collect();
+    let b_keys: Vec<&str> = (0..b.instructions.len()).map(|i| b.key(i)).collect();
+    let mut edits = shortest_edit(&a_keys, &b_keys);
+    // Labels are compared by the first instruction at or after them that
+    // both programs have, so code inserted right at a label doesn't make
+    // every jump to it a change. The ends of the programs correspond.
+    let (n, m) = (a.instructions.len(), b.instructions.len());
+    let mut corresponding = vec![None; n + 1];
+    let (mut a_next, mut b_next) = (vec![n; n + 1], vec![m; m + 1]);
+    corresponding[n] = Some(m);
+    for edit in &edits {
+        if let Edit::Equal(i, j) = *edit {
+            corresponding[i] = Some(j);
+            a_next[i] = i;
+            b_next[j] = j;
+        }
+    }
+    for i in (0..n).rev() {
+        a_next[i] = a_next[i].min(a_next[i + 1]);
+    }
+    for j in (0..m).rev() {
+        b_next[j] = b_next[j].min(b_next[j + 1]);
+    }
+    // Label loads whose labels mark different code are changes
+    let same_target = |i: usize, j: usize| match (a.target(i), b.target(j)) {
+        (Some(a_target), Some(b_target)) => {
+            corresponding[a_next[a_target]] == Some(b_next[b_target])
+        }
+        _ => true,
+    };
+    edits = edits
+        .into_iter()
+        .flat_map(|edit| match edit {
+            Edit::Equal(i, j) if !same_target(i, j) => vec![Edit::Delete(i), Edit::Insert(j)],
+            edit => vec![edit],
+        })
+        .collect();
+    let mut hunks = vec![];
+    let mut hunk: Option<Hunk> = None;
+    for edit in edits {
+        match edit {
+            Edit::Equal(..) => hunks.extend(hunk.take()),
+            Edit::Delete(i) => {
+                let instr = &a.instructions[i];
+                hunk.get_or_insert_with(Hunk::default)
+                    .removed
+                    .push((instr.line, instr.text.clone()));
+            }
+            Edit::Insert(j) => {
+                let instr = &b.instructions[j];
+                hunk.get_or_insert_with(Hunk::default)
+                    .added
+                    .push((instr.line, instr.text.clone()));
+            }
+        }
+    }
+    hunks.extend(hunk);
+    hunks
+}
+
+fn line_range(lines: &[(usize, String)]) -> String {
+    match (lines.first(), lines.last()) {
+        (Some((first, _)), Some((last, _))) if first == last => first.to_string(),
+        (Some((first, _)), Some((last, _))) => format!("{first}-{last}"),
+        _ => String::from("-"),
+    }
+}
+
+// Runs `vm_translator_rs diff <a.asm> <b.asm>`, printing the differences.
+// Returns whether the programs are equivalent.
+pub fn run(args: &[String]) -> bool {
+    let [a_path, b_path] = args else {
+        panic!("Usage: vm_translator_rs diff <a.asm> <b.asm>");
+    };
+    let read = |path: &str| {
+        read_to_string(Path::new(path)).unwrap_or_else(|_| panic!("Failed to read {path}"))
+    };
+    let hunks = diff(&read(a_path), &read(b_path));
+    for hunk in &hunks {
+        println!(
+            "@@ {a_path}:{} {b_path}:{} @@",
+            line_range(&hunk.removed),
+            line_range(&hunk.added)
+        );
+        for (_, text) in &hunk.removed {
+            println!("-{text}");
+        }
+        for (_, text) in &hunk.added {
+            println!("+{text}");
+        }
+    }
+    if hunks.is_empty() {
+        println!("No differences");
+    } else {
+        println!("{} divergent sections", hunks.len());
+    }
+    hunks.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff;
+
+    #[test]
+    fn test_diff_ignores_label_names() {
+        let a =
+            "// Main.f\n@SP\nAM=M-1\n(Main.f$LOOP)\nD=M\n@Main.f$LOOP\nD;JGT\n@END\n0;JMP\n(END)";
+        let b = "@SP\nAM = M-1 // pop\n(L1)\nD=M\n@L1\nD;JGT\n@L2\n0;JMP\n(L2)";
+        assert!(diff(a, b).is_empty());
+    }
+
+    #[test]
+    fn test_diff_reports_divergence() {
+        let a = "(LOOP)\n@SP\nD=M\n@LOOP\nD;JGT\n@LOOP\n0;JMP";
+        let b = "(LOOP)\n@SP\nD=M\n(L)\n@L\nD;JGE\n@LOOP\n0;JMP";
+        let hunks = diff(a, b);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!(
+            hunks[0].removed,
+            vec![(4, String::from("@LOOP")), (5, String::from("D;JGT"))]
+        );
+        assert_eq!(
+            hunks[0].added,
+            vec![(5, String::from("@L")), (6, String::from("D;JGE"))]
+        );
+    }
+}
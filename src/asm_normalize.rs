@@ -0,0 +1,62 @@
+use std::fs::{read_to_string, write};
+
+use crate::asm_diff::Program;
+
+// Rewrites an assembly program into a canonical form: no comments or blank
+// lines, no whitespace within instructions, and one label per marked
+// instruction, named `L$<n>` in order of address. Programs that differ only
+// in those respects normalize to the same text.
+pub fn normalize(source: &str) -> String {
+    let program = Program::parse(source);
+    let mut marked: Vec<usize> = program.labels.values().copied().collect();
+    marked.sort_unstable();
+    marked.dedup();
+    let name = |target: usize| format!("L${}", marked.binary_search(&target).unwrap());
+    let mut lines = vec![];
+    for i in 0..=program.instructions.len() {
+        if marked.binary_search(&i).is_ok() {
+            lines.push(format!("({})", name(i)));
+        }
+        if i == program.instructions.len() {
+            break;
+        }
+        match program.target(i) {
+            Some(target) => lines.push(format!("@{}", name(target))),
+            None => lines.push(program.instructions[i].text.clone()),
+        }
+    }
+    lines.join("\n")
+}
+
+// Runs `vm_translator_rs normalize <in.asm> [<out.asm>]`, writing the
+// normalized program to the outfile or stdout
+pub fn run(args: &[String]) {
+    let (infile, outfile) = match args {
+        [infile] => (infile, None),
+        [infile, outfile] => (infile, Some(outfile)),
+        _ => panic!("Usage: vm_translator_rs normalize <in.asm> [<out.asm>]"),
+    };
+    let source = read_to_string(infile).unwrap_or_else(|_| panic!("Failed to read {infile}"));
+    let normalized = normalize(&source);
+    match outfile {
+        Some(outfile) => write(outfile, normalized + "\n")
+            .unwrap_or_else(|_| panic!("Failed to write {outfile}")),
+        None => println!("{normalized}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn test_normalize() {
+        let a = "// bootstrap\n@Sys.init$ret.0\nD = A // return address\n(Sys.init$ret.0)\n\
+                 (END)\n@END\n0;JMP\n@Main.0\nM=D\n(EXIT)";
+        let b = "@ret\nD=A\n(ret)\n@ret\n  0;JMP\n@Main.0\nM=D\n(done)";
+        let normalized = "@L$0\nD=A\n(L$0)\n@L$0\n0;JMP\n@Main.0\nM=D\n(L$1)";
+        assert_eq!(normalize(a), normalized);
+        assert_eq!(normalize(b), normalized);
+        assert_eq!(normalize(normalized), normalized);
+    }
+}
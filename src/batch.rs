@@ -0,0 +1,440 @@
+use std::env;
+use std::fs::{read_to_string, File};
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+use flate2::read::GzDecoder;
+use rayon::prelude::*;
+
+use crate::asm_diff;
+use crate::emulator::Emulator;
+use crate::json::Json;
+use crate::junit::{self, TestResult};
+use crate::limits::parse_limit;
+use crate::options::{Options, ENV_PREFIX};
+use crate::target::Target;
+
+// Translates and checks every project listed in a JSON manifest, for building
+// many programs at once, such as a course's reference solutions. Each project
+// is translated by a separate run of the translator with its own options, so
+// one failing doesn't stop the others, and what it printed is kept for the
+// summary.
+
+const USAGE: &str =
+    "Usage: vm_translator_rs batch [--max-steps=<n>] [--junit=<path>] <manifest.json>";
+
+const MAX_STEPS: usize = 1_000_000;
+
+struct Project {
+    name: String,
+    input: PathBuf,
+    options: Vec<String>,
+    // Compared with the output ignoring label names, as by `diff`
+    expected_asm: Option<PathBuf>,
+    // RAM values the program should end with when run on the emulator
+    expected_ram: Vec<(usize, i32)>,
+}
+
+// How one check of a project went
+#[derive(Clone, Copy, PartialEq)]
+enum Check {
+    Passed,
+    Failed,
+    NotRun,
+}
+
+impl Check {
+    fn describe(self) -> &'static str {
+        match self {
+            Check::Passed => "ok",
+            Check::Failed => "FAILED",
+            Check::NotRun => "-",
+        }
+    }
+}
+
+struct Outcome {
+    translation: Check,
+    asm: Check,
+    ram: Check,
+    // Why the project failed, with the translator's output if it did
+    errors: Vec<String>,
+    time: Duration,
+}
+
+impl Outcome {
+    fn passed(&self) -> bool {
+        [self.translation, self.asm, self.ram]
+            .iter()
+            .all(|&check| check != Check::Failed)
+    }
+}
+
+// Reads the manifest. Paths in it are relative to its directory.
+fn read_manifest(path: &Path) -> Vec<Project> {
+    let path_str = path.to_str().unwrap();
+    let text =
+        read_to_string(path).unwrap_or_else(|_| panic!("Failed to read manifest {path_str}"));
+    let manifest =
+        Json::parse(&text).unwrap_or_else(|| panic!("Manifest {path_str} isn't valid JSON"));
+    let base = path.parent().unwrap_or(Path::new(""));
+    let projects = manifest
+        .get("projects")
+        .and_then(Json::as_array)
+        .unwrap_or_else(|| panic!("Manifest {path_str} has no projects"));
+    projects
+        .iter()
+        .map(|project| {
+            let invalid = || -> ! { panic!("Invalid project in manifest {path_str}: {project}") };
+            let field = |name| project.get(name).and_then(Json::as_str);
+            let input = field("path").unwrap_or_else(|| invalid());
+            let options = match project.get("options") {
+                None => vec![],
+                Some(options) => (options.as_array().unwrap_or_else(|| invalid()).iter())
+                    .map(|option| option.as_str().unwrap_or_else(|| invalid()).to_owned())
+                    .collect(),
+            };
+            let expected = project.get("expected");
+            let expected_field = |name| expected.and_then(|expected| expected.get(name));
+            let expected_asm = expected_field("asm")
+                .map(|asm| base.join(asm.as_str().unwrap_or_else(|| invalid())));
+            let expected_ram = match expected_field("ram") {
+                None => vec![],
+                Some(Json::Object(values)) => values
+                    .iter()
+                    .map(|(address, value)| {
+                        let address = address.parse().ok();
+                        let value = value.as_number().and_then(|value| value.try_into().ok());
+                        address.zip(value).unwrap_or_else(|| invalid())
+                    })
+                    .collect(),
+                Some(_) => invalid(),
+            };
+            let name = field("name").unwrap_or(input);
+            let given = |option: &str| {
+                (options.iter()).any(|arg: &String| arg.split('=').next() == Some(option))
+            };
+            // The checks need the output of this run, in standard Hack assembly
+            // for the emulator
+            if given("--since") {
+                panic!("Project {name} in manifest {path_str}: --since isn't supported, as the output must be written to be checked");
+            }
+            if given("--asm-dialect") && !expected_ram.is_empty() {
+                panic!("Project {name} in manifest {path_str}: --asm-dialect can't be used with expected RAM values, as the emulator only runs standard Hack assembly");
+            }
+            Project {
+                name: name.to_owned(),
+                input: base.join(input),
+                options,
+                expected_asm,
+                expected_ram,
+            }
+        })
+        .collect()
+}
+
+// Runs the assembly on the project's target, returning a description of every
+// RAM value that differs from the expected one
+fn check_ram(
+    asm: &str,
+    target: &Target,
+    expected: &[(usize, i32)],
+    max_steps: usize,
+) -> Vec<String> {
+    let lines: Vec<&str> = asm.lines().collect();
+    let mut emulator = Emulator::new(&lines, target);
+    if !emulator.run(max_steps) {
+        return vec![format!("Still running after --max-steps={max_steps}")];
+    }
+    expected
+        .iter()
+        .filter_map(|&(address, value)| match emulator.ram.get(address) {
+            Some(&actual) if actual == value => None,
+            Some(actual) => Some(format!("RAM[{address}] is {actual}, expected {value}")),
+            None => Some(format!("RAM[{address}] is out of range")),
+        })
+        .collect()
+}
+
+// The assembly output given by the translator's `translation-finished` event,
+// if it wrote one
+fn reported_asm(events: &str) -> Option<PathBuf> {
+    events
+        .lines()
+        .filter_map(Json::parse)
+        .find(|event| event.get("event").and_then(Json::as_str) == Some("translation-finished"))
+        .and_then(|event| event.get("output")?.as_str().map(PathBuf::from))
+}
+
+// Reads the assembly, decompressing it if it was written with --compress
+fn read_asm(path: &Path) -> Option<String> {
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        let mut text = String::new();
+        GzDecoder::new(File::open(path).ok()?)
+            .read_to_string(&mut text)
+            .ok()?;
+        Some(text)
+    } else {
+        read_to_string(path).ok()
+    }
+}
+
+// Translates the project with a run of this executable, without the options
+// of the environment, so every project is built by its manifest entry alone
+fn check(project: &Project, max_steps: usize) -> Outcome {
+    let start = Instant::now();
+    let mut outcome = check_project(project, max_steps);
+    outcome.time = start.elapsed();
+    outcome
+}
+
+fn check_project(project: &Project, max_steps: usize) -> Outcome {
+    let mut outcome = Outcome {
+        translation: Check::Passed,
+        asm: Check::NotRun,
+        ram: Check::NotRun,
+        errors: vec![],
+        time: Duration::ZERO,
+    };
+    let executable = env::current_exe().expect("Failed to find the translator's executable");
+    let mut command = Command::new(executable);
+    // Events rather than text, for the path of the assembly written
+    command
+        .args(&project.options)
+        .arg("--message-format=ndjson")
+        .arg(&project.input)
+        .env_remove("RUST_BACKTRACE");
+    for (key, _) in env::vars().filter(|(key, _)| key.starts_with(ENV_PREFIX)) {
+        command.env_remove(key);
+    }
+    let output = command
+        .output()
+        .unwrap_or_else(|_| panic!("Failed to run the translator for {}", project.name));
+    if !output.status.success() {
+        outcome.translation = Check::Failed;
+        outcome.errors.push(String::from("Translation failed:"));
+        let printed = [output.stdout, output.stderr].concat();
+        let printed = String::from_utf8_lossy(&printed);
+        outcome
+            .errors
+            .extend(printed.lines().map(|line| format!("  {line}")));
+        return outcome;
+    }
+
+    let Some(outfile) = reported_asm(&String::from_utf8_lossy(&output.stdout)) else {
+        outcome.translation = Check::Failed;
+        outcome
+            .errors
+            .push(String::from("The translator wrote no assembly"));
+        return outcome;
+    };
+    let Some(asm) = read_asm(&outfile) else {
+        outcome.translation = Check::Failed;
+        outcome.errors.push(format!(
+            "Failed to read the assembly output {}",
+            outfile.to_str().unwrap()
+        ));
+        return outcome;
+    };
+    if let Some(expected_path) = &project.expected_asm {
+        outcome.asm = match read_to_string(expected_path) {
+            Ok(expected) => {
+                let hunks = asm_diff::diff(&expected, &asm);
+                if hunks.is_empty() {
+                    Check::Passed
+                } else {
+                    outcome.errors.push(format!(
+                        "Output differs from {} in {} sections",
+                        expected_path.to_str().unwrap(),
+                        hunks.len()
+                    ));
+                    Check::Failed
+                }
+            }
+            Err(_) => {
+                outcome.errors.push(format!(
+                    "Failed to read expected output {}",
+                    expected_path.to_str().unwrap()
+                ));
+                Check::Failed
+            }
+        };
+    }
+    if !project.expected_ram.is_empty() {
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut args = project.options.clone();
+            args.push(project.input.to_str().unwrap().to_owned());
+            let target = Options::from_args(&args).target;
+            check_ram(&asm, &target, &project.expected_ram, max_steps)
+        }));
+        let errors = run.unwrap_or_else(|payload| {
+            vec![format!(
+                "The emulator stopped: {}",
+                crate::panic_message(&*payload)
+            )]
+        });
+        outcome.ram = if errors.is_empty() {
+            Check::Passed
+        } else {
+            Check::Failed
+        };
+        outcome.errors.extend(errors);
+    }
+    outcome
+}
+
+// A table with a row per project and a column per check
+fn summary(projects: &[Project], outcomes: &[Outcome]) -> String {
+    let width = (projects.iter())
+        .map(|project| project.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("Project".len());
+    let mut table = format!(
+        "{:width$}  {:11}  {:6}  RAM\n",
+        "Project", "Translation", "Asm"
+    );
+    for (project, outcome) in projects.iter().zip(outcomes) {
+        table.push_str(&format!(
+            "{:width$}  {:11}  {:6}  {}\n",
+            project.name,
+            outcome.translation.describe(),
+            outcome.asm.describe(),
+            outcome.ram.describe()
+        ));
+    }
+    table
+}
+
+// Runs `vm_translator_rs batch [--max-steps=<n>] [--junit=<path>]
+// <manifest.json>`, exiting with status 1 if any project fails
+pub fn run(args: &[String]) {
+    let mut manifest = None;
+    let mut max_steps = MAX_STEPS;
+    let mut junit = None;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((option @ "--max-steps", value)) => max_steps = parse_limit(option, value),
+            Some(("--junit", value)) => junit = Some(PathBuf::from(value)),
+            _ if arg.starts_with("--") || manifest.is_some() => panic!("{USAGE}"),
+            _ => manifest = Some(PathBuf::from(arg)),
+        }
+    }
+    let projects = read_manifest(&manifest.unwrap_or_else(|| panic!("{USAGE}")));
+    // Programs crashing the emulator are reported with their project
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let outcomes: Vec<Outcome> = projects
+        .par_iter()
+        .map(|project| check(project, max_steps))
+        .collect();
+    panic::set_hook(hook);
+    for (project, outcome) in projects.iter().zip(&outcomes) {
+        if !outcome.errors.is_empty() {
+            println!("{}:", project.name);
+            for error in &outcome.errors {
+                println!("    {error}");
+            }
+        }
+    }
+    print!("{}", summary(&projects, &outcomes));
+    if let Some(path) = &junit {
+        let results: Vec<TestResult> = (projects.iter().zip(&outcomes))
+            .map(|(project, outcome)| TestResult {
+                name: project.name.clone(),
+                class: String::from("batch"),
+                time: outcome.time,
+                errors: outcome.errors.clone(),
+            })
+            .collect();
+        junit::write_results(path, "batch", &results);
+    }
+    let failures = outcomes.iter().filter(|outcome| !outcome.passed()).count();
+    println!(
+        "{} of {} projects passed",
+        projects.len() - failures,
+        projects.len()
+    );
+    if failures > 0 {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_manifest, reported_asm};
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::path::Path;
+
+    #[test]
+    fn test_read_manifest() {
+        let dir = std::env::temp_dir().join(format!("vm_translator_batch_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let manifest = dir.join("projects.json");
+        write(
+            &manifest,
+            r#"{"projects": [
+                {"name": "Fib", "path": "08/Fib", "options": ["--end-loop"],
+                 "expected": {"asm": "expected/Fib.asm", "ram": {"0": 262, "261": -3}}},
+                {"path": "07/SimpleAdd.vm"}
+            ]}"#,
+        )
+        .unwrap();
+        let projects = read_manifest(&manifest);
+        assert_eq!(projects.len(), 2);
+        assert_eq!(projects[0].name, "Fib");
+        assert_eq!(projects[0].input, dir.join("08/Fib"));
+        assert_eq!(projects[0].options, ["--end-loop"]);
+        assert_eq!(
+            projects[0].expected_asm.as_deref(),
+            Some(dir.join("expected/Fib.asm").as_path())
+        );
+        assert_eq!(projects[0].expected_ram, [(0, 262), (261, -3)]);
+        assert_eq!(projects[1].name, "07/SimpleAdd.vm");
+        assert_eq!(projects[1].expected_asm.as_deref(), None::<&Path>);
+        assert!(projects[1].expected_ram.is_empty());
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "--asm-dialect can't be used with expected RAM values")]
+    fn test_read_manifest_dialect_and_ram() {
+        let dir = std::env::temp_dir().join(format!(
+            "vm_translator_batch_dialect_{}",
+            std::process::id()
+        ));
+        create_dir_all(&dir).unwrap();
+        let manifest = dir.join("projects.json");
+        write(
+            &manifest,
+            r#"{"projects": [{"path": "Fib", "options": ["--asm-dialect=case=lower"],
+                "expected": {"ram": {"0": 262}}}]}"#,
+        )
+        .unwrap();
+        let result = std::panic::catch_unwind(|| read_manifest(&manifest));
+        remove_dir_all(&dir).unwrap();
+        if let Err(payload) = result {
+            std::panic::resume_unwind(payload);
+        }
+    }
+
+    #[test]
+    fn test_reported_asm() {
+        let events = concat!(
+            r#"{"event":"translation-started","input":"Fib","output":"Fib/Fib.asm.gz"}"#,
+            "\n",
+            r#"{"event":"translation-finished","output":"Fib/Fib.asm.gz"}"#,
+            "\n"
+        );
+        assert_eq!(reported_asm(events), Some("Fib/Fib.asm.gz".into()));
+        let events = r#"{"event":"up-to-date","since":"HEAD","output":"Fib/Fib.asm"}"#;
+        assert_eq!(reported_asm(events), None);
+        assert_eq!(
+            reported_asm(r#"{"event":"translation-finished","output":null}"#),
+            None
+        );
+    }
+}
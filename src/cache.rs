@@ -0,0 +1,182 @@
+use std::fmt::{self, Display};
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, write, File};
+use std::path::{Path, PathBuf};
+use std::str::{FromStr, Lines};
+use std::time::SystemTime;
+
+use crate::hash::sha256;
+
+// Per-file translations kept between runs, one file per translation named by
+// the hash of everything it depends on. Entries that fail to decode, e.g. ones
+// written by another version, are translated again.
+pub const CACHE_DIR: &str = ".vm_translator_cache";
+
+// The size the cache is pruned to after each translation
+pub const MAX_SIZE: u64 = 64 << 20;
+
+const FORMAT: &str = "vm_translator_rs cache 2";
+
+// Cached data is line-based: single values, space-separated fields and
+// counted lists of either
+#[derive(Default)]
+pub struct Encoder {
+    text: String,
+}
+
+impl fmt::Display for Encoder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Encoder {
+    pub fn line(&mut self, line: impl Display) {
+        self.text.push_str(&line.to_string());
+        self.text.push('\n');
+    }
+
+    pub fn count(&mut self, count: usize) {
+        self.line(count);
+    }
+}
+
+pub struct Decoder<'a> {
+    lines: Lines<'a>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Self {
+            lines: text.lines(),
+        }
+    }
+
+    pub fn line(&mut self) -> Option<&'a str> {
+        self.lines.next()
+    }
+
+    pub fn parse<T: FromStr>(&mut self) -> Option<T> {
+        self.line()?.parse().ok()
+    }
+
+    pub fn count(&mut self) -> Option<usize> {
+        self.parse()
+    }
+}
+
+// `-` stands for None
+pub fn optional<T: Display>(value: Option<T>) -> String {
+    value.map_or(String::from("-"), |value| value.to_string())
+}
+
+pub fn parse_optional<T: FromStr>(field: &str) -> Option<Option<T>> {
+    match field {
+        "-" => Some(None),
+        _ => field.parse().ok().map(Some),
+    }
+}
+
+// The key of a translation whose inputs are described by the text
+pub fn key(inputs: &str) -> String {
+    sha256(format!("{FORMAT}\n{}\n{inputs}", env!("CARGO_PKG_VERSION")).as_bytes())
+}
+
+// The key of the parsed program of a source file, which is assumed to be
+// unchanged as long as its size and modification time are. None if the file's
+// metadata can't be read.
+pub fn source_key(path: &Path) -> Option<String> {
+    let metadata = path.metadata().ok()?;
+    let path = path.canonicalize().ok()?;
+    let modified = metadata.modified().ok()?;
+    Some(key(&format!(
+        "source {}\n{} {:?}",
+        path.to_str()?,
+        metadata.len(),
+        modified
+    )))
+}
+
+pub fn load<T>(dir: &Path, key: &str, decode: impl FnOnce(&mut Decoder) -> Option<T>) -> Option<T> {
+    let path = dir.join(key);
+    let text = read_to_string(&path).ok()?;
+    let mut decoder = Decoder::new(&text);
+    if decoder.line()? != FORMAT {
+        return None;
+    }
+    let value = decode(&mut decoder)?;
+    // The modification time of an entry is when it was last used, for prune
+    if let Ok(file) = File::options().write(true).open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+    Some(value)
+}
+
+pub fn store(dir: &Path, key: &str, encode: impl FnOnce(&mut Encoder)) {
+    let mut encoder = Encoder::default();
+    encoder.line(FORMAT);
+    encode(&mut encoder);
+    create_dir_all(dir)
+        .unwrap_or_else(|_| panic!("Failed to create cache {}", dir.to_str().unwrap()));
+    let path = dir.join(key);
+    write(&path, encoder.text)
+        .unwrap_or_else(|_| panic!("Failed to write cache entry {}", path.to_str().unwrap()));
+}
+
+// Removes the least recently used entries until the cache is no larger than
+// max_size, so that entries of files long changed or deleted don't pile up.
+// Entries that can't be removed are left.
+pub fn prune(dir: &Path, max_size: u64) {
+    let Ok(entries) = read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let metadata = entry
+                .metadata()
+                .ok()
+                .filter(|metadata| metadata.is_file())?;
+            Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+        })
+        .collect();
+    entries.sort();
+    let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in entries {
+        if size <= max_size {
+            break;
+        }
+        if remove_file(path).is_ok() {
+            size -= len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_prune() {
+        let dir = std::env::temp_dir().join(format!("vm_translator_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let start = SystemTime::now() - Duration::from_secs(60);
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            store(&dir, key, |encoder| encoder.line(key));
+            let file = File::options().write(true).open(dir.join(key)).unwrap();
+            file.set_modified(start + Duration::from_secs(i as u64))
+                .unwrap();
+        }
+        // Loading an entry makes it the most recently used
+        assert_eq!(
+            load(&dir, "a", |decoder| decoder.parse()),
+            Some(String::from("a"))
+        );
+        let entry_size = dir.join("a").metadata().unwrap().len();
+        prune(&dir, 2 * entry_size);
+        assert!(dir.join("a").exists());
+        assert!(!dir.join("b").exists());
+        assert!(dir.join("c").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::intern::Name;
+use crate::target::Word;
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::VMFile;
+
+// Words a call pushes besides the arguments: the return address and the
+// caller's LCL, ARG, THIS and THAT
+const SAVED_FRAME: Word = 5;
+
+pub struct FunctionNode {
+    pub name: Name,
+    // Where the function is defined: the index of its file and its line
+    pub file: usize,
+    pub line: usize,
+    pub num_locals: Word,
+    // The most arguments the function is called with
+    pub num_args: Word,
+    // Called functions, without duplicates, in order of first call
+    pub callees: Vec<Name>,
+}
+
+// The functions of a program and the functions each of them calls
+pub struct CallGraph {
+    pub functions: Vec<FunctionNode>,
+    // The groups of functions that call each other recursively, each in
+    // definition order
+    pub recursion_cycles: Vec<Vec<Name>>,
+    // The position of each function in functions, the first if it's defined
+    // more than once
+    positions: HashMap<Name, usize>,
+}
+
+impl CallGraph {
+    pub fn build(files: &[VMFile]) -> Self {
+        let mut functions: Vec<FunctionNode> = vec![];
+        for (file_index, file) in files.iter().enumerate() {
+            let mut in_function = false;
+            for instr in &file.instructions {
+                match instr.instruction {
+                    ParsedVMInstruction::Function {
+                        name,
+                        num_local_vars,
+                    } => {
+                        functions.push(FunctionNode {
+                            name,
+                            file: file_index,
+                            line: instr.line,
+                            num_locals: num_local_vars,
+                            num_args: 0,
+                            callees: vec![],
+                        });
+                        in_function = true;
+                    }
+                    ParsedVMInstruction::Call { name, .. } if in_function => {
+                        let function = functions.last_mut().unwrap();
+                        if !function.callees.contains(&name) {
+                            function.callees.push(name);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut num_args: HashMap<Name, Word> = HashMap::new();
+        for instr in files.iter().flat_map(|file| &file.instructions) {
+            if let ParsedVMInstruction::Call { name, num_args: n } = instr.instruction {
+                let most = num_args.entry(name).or_default();
+                *most = (*most).max(n);
+            }
+        }
+        for function in &mut functions {
+            function.num_args = num_args.get(&function.name).copied().unwrap_or(0);
+        }
+        let mut positions = HashMap::new();
+        for (i, function) in functions.iter().enumerate() {
+            positions.entry(function.name).or_insert(i);
+        }
+        let mut graph = Self {
+            functions,
+            recursion_cycles: vec![],
+            positions,
+        };
+        graph.recursion_cycles = graph.find_recursion_cycles();
+        graph
+    }
+
+    pub fn get(&self, name: Name) -> Option<&FunctionNode> {
+        Some(&self.functions[*self.positions.get(&name)?])
+    }
+
+    // Names of all defined functions reachable through calls from root
+    pub fn reachable_from(&self, root: Name) -> HashSet<Name> {
+        let mut reachable = HashSet::new();
+        let mut pending: Vec<&FunctionNode> = self.get(root).into_iter().collect();
+        while let Some(function) = pending.pop() {
+            if reachable.insert(function.name) {
+                pending.extend(function.callees.iter().filter_map(|&name| self.get(name)));
+            }
+        }
+        reachable
+    }
+
+    // Where the program starts: Sys.init if it's defined, otherwise every
+    // function no other function calls
+    pub fn entry_points(&self) -> Vec<Name> {
+        let sys_init = Name::new("Sys.init");
+        if self.get(sys_init).is_some() {
+            return vec![sys_init];
+        }
+        let called: HashSet<Name> = self
+            .functions
+            .iter()
+            .flat_map(|function| function.callees.iter().copied())
+            .collect();
+        self.functions
+            .iter()
+            .map(|function| function.name)
+            .filter(|name| !called.contains(name))
+            .collect()
+    }
+
+    // The recursion cycles, found with Tarjan's algorithm
+    fn find_recursion_cycles(&self) -> Vec<Vec<Name>> {
+        let count = self.functions.len();
+        let mut tarjan = Tarjan {
+            graph: self,
+            next_index: 0,
+            index: vec![None; count],
+            low_link: vec![0; count],
+            on_stack: vec![false; count],
+            stack: vec![],
+            visiting: vec![],
+            cycles: vec![],
+        };
+        for (i, function) in self.functions.iter().enumerate() {
+            // Later definitions of a name are never called
+            if tarjan.index[i].is_none() && self.positions[&function.name] == i {
+                tarjan.visit(i);
+            }
+        }
+        let mut cycles = tarjan.cycles;
+        for cycle in &mut cycles {
+            cycle.sort();
+        }
+        cycles.sort();
+        (cycles.into_iter())
+            .map(|cycle| {
+                (cycle.into_iter())
+                    .map(|i| self.functions[i].name)
+                    .collect()
+            })
+            .collect()
+    }
+
+    // The worst-case stack usage in words of a call to root, counting the
+    // arguments, saved frame and locals of every function on the deepest
+    // chain of calls but not the working stack of their code. Returns the
+    // usage and the chain, or None if root can recurse.
+    pub fn stack_depth(&self, root: Name) -> Option<(Word, Vec<Name>)> {
+        let recursive: HashSet<Name> = self.recursion_cycles.iter().flatten().copied().collect();
+        if self
+            .reachable_from(root)
+            .iter()
+            .any(|name| recursive.contains(name))
+        {
+            return None;
+        }
+        let root = *self.positions.get(&root)?;
+        // The usage of a call to each function, with the callee on its
+        // deepest chain, found callees first without recursing, as above
+        let mut deepest: HashMap<usize, (Word, Option<usize>)> = HashMap::new();
+        let mut visiting = vec![(root, 0)];
+        while let Some((node, next)) = visiting.last_mut() {
+            let function = &self.functions[*node];
+            if let Some(callee) = function.callees.get(*next) {
+                *next += 1;
+                if let Some(&callee) = self.positions.get(callee) {
+                    if !deepest.contains_key(&callee) {
+                        visiting.push((callee, 0));
+                    }
+                }
+                continue;
+            }
+            let node = *node;
+            visiting.pop();
+            let mut callee_depth = (0, None);
+            let callees = function.callees.iter();
+            for &callee in callees.filter_map(|callee| self.positions.get(callee)) {
+                let depth = deepest[&callee].0;
+                if depth > callee_depth.0 {
+                    callee_depth = (depth, Some(callee));
+                }
+            }
+            let depth = function.num_args + SAVED_FRAME + function.num_locals + callee_depth.0;
+            deepest.insert(node, (depth, callee_depth.1));
+        }
+        let mut chain = vec![root];
+        while let Some(&(_, Some(next))) = deepest.get(chain.last().unwrap()) {
+            chain.push(next);
+        }
+        let chain = chain.into_iter().map(|i| self.functions[i].name).collect();
+        Some((deepest[&root].0, chain))
+    }
+}
+
+// Functions are identified by their position in the graph. The depth-first
+// search keeps its own stack of the functions being visited, each with the
+// next of its callees to look at, so that long chains of calls can't overflow
+// the thread's stack.
+struct Tarjan<'a> {
+    graph: &'a CallGraph,
+    next_index: usize,
+    index: Vec<Option<usize>>,
+    low_link: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    visiting: Vec<(usize, usize)>,
+    cycles: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn start(&mut self, function: usize) {
+        self.index[function] = Some(self.next_index);
+        self.low_link[function] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(function);
+        self.on_stack[function] = true;
+        self.visiting.push((function, 0));
+    }
+
+    fn visit(&mut self, root: usize) {
+        let graph = self.graph;
+        self.start(root);
+        while let Some((function, next)) = self.visiting.last_mut() {
+            let function = *function;
+            let node = &graph.functions[function];
+            if let Some(callee) = node.callees.get(*next) {
+                *next += 1;
+                let Some(&callee) = graph.positions.get(callee) else {
+                    continue;
+                };
+                match self.index[callee] {
+                    None => self.start(callee),
+                    Some(index) if self.on_stack[callee] => {
+                        self.low_link[function] = self.low_link[function].min(index)
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+            self.visiting.pop();
+            if let Some(&(caller, _)) = self.visiting.last() {
+                self.low_link[caller] = self.low_link[caller].min(self.low_link[function]);
+            }
+            if Some(self.low_link[function]) == self.index[function] {
+                let start = (self.stack.iter())
+                    .rposition(|&other| other == function)
+                    .unwrap();
+                let component = self.stack.split_off(start);
+                for &member in &component {
+                    self.on_stack[member] = false;
+                }
+                // A single function is only recursive if it calls itself
+                if component.len() > 1 || node.callees.contains(&node.name) {
+                    self.cycles.push(component);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CallGraph;
+    use crate::intern::Name;
+    use crate::target::Word;
+    use crate::vm_translator::parse_source;
+
+    fn names(names: &[&str]) -> Vec<Name> {
+        names.iter().map(|name| Name::new(name)).collect()
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        let lines = [
+            "function Sys.init 0",
+            "call Main.main 0",
+            "call Main.leaf 1",
+            "function Main.main 2",
+            "call Main.leaf 3",
+            "function Main.leaf 1",
+            "return",
+            "function Math.a 0",
+            "call Math.b 0",
+            "function Math.b 0",
+            "call Math.a 0",
+            "function Math.c 0",
+            "call Math.c 0",
+        ];
+        let graph = CallGraph::build(&[parse_source("Main.vm", &lines.join("\n"))]);
+        let (depth, chain) = graph.stack_depth(Name::new("Sys.init")).unwrap();
+        // Sys.init 5, Main.main 5 + 2, Main.leaf 3 + 5 + 1
+        assert_eq!(depth, 21);
+        assert_eq!(chain, names(&["Sys.init", "Main.main", "Main.leaf"]));
+        assert_eq!(
+            graph.recursion_cycles,
+            vec![names(&["Math.a", "Math.b"]), names(&["Math.c"])]
+        );
+        assert_eq!(graph.stack_depth(Name::new("Math.b")), None);
+    }
+
+    #[test]
+    fn test_long_call_chain() {
+        // Deep enough to overflow the stack of a recursive search
+        const LENGTH: usize = 100_000;
+        let mut source = String::new();
+        for i in 0..LENGTH {
+            source.push_str(&format!("function F.f{i} 0\ncall F.f{} 0\n", i + 1));
+        }
+        let graph = CallGraph::build(&[parse_source("F.vm", &source)]);
+        assert!(graph.recursion_cycles.is_empty());
+        let (depth, chain) = graph.stack_depth(Name::new("F.f0")).unwrap();
+        assert_eq!(chain.len(), LENGTH);
+        assert_eq!(depth, 5 * LENGTH as Word);
+        // Calling the first function from the last makes them all one cycle
+        source.push_str(&format!("function F.f{LENGTH} 0\ncall F.f0 0\n"));
+        let graph = CallGraph::build(&[parse_source("F.vm", &source)]);
+        assert_eq!(graph.recursion_cycles.len(), 1);
+        assert_eq!(graph.recursion_cycles[0].len(), LENGTH + 1);
+        assert_eq!(graph.recursion_cycles[0][0], Name::new("F.f0"));
+    }
+}
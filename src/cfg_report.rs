@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use crate::diagnostics::{function_scopes, ControlFlow};
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{SourceInstruction, Translation, VMFile};
+
+// Renders a Markdown report of the control flow of every function: its basic
+// blocks and the edges between them, drawn as a Mermaid flowchart and listed
+// in a table with their sizes and whether they can be reached from the entry
+
+// Where control goes from the end of a block
+#[derive(Clone, Copy, PartialEq)]
+enum Edge {
+    Block(usize),
+    Return,
+    Halt,
+    // Into the code following the function
+    FallThrough,
+}
+
+struct Block {
+    // Instructions of the function, by index
+    start: usize,
+    end: usize,
+    edges: Vec<Edge>,
+    reachable: bool,
+}
+
+fn ends_block(instruction: &ParsedVMInstruction) -> bool {
+    matches!(
+        instruction,
+        ParsedVMInstruction::Goto { .. }
+            | ParsedVMInstruction::IfGoto { .. }
+            | ParsedVMInstruction::Return
+            | ParsedVMInstruction::Halt { .. }
+    )
+}
+
+fn blocks(function: &[&SourceInstruction]) -> Vec<Block> {
+    let flow = ControlFlow::new(function);
+    let mut starts = vec![0];
+    for (i, instr) in function.iter().enumerate().skip(1) {
+        let label = matches!(instr.instruction, ParsedVMInstruction::Label { .. });
+        if (label || ends_block(&function[i - 1].instruction)) && starts.last() != Some(&i) {
+            starts.push(i);
+        }
+    }
+    let block_of: HashMap<usize, usize> = starts.iter().enumerate().map(|(b, &i)| (i, b)).collect();
+    let mut blocks: Vec<Block> = starts
+        .iter()
+        .enumerate()
+        .map(|(b, &start)| {
+            let end = starts.get(b + 1).copied().unwrap_or(function.len());
+            let edges = match function[end - 1].instruction {
+                ParsedVMInstruction::Return => vec![Edge::Return],
+                ParsedVMInstruction::Halt { .. } => vec![Edge::Halt],
+                _ => flow
+                    .successors(end - 1)
+                    .into_iter()
+                    .map(|i| match block_of.get(&i) {
+                        Some(&block) => Edge::Block(block),
+                        None => Edge::FallThrough,
+                    })
+                    .collect(),
+            };
+            Block {
+                start,
+                end,
+                edges,
+                reachable: false,
+            }
+        })
+        .collect();
+    let mut pending = vec![0];
+    while let Some(b) = pending.pop() {
+        if std::mem::replace(&mut blocks[b].reachable, true) {
+            continue;
+        }
+        pending.extend(blocks[b].edges.iter().filter_map(|edge| match edge {
+            Edge::Block(block) => Some(*block),
+            _ => None,
+        }));
+    }
+    blocks
+}
+
+fn render_function(
+    report: &mut String,
+    title: &str,
+    file: &VMFile,
+    function: &[&SourceInstruction],
+    rom: &HashMap<usize, usize>,
+) {
+    let blocks = blocks(function);
+    let unreachable = blocks.iter().filter(|block| !block.reachable).count();
+    let lines = |block: &Block| {
+        let (first, last) = (function[block.start].line, function[block.end - 1].line);
+        if first == last {
+            format!("line {first}")
+        } else {
+            format!("lines {first}-{last}")
+        }
+    };
+    let rom_instructions = |block: &Block| -> usize {
+        function[block.start..block.end]
+            .iter()
+            .filter_map(|instr| rom.get(&instr.line))
+            .sum()
+    };
+
+    report.push_str(&format!("\n## {title}\n\n"));
+    report.push_str(&format!(
+        "In `{}`, {} block{}, {unreachable} unreachable.\n\n",
+        file.name,
+        blocks.len(),
+        if blocks.len() == 1 { "" } else { "s" }
+    ));
+    report.push_str("```mermaid\nflowchart TD\n");
+    for (b, block) in blocks.iter().enumerate() {
+        report.push_str(&format!(
+            "    b{b}[\"{}<br/>{}<br/>{} VM, {} ROM\"]\n",
+            lines(block),
+            function[block.start].instruction,
+            block.end - block.start,
+            rom_instructions(block)
+        ));
+    }
+    for (b, block) in blocks.iter().enumerate() {
+        for edge in &block.edges {
+            let target = match edge {
+                Edge::Block(target) => format!("b{target}"),
+                Edge::Return => String::from("ret([return])"),
+                Edge::Halt => String::from("halted([halt])"),
+                Edge::FallThrough => String::from("next([following code])"),
+            };
+            report.push_str(&format!("    b{b} --> {target}\n"));
+        }
+    }
+    if unreachable > 0 {
+        report.push_str("    classDef unreachable stroke-dasharray: 5 5\n");
+        for (b, block) in blocks.iter().enumerate() {
+            if !block.reachable {
+                report.push_str(&format!("    class b{b} unreachable\n"));
+            }
+        }
+    }
+    report.push_str("```\n\n");
+
+    report.push_str(
+        "| Block | Lines | VM instructions | ROM instructions | Reachable | Successors |\n",
+    );
+    report.push_str("| --- | --- | ---: | ---: | --- | --- |\n");
+    for (b, block) in blocks.iter().enumerate() {
+        let successors: Vec<String> = block
+            .edges
+            .iter()
+            .map(|edge| match edge {
+                Edge::Block(target) => format!("b{target}"),
+                Edge::Return => String::from("return"),
+                Edge::Halt => String::from("halt"),
+                Edge::FallThrough => String::from("following code"),
+            })
+            .collect();
+        report.push_str(&format!(
+            "| b{b} | {} | {} | {} | {} | {} |\n",
+            lines(block),
+            block.end - block.start,
+            rom_instructions(block),
+            if block.reachable { "yes" } else { "no" },
+            successors.join(", ")
+        ));
+    }
+}
+
+pub fn render(translation: &Translation, files: &[VMFile]) -> String {
+    // ROM instructions generated for each line, per file
+    let mut rom: Vec<HashMap<usize, usize>> = vec![HashMap::new(); files.len()];
+    for entry in translation.source_map.entries() {
+        if let (Some(_), Some(source), Some(line)) = (entry.rom_addr, entry.source, entry.vm_line) {
+            *rom[source].entry(line).or_default() += 1;
+        }
+    }
+    let mut report = String::from("# Control flow report\n");
+    for (file, rom) in files.iter().zip(&rom) {
+        for scope in function_scopes(file) {
+            let title = match scope.first().map(|instr| instr.instruction) {
+                None => continue,
+                Some(ParsedVMInstruction::Function { name, .. }) => format!("`{name}`"),
+                Some(_) => format!("`{}` outside functions", file.name),
+            };
+            render_function(&mut report, &title, file, &scope, rom);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::vm_translator::{parse_source, translate, TranslateOptions};
+
+    #[test]
+    fn test_render() {
+        let source = "function Main.main 0
+label LOOP
+push constant 1
+if-goto LOOP
+push constant 0
+return
+label DEAD
+goto LOOP
+";
+        let files = vec![parse_source("Main.vm", source)];
+        let translation = translate(&files, &TranslateOptions::default());
+        let report = render(&translation, &files);
+        assert!(report.contains("## `Main.main`\n\nIn `Main.vm`, 4 blocks, 1 unreachable.\n"));
+        assert!(report.contains("    b1 --> b1\n    b1 --> b2\n"));
+        assert!(report.contains("    b2 --> ret([return])\n"));
+        assert!(report.contains("    class b3 unreachable\n"));
+        assert!(report.contains("| b3 | lines 7-8 | 2 | 2 | no | b1 |\n"));
+    }
+}
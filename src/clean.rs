@@ -0,0 +1,101 @@
+use std::fs::{read, read_to_string, remove_dir, remove_file};
+use std::path::Path;
+
+use crate::hash::sha256;
+use crate::json::Json;
+
+// Artifacts written as directories, whose directory is removed with the
+// files once it's empty
+const DIRECTORY_KINDS: [&str; 2] = ["fragments", "cache"];
+
+// What became of an artifact listed in a manifest
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Removed,
+    // Changed since the translator wrote it, so it's no longer its own
+    Changed,
+    Missing,
+}
+
+// Removes an artifact if it still has the hash the manifest recorded
+pub fn clean_artifact(path: &Path, sha256_hex: &str) -> Outcome {
+    let Ok(contents) = read(path) else {
+        return Outcome::Missing;
+    };
+    if sha256(&contents) != sha256_hex {
+        return Outcome::Changed;
+    }
+    remove_file(path).unwrap_or_else(|_| panic!("Failed to remove {}", path.to_str().unwrap()));
+    Outcome::Removed
+}
+
+// Runs `vm_translator_rs clean <manifest.json>`, removing the artifacts
+// recorded in a build manifest and then the manifest. Inputs, and artifacts
+// modified since, are left alone.
+pub fn run(args: &[String]) {
+    let [manifest_path] = args else {
+        panic!("Usage: vm_translator_rs clean <manifest.json>");
+    };
+    let text = read_to_string(manifest_path)
+        .unwrap_or_else(|_| panic!("Failed to read manifest {manifest_path}"));
+    let manifest = Json::parse(&text)
+        .filter(|manifest| manifest.get("version") == Some(&Json::from(1usize)))
+        .unwrap_or_else(|| panic!("{manifest_path} is not a version 1 build manifest"));
+    let artifacts = manifest
+        .get("artifacts")
+        .and_then(Json::as_array)
+        .unwrap_or_else(|| panic!("Manifest {manifest_path} has no artifacts"));
+    let mut directories = vec![];
+    for artifact in artifacts {
+        let field = |name| artifact.get(name).and_then(Json::as_str);
+        let (Some(kind), Some(path), Some(hash)) = (field("kind"), field("path"), field("sha256"))
+        else {
+            panic!("Invalid artifact in manifest {manifest_path}: {artifact}");
+        };
+        let path = Path::new(path);
+        match clean_artifact(path, hash) {
+            Outcome::Removed => println!("Removed {}", path.to_str().unwrap()),
+            Outcome::Changed => println!(
+                "Kept {}, which changed after it was written",
+                path.to_str().unwrap()
+            ),
+            Outcome::Missing => {}
+        }
+        if DIRECTORY_KINDS.contains(&kind) {
+            directories.extend(path.parent());
+        }
+    }
+    directories.dedup();
+    for directory in directories {
+        // Fails, leaving the directory, if anything else is in it
+        if remove_dir(directory).is_ok() {
+            println!("Removed {}", directory.to_str().unwrap());
+        }
+    }
+    remove_file(manifest_path)
+        .unwrap_or_else(|_| panic!("Failed to remove manifest {manifest_path}"));
+    println!("Removed {manifest_path}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    use super::{clean_artifact, Outcome};
+    use crate::hash::sha256;
+
+    #[test]
+    fn test_clean_artifact() {
+        let dir = std::env::temp_dir().join(format!("vm_translator_clean_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let (asm, edited) = (dir.join("Main.asm"), dir.join("Main.lst"));
+        write(&asm, "@SP").unwrap();
+        write(&edited, "edited by hand").unwrap();
+        assert_eq!(clean_artifact(&asm, &sha256(b"@SP")), Outcome::Removed);
+        assert!(!asm.exists());
+        assert_eq!(clean_artifact(&asm, &sha256(b"@SP")), Outcome::Missing);
+        assert_eq!(clean_artifact(&edited, &sha256(b"@SP")), Outcome::Changed);
+        assert!(edited.exists());
+        remove_dir_all(&dir).unwrap();
+    }
+}
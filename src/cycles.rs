@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+
+use crate::intern::Name;
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{SourceInstruction, Translation, VMFile};
+
+// Estimates of running time in Hack cycles, at one cycle per instruction. All
+// the code generated for a VM instruction is counted, so comparisons cost
+// their longer path. A loop runs as many times as a `// @bound <n>` comment on
+// the line of its label, or the line before, says. Code in unbounded loops and
+// recursive functions has no estimate, except for a loop of just a label and a
+// `goto` back to it, which halts the program and isn't counted.
+const BOUND_ANNOTATION: &str = "@bound";
+
+pub struct FunctionCycles {
+    // One call, not counting the functions it calls
+    pub own: Option<usize>,
+    // One call, with the functions it calls
+    pub total: Option<usize>,
+}
+
+pub struct Cycles {
+    // Per file, the cycles of the VM instruction on each line
+    pub instructions: Vec<HashMap<usize, usize>>,
+    // Per function of the source map, in the same order
+    pub functions: Vec<FunctionCycles>,
+    // The generated code, the code preceding the first function of each file
+    // and Sys.init, each run once
+    pub program: Option<usize>,
+}
+
+// A function, or the code preceding the first function of a file
+struct Scope {
+    name: Option<Name>,
+    own: Option<usize>,
+    // Called functions with the times they're called
+    calls: Vec<(Name, Option<usize>)>,
+}
+
+// Loop bounds by the line of the comment giving them
+fn bounds(file: &VMFile) -> HashMap<usize, usize> {
+    file.comments()
+        .filter_map(|(line, comment)| {
+            let bound = comment.trim().strip_prefix(BOUND_ANNOTATION)?;
+            Some((line, bound.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+// The times each instruction runs per run through its scope: the product of
+// the bounds of the loops around it. A loop spans from its label to the last
+// jump back to it.
+fn multipliers(
+    instructions: &[SourceInstruction],
+    bounds: &HashMap<usize, usize>,
+) -> Vec<Option<usize>> {
+    let mut labels = HashMap::new();
+    let mut loops: HashMap<usize, usize> = HashMap::new();
+    for (i, instr) in instructions.iter().enumerate() {
+        match instr.instruction {
+            ParsedVMInstruction::Label { label } => {
+                labels.entry(label).or_insert(i);
+            }
+            ParsedVMInstruction::Goto { label } | ParsedVMInstruction::IfGoto { label } => {
+                if let Some(&start) = labels.get(&label) {
+                    loops.insert(start, i);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut times = vec![Some(1); instructions.len()];
+    for (start, end) in loops {
+        let line = instructions[start].line;
+        let halt = end == start + 1
+            && matches!(
+                instructions[end].instruction,
+                ParsedVMInstruction::Goto { .. }
+            );
+        let bound = if halt {
+            Some(0)
+        } else {
+            bounds.get(&line).or(bounds.get(&(line - 1))).copied()
+        };
+        for time in &mut times[start..=end] {
+            *time = time.zip(bound).map(|(time, bound)| time * bound);
+        }
+    }
+    times
+}
+
+fn scopes(file: &VMFile, cycles: &HashMap<usize, usize>) -> Vec<Scope> {
+    let bounds = bounds(file);
+    let mut starts: Vec<usize> = file
+        .instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, instr)| matches!(instr.instruction, ParsedVMInstruction::Function { .. }))
+        .map(|(i, _)| i)
+        .collect();
+    starts.insert(0, 0);
+    starts.push(file.instructions.len());
+    starts
+        .windows(2)
+        .map(|range| {
+            let instructions = &file.instructions[range[0]..range[1]];
+            let name = match instructions.first().map(|instr| instr.instruction) {
+                Some(ParsedVMInstruction::Function { name, .. }) => Some(name),
+                _ => None,
+            };
+            let times = multipliers(instructions, &bounds);
+            let own = instructions
+                .iter()
+                .zip(&times)
+                .map(|(instr, times)| {
+                    let cycles = cycles.get(&instr.line).copied().unwrap_or(0);
+                    times.map(|times| times * cycles)
+                })
+                .sum();
+            let calls = instructions
+                .iter()
+                .zip(&times)
+                .filter_map(|(instr, &times)| match instr.instruction {
+                    ParsedVMInstruction::Call { name, .. } => Some((name, times)),
+                    _ => None,
+                })
+                .collect();
+            Scope { name, own, calls }
+        })
+        .collect()
+}
+
+// One run through the scope, with the functions it calls. Functions outside
+// the program only count for the code calling them.
+fn total(
+    scope: &Scope,
+    defined: &HashMap<Name, &Scope>,
+    totals: &mut HashMap<Name, Option<usize>>,
+    active: &mut Vec<Name>,
+) -> Option<usize> {
+    let mut cycles = scope.own?;
+    for &(callee, times) in &scope.calls {
+        let Some(callee_scope) = defined.get(&callee) else {
+            continue;
+        };
+        let callee_total = match totals.get(&callee) {
+            Some(&callee_total) => callee_total,
+            None if active.contains(&callee) => None,
+            None => {
+                active.push(callee);
+                let callee_total = total(callee_scope, defined, totals, active);
+                active.pop();
+                totals.insert(callee, callee_total);
+                callee_total
+            }
+        };
+        cycles += times? * callee_total?;
+    }
+    Some(cycles)
+}
+
+impl Cycles {
+    pub fn estimate(translation: &Translation, files: &[VMFile]) -> Self {
+        let mut instructions = vec![HashMap::new(); files.len()];
+        let mut generated = 0;
+        for entry in translation.source_map.entries() {
+            if entry.rom_addr.is_none() {
+                continue;
+            }
+            match (entry.source, entry.vm_line) {
+                (Some(source), Some(line)) => *instructions[source].entry(line).or_default() += 1,
+                _ => generated += 1,
+            }
+        }
+
+        let scopes: Vec<Scope> = files
+            .iter()
+            .zip(&instructions)
+            .flat_map(|(file, cycles)| scopes(file, cycles))
+            .collect();
+        let mut defined = HashMap::new();
+        for scope in &scopes {
+            if let Some(name) = scope.name {
+                defined.entry(name).or_insert(scope);
+            }
+        }
+        let mut totals = HashMap::new();
+        let mut function_total = |name: Name| {
+            let scope = defined.get(&name)?;
+            total(scope, &defined, &mut totals, &mut vec![name])
+        };
+        let functions = translation
+            .source_map
+            .functions()
+            .iter()
+            .map(|&name| FunctionCycles {
+                own: defined.get(&name).and_then(|scope| scope.own),
+                total: function_total(name),
+            })
+            .collect();
+
+        let sys_init = Name::new("Sys.init");
+        let mut program = Some(generated);
+        if defined.contains_key(&sys_init) {
+            program = program.zip(function_total(sys_init)).map(|(a, b)| a + b);
+        }
+        for scope in scopes.iter().filter(|scope| scope.name.is_none()) {
+            let cycles = total(scope, &defined, &mut totals, &mut vec![]);
+            program = program.zip(cycles).map(|(a, b)| a + b);
+        }
+
+        Self {
+            instructions,
+            functions,
+            program,
+        }
+    }
+}
+
+pub fn describe(cycles: Option<usize>) -> String {
+    match cycles {
+        Some(cycles) => cycles.to_string(),
+        None => String::from("unbounded"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cycles;
+    use crate::vm_translator::{parse_source, translate, TranslateOptions};
+
+    #[test]
+    fn test_estimate() {
+        let source = "function Main.main 0
+push constant 3
+pop local 0
+label LOOP // @bound 3
+call Main.step 0
+pop temp 0
+push local 0
+push constant 1
+sub
+pop local 0
+push local 0
+if-goto LOOP
+push constant 0
+return
+function Main.step 0
+push constant 0
+return
+function Main.spin 0
+label WAIT
+push constant 1
+if-goto WAIT
+label HALT
+goto HALT
+function Main.recurse 0
+call Main.recurse 0
+return
+";
+        let files = vec![parse_source("Main.vm", source)];
+        let translation = translate(&files, &TranslateOptions::default());
+        let cycles = Cycles::estimate(&translation, &files);
+        let [main, step, spin, recurse] = &cycles.functions[..] else {
+            panic!("expected 4 functions");
+        };
+        let lines = &cycles.instructions[0];
+        let line_cycles = |range: std::ops::RangeInclusive<usize>| -> usize {
+            range.filter_map(|line| lines.get(&line)).sum()
+        };
+        // The loop body (lines 4 to 12) runs 3 times
+        let main_own = line_cycles(1..=3) + 3 * line_cycles(4..=12) + line_cycles(13..=14);
+        assert_eq!(main.own, Some(main_own));
+        assert_eq!(step.own, Some(line_cycles(15..=17)));
+        assert_eq!(step.total, step.own);
+        assert_eq!(main.total, Some(main_own + 3 * line_cycles(15..=17)));
+        // Unbounded, unlike the halt loop after it
+        assert_eq!(spin.own, None);
+        assert!(recurse.own.is_some());
+        assert_eq!(recurse.total, None);
+        assert_eq!(cycles.program, Some(0));
+    }
+}
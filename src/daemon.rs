@@ -0,0 +1,598 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::diagnostics::{self, Diagnostic};
+use crate::json::Json;
+use crate::limits::LimitExceeded;
+use crate::options::{self, Options};
+use crate::plugin;
+use crate::run::source_times;
+use crate::vm_translator::{self, Translation, VMFile};
+
+// Keeps the translator resident, answering JSON-RPC 2.0 requests, one per
+// line, over stdin and stdout or a Unix socket. Parsed files are kept between
+// requests and parsed again only once modified, and so is the last
+// translation of each input with each set of options, so editors and build
+// servers don't pay for starting the translator and parsing unchanged files,
+// such as the OS's, on every request. Only the most recently used builds are
+// kept, and the files they parsed; the interned names of every program stay
+// for the daemon's lifetime (see intern.rs).
+
+const USAGE: &str = "Usage: vm_translator_rs daemon [--socket=<path>]";
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// The translation failed, with the translator's message
+const TRANSLATION_ERROR: i64 = -32000;
+// The input exceeded --max-input-size or --max-instructions
+const LIMIT_EXCEEDED: i64 = -32001;
+
+const MAX_BUILDS: usize = 16;
+
+type Error = (i64, String);
+
+fn invalid_params(message: &str) -> Error {
+    (INVALID_PARAMS, message.to_owned())
+}
+
+fn limit_exceeded(exceeded: LimitExceeded) -> Error {
+    (LIMIT_EXCEEDED, format!("Limit exceeded: {exceeded}"))
+}
+
+// The first option given that the daemon doesn't honour, as it only writes
+// the assembly
+fn unsupported_option(options: &Options) -> Option<&'static str> {
+    let unsupported = [
+        (options.stream, "--stream"),
+        (options.backend.is_some(), "--backend"),
+        (options.source_map, "--emit=map"),
+        (options.listing, "--emit=listing"),
+        (options.emit_symbols, "--emit=symbols"),
+        (options.split_functions, "--emit=fragments"),
+        (!options.emit_ir.is_empty(), "--emit=ir-json and ir-xml"),
+        (
+            !options.emit_machine_code.is_empty(),
+            "--emit=hack, hex and bin",
+        ),
+        (options.verify_asm, "--verify-asm"),
+        (options.mangle_map.is_some(), "--mangle-map"),
+        (options.cache, "--cache"),
+        (options.since.is_some(), "--since"),
+        (options.stats_json.is_some(), "--stats-json"),
+        (options.report.is_some(), "--report"),
+        (options.cfg_report.is_some(), "--cfg-report"),
+        (options.manifest.is_some(), "--manifest"),
+    ];
+    (unsupported.iter())
+        .find(|(given, _)| *given)
+        .map(|&(_, option)| option)
+}
+
+// The options of a request, after those set by VM_TRANSLATOR_ environment
+// variables, as for the translator
+fn parse_options((input, args): &BuildKey) -> Options {
+    let args: Vec<String> = (options::env_args(env::vars(), args).into_iter())
+        .chain(args.iter().cloned())
+        .chain([input.to_str().unwrap().to_owned()])
+        .collect();
+    Options::from_args(&args)
+}
+
+// The files besides the sources that the translation reads, with their
+// modification times
+fn option_files(options: &Options) -> Vec<(PathBuf, Option<SystemTime>)> {
+    [&options.header_file, &options.target_profile]
+        .into_iter()
+        .flatten()
+        .map(|path| {
+            let modified = path
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            (path.clone(), modified)
+        })
+        .collect()
+}
+
+// A translated input, kept until one of its files changes
+struct Build {
+    // The sources, then the header and target profile
+    sources: Vec<(PathBuf, Option<SystemTime>)>,
+    options: Options,
+    translation: Translation,
+    diagnostics: Vec<Diagnostic>,
+    // The request that last used it, for evicting the least recently used
+    used: u64,
+}
+
+// Builds are kept by input and options, as given in the request
+type BuildKey = (PathBuf, Vec<String>);
+
+fn build_key(params: &Json) -> Result<BuildKey, Error> {
+    let input = params
+        .get("input")
+        .and_then(Json::as_str)
+        .ok_or_else(|| invalid_params("input must be a path"))?;
+    let options = match params.get("options") {
+        None => vec![],
+        Some(options) => (options.as_array())
+            .and_then(|options| {
+                let options = options
+                    .iter()
+                    .map(|option| option.as_str().map(str::to_owned));
+                options.collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| invalid_params("options must be an array of strings"))?,
+    };
+    Ok((PathBuf::from(input), options))
+}
+
+fn diagnostic_json(diagnostic: &Diagnostic) -> Json {
+    Json::object(vec![
+        ("file", Json::from(diagnostic.file.clone())),
+        ("line", Json::from(diagnostic.line)),
+        ("message", Json::from(diagnostic.message.as_str())),
+    ])
+}
+
+#[derive(Default)]
+struct Daemon {
+    // Parsed files by path, with the modification time they were parsed at
+    parsed: HashMap<PathBuf, (Option<SystemTime>, VMFile)>,
+    builds: HashMap<BuildKey, Build>,
+    requests: u64,
+}
+
+impl Daemon {
+    fn parse(&mut self, path: &Path, modified: Option<SystemTime>) -> VMFile {
+        match self.parsed.get(path) {
+            Some((parsed_at, file)) if modified.is_some() && *parsed_at == modified => file.clone(),
+            _ => {
+                let file = vm_translator::parse_file(path);
+                self.parsed
+                    .insert(path.to_path_buf(), (modified, file.clone()));
+                file
+            }
+        }
+    }
+
+    // Translates the input as the translator would with the options, unless
+    // it already has since its files, the header or the target profile last
+    // changed
+    fn build(&mut self, params: &Json) -> Result<&Build, Error> {
+        let key = build_key(params)?;
+        let options = parse_options(&key);
+        let vm_sources = source_times(&key.0);
+        let sources: Vec<_> = (vm_sources.iter().cloned())
+            .chain(option_files(&options))
+            .collect();
+        let existing = self.builds.get(&key);
+        if existing.is_none_or(|build| build.sources != sources) {
+            let mut build = self.translate(&key.0, options, vm_sources)?;
+            build.sources = sources;
+            self.builds.insert(key.clone(), build);
+        }
+        self.requests += 1;
+        self.builds.get_mut(&key).unwrap().used = self.requests;
+        self.evict();
+        Ok(&self.builds[&key])
+    }
+
+    // Drops the builds of inputs that are gone and the least recently used
+    // beyond MAX_BUILDS, and the parsed files no build kept has
+    fn evict(&mut self) {
+        self.builds.retain(|(input, _), _| input.exists());
+        while self.builds.len() > MAX_BUILDS {
+            let oldest = (self.builds.iter())
+                .min_by_key(|(_, build)| build.used)
+                .map(|(key, _)| key.clone())
+                .unwrap();
+            self.builds.remove(&oldest);
+        }
+        let sources: HashSet<&PathBuf> = (self.builds.values())
+            .flat_map(|build| build.sources.iter().map(|(path, _)| path))
+            .collect();
+        self.parsed.retain(|path, _| sources.contains(path));
+    }
+
+    fn translate(
+        &mut self,
+        input: &Path,
+        options: Options,
+        sources: Vec<(PathBuf, Option<SystemTime>)>,
+    ) -> Result<Build, Error> {
+        if let Some(option) = unsupported_option(&options) {
+            return Err(invalid_params(&format!(
+                "{option} isn't supported by the daemon"
+            )));
+        }
+        let paths: Vec<PathBuf> = sources.iter().map(|(path, _)| path.clone()).collect();
+        (options.limits.check_input_size(&paths)).map_err(limit_exceeded)?;
+        let mut translate_options = crate::translate_options(&options, &crate::asm_outfile(input));
+        // Directories and bundles hold whole programs, which need a bootstrap
+        let (files, whole_program) = if input.is_dir() {
+            let modified: HashMap<&PathBuf, Option<SystemTime>> =
+                sources.iter().map(|(path, time)| (path, *time)).collect();
+            let files = vm_translator::list_directory(input)
+                .iter()
+                .map(|path| self.parse(path, modified.get(path).copied().flatten()))
+                .collect();
+            (files, true)
+        } else {
+            vm_translator::parse_input(input)
+        };
+        translate_options.bootstrap = whole_program;
+        let files = plugin::run_passes(&options.passes, files);
+        vm_translator::check_extensions(&files, &options.extensions);
+        (options.limits.check_instructions(&files)).map_err(limit_exceeded)?;
+        let diagnostics = diagnostics::check(&files, whole_program, &options.target.layout);
+        let translation = vm_translator::translate(&files, &translate_options);
+        options.target.check_rom(translation.rom_instructions);
+        Ok(Build {
+            sources,
+            options,
+            translation,
+            diagnostics,
+            used: 0,
+        })
+    }
+
+    fn call(&mut self, method: &str, params: &Json) -> Result<Json, Error> {
+        match method {
+            // Writes the assembly, like the translator without other outputs
+            "translate" => {
+                let input = build_key(params)?.0;
+                let build = self.build(params)?;
+                let outfile = crate::asm_outfile(&input);
+                let output = build.options.emit_asm.then(|| {
+                    let asm: Vec<_> = (build.translation.asm.iter())
+                        .map(|line| build.options.dialect.apply(line))
+                        .collect();
+                    if build.options.compress {
+                        let outfile = outfile.with_extension("asm.gz");
+                        crate::write_compressed(&outfile, &asm);
+                        outfile
+                    } else {
+                        crate::write_lines(&outfile, &asm);
+                        outfile
+                    }
+                });
+                let output = output.map(|path| path.to_str().unwrap().to_owned());
+                let warnings: Vec<Json> = build.diagnostics.iter().map(diagnostic_json).collect();
+                Ok(Json::object(vec![
+                    ("output", Json::from(output)),
+                    (
+                        "instructions",
+                        Json::from(build.translation.rom_instructions),
+                    ),
+                    ("warnings", Json::from(warnings)),
+                ]))
+            }
+            "check" => {
+                let build = self.build(params)?;
+                let warnings: Vec<Json> = build.diagnostics.iter().map(diagnostic_json).collect();
+                Ok(Json::object(vec![("warnings", Json::from(warnings))]))
+            }
+            "symbols" => {
+                let build = self.build(params)?;
+                let symbols: Vec<Json> = (build.translation.symbols.symbols().iter())
+                    .map(|symbol| {
+                        Json::object(vec![
+                            ("kind", Json::from(symbol.kind.name())),
+                            ("address", Json::from(symbol.address)),
+                            ("name", Json::from(symbol.name.as_str())),
+                            (
+                                "vm_label",
+                                Json::from(symbol.vm_label.map(|label| label.as_str())),
+                            ),
+                        ])
+                    })
+                    .collect();
+                Ok(Json::object(vec![("symbols", Json::from(symbols))]))
+            }
+            // The VM line of a ROM address, or the ROM addresses of a VM line
+            "source-map" => {
+                let address = params.get("address").map(Json::as_number);
+                let line = params.get("line").map(Json::as_number);
+                let file = params.get("file").map(Json::as_str);
+                let source_map = &self.build(params)?.translation.source_map;
+                let (sources, functions) = (source_map.sources(), source_map.functions());
+                match (address, file, line) {
+                    (Some(Some(address)), None, None) => {
+                        let entry = (source_map.entries().iter())
+                            .find(|entry| entry.rom_addr.map(i64::from) == Some(address));
+                        Ok(entry.map_or(Json::Null, |entry| {
+                            Json::object(vec![
+                                (
+                                    "file",
+                                    Json::from(entry.source.map(|source| sources[source].as_str())),
+                                ),
+                                ("line", Json::from(entry.vm_line)),
+                                (
+                                    "function",
+                                    Json::from(entry.function.map(|f| functions[f].as_str())),
+                                ),
+                            ])
+                        }))
+                    }
+                    (None, Some(Some(file)), Some(Some(line))) => {
+                        let addresses: Vec<Json> = (source_map.entries().iter())
+                            .filter(|entry| {
+                                entry.source.map(|source| sources[source].as_str()) == Some(file)
+                                    && entry.vm_line.map(|line| line as i64) == Some(line)
+                            })
+                            .filter_map(|entry| entry.rom_addr.map(Json::from))
+                            .collect();
+                        Ok(Json::object(vec![("addresses", Json::from(addresses))]))
+                    }
+                    _ => Err(invalid_params("expected an address, or a file and a line")),
+                }
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {method}"))),
+        }
+    }
+
+    // The response to a request, if it has an id, and whether it's asking
+    // the daemon to shut down
+    fn handle(&mut self, line: &str) -> (Option<String>, bool) {
+        let respond = |id: Json, outcome: Result<Json, Error>| {
+            let field = match outcome {
+                Ok(result) => ("result", result),
+                Err((code, message)) => (
+                    "error",
+                    Json::object(vec![
+                        ("code", Json::Number(code)),
+                        ("message", Json::from(message)),
+                    ]),
+                ),
+            };
+            let response = Json::object(vec![("jsonrpc", Json::from("2.0")), ("id", id), field]);
+            Some(response.to_string())
+        };
+        let Some(request) = Json::parse(line) else {
+            let error = (PARSE_ERROR, String::from("Invalid JSON"));
+            return (respond(Json::Null, Err(error)), false);
+        };
+        let id = request.get("id").cloned();
+        let Some(method) = request.get("method").and_then(Json::as_str) else {
+            let error = (INVALID_REQUEST, String::from("Missing method"));
+            return (respond(id.unwrap_or(Json::Null), Err(error)), false);
+        };
+        let shutdown = method == "shutdown";
+        let outcome = if shutdown {
+            Ok(Json::Null)
+        } else {
+            let params = request
+                .get("params")
+                .cloned()
+                .unwrap_or(Json::Object(vec![]));
+            // Failed requests are reported in their responses, so the panic
+            // hook is silenced while one runs. Invalid options are reported
+            // without the usage that follows.
+            let hook = panic::take_hook();
+            panic::set_hook(Box::new(|_| {}));
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| self.call(method, &params)));
+            panic::set_hook(hook);
+            outcome.unwrap_or_else(|payload| {
+                let message = crate::panic_message(&*payload);
+                let message = message.split("\nUsage:").next().unwrap_or_default();
+                Err((TRANSLATION_ERROR, message.to_owned()))
+            })
+        };
+        // Notifications, without an id, get no response
+        (id.and_then(|id| respond(id, outcome)), shutdown)
+    }
+
+    // Answers the requests read until the end of the input, or a shutdown
+    // request, which it returns true for
+    fn serve<R: BufRead, W: Write>(&mut self, reader: R, mut writer: W) -> io::Result<bool> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (response, shutdown) = self.handle(&line);
+            if let Some(response) = response {
+                writeln!(writer, "{response}")?;
+                writer.flush()?;
+            }
+            if shutdown {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// Removes the socket file when the daemon stops, however it stops
+#[cfg(unix)]
+struct SocketFile<'a>(&'a Path);
+
+#[cfg(unix)]
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(self.0);
+    }
+}
+
+#[cfg(unix)]
+fn serve_socket(daemon: &mut Daemon, path: &Path) -> io::Result<()> {
+    use std::io::BufReader;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    // A socket left behind by a daemon that was killed is replaced, but not
+    // one a daemon is still listening on, nor a file that isn't a socket
+    let is_socket = path
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket {
+        if UnixStream::connect(path).is_ok() {
+            panic!("Another daemon is listening on {}", path.to_str().unwrap());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let _socket_file = SocketFile(path);
+    // One connection at a time, so requests never run concurrently
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if daemon.serve(BufReader::new(stream.try_clone()?), &stream)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_socket(_: &mut Daemon, _: &Path) -> io::Result<()> {
+    panic!("--socket needs Unix domain sockets");
+}
+
+// Runs `vm_translator_rs daemon [--socket=<path>]` until a shutdown request
+// or the end of the input
+pub fn run(args: &[String]) {
+    let socket = match args {
+        [] => None,
+        [arg] => match arg.split_once('=') {
+            Some(("--socket", path)) => Some(PathBuf::from(path)),
+            _ => panic!("{USAGE}"),
+        },
+        _ => panic!("{USAGE}"),
+    };
+    let mut daemon = Daemon::default();
+    let served = match &socket {
+        Some(path) => serve_socket(&mut daemon, path),
+        None => daemon
+            .serve(io::stdin().lock(), io::stdout().lock())
+            .map(|_| ()),
+    };
+    served.unwrap_or_else(|error| panic!("Daemon failed: {error}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Daemon;
+    use crate::json::Json;
+    use std::fs::{create_dir_all, read_to_string, remove_dir_all, write, File};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_handle() {
+        let dir = std::env::temp_dir().join(format!("vm_translator_daemon_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(
+            dir.join("Sys.vm"),
+            "function Sys.init 0\nlabel END\ngoto END\n",
+        )
+        .unwrap();
+        write(
+            dir.join("Main.vm"),
+            "function Main.main 0\npush constant 1\nreturn\n",
+        )
+        .unwrap();
+        let input = dir.to_str().unwrap();
+        let mut daemon = Daemon::default();
+        // The result of a request, or its error
+        let mut request = |method: &str, params: String| {
+            let (response, _) = daemon.handle(&format!(
+                r#"{{"jsonrpc":"2.0","id":1,"method":"{method}","params":{params}}}"#
+            ));
+            let response = Json::parse(&response.unwrap()).unwrap();
+            match response.get("result") {
+                Some(result) => Ok(result.clone()),
+                None => Err(response.get("error").unwrap().clone()),
+            }
+        };
+
+        let params = format!(r#"{{"input":"{input}"}}"#);
+        let translated = request("translate", params.clone()).unwrap();
+        let outfile = crate::asm_outfile(&dir);
+        assert_eq!(
+            translated.get("output"),
+            Some(&Json::from(outfile.to_str().unwrap()))
+        );
+        assert!(outfile.exists());
+
+        // Editing the header translates the input again
+        let header = dir.join("header.txt");
+        write(&header, "v1").unwrap();
+        let with_header = format!(
+            r#"{{"input":"{input}","options":["--header-file={}"]}}"#,
+            header.to_str().unwrap()
+        );
+        request("translate", with_header.clone()).unwrap();
+        assert!(read_to_string(&outfile).unwrap().starts_with("// v1\n"));
+        write(&header, "v2").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&header)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        request("translate", with_header).unwrap();
+        assert!(read_to_string(&outfile).unwrap().starts_with("// v2\n"));
+
+        let symbols = request("symbols", params.clone()).unwrap();
+        let main = Json::from("Main.main");
+        let symbols = symbols.get("symbols").and_then(Json::as_array).unwrap();
+        assert!(symbols
+            .iter()
+            .any(|symbol| symbol.get("name") == Some(&main)));
+
+        let lookup = format!(r#"{{"input":"{input}","file":"Main.vm","line":2}}"#);
+        let addresses = request("source-map", lookup).unwrap();
+        let address = addresses.get("addresses").and_then(Json::as_array).unwrap()[0].clone();
+        let lookup = format!(r#"{{"input":"{input}","address":{address}}}"#);
+        let location = request("source-map", lookup).unwrap();
+        assert_eq!(location.get("function"), Some(&main));
+        assert_eq!(location.get("line"), Some(&Json::from(2usize)));
+
+        let error = request("frobnicate", params.clone()).unwrap_err();
+        assert_eq!(error.get("code"), Some(&Json::Number(-32601)));
+        let unsupported = format!(r#"{{"input":"{input}","options":["--emit=asm,listing"]}}"#);
+        let error = request("translate", unsupported).unwrap_err();
+        assert_eq!(error.get("code"), Some(&Json::Number(-32602)));
+        assert_eq!(
+            error.get("message"),
+            Some(&Json::from("--emit=listing isn't supported by the daemon"))
+        );
+        let limited = format!(r#"{{"input":"{input}","options":["--max-instructions=3"]}}"#);
+        let error = request("check", limited).unwrap_err();
+        assert_eq!(error.get("code"), Some(&Json::Number(-32001)));
+        assert_eq!(
+            error.get("message"),
+            Some(&Json::from(
+                "Limit exceeded: 6 is over --max-instructions=3"
+            ))
+        );
+
+        // A build of an input that's gone is dropped, along with its files
+        let other = dir.join("Other");
+        create_dir_all(&other).unwrap();
+        write(other.join("Sys.vm"), "function Sys.init 0\n").unwrap();
+        let other_params = format!(r#"{{"input":"{}"}}"#, other.to_str().unwrap());
+        request("check", other_params).unwrap();
+        remove_dir_all(&other).unwrap();
+        request("check", params).unwrap();
+        let error = request(
+            "check",
+            String::from(r#"{"input":"/nonexistent/Missing.vm"}"#),
+        );
+        assert_eq!(error.unwrap_err().get("code"), Some(&Json::Number(-32000)));
+        // The plain and header builds of the input are kept, not Other's
+        assert_eq!(daemon.builds.len(), 2);
+        assert_eq!(daemon.parsed.len(), 2);
+        let shutdown = daemon.handle(r#"{"jsonrpc":"2.0","method":"shutdown"}"#);
+        assert_eq!(shutdown, (None, true));
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,684 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::call_graph::CallGraph;
+use crate::intern::Name;
+use crate::target::{MemoryLayout, Word};
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{MemorySegment, SourceInstruction, VMFile};
+
+// A problem found in a VM program that doesn't prevent its translation, but
+// will most likely make the generated assembly fail to assemble or misbehave
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{file}:{line}: warning: {}", self.message),
+            (Some(file), None) => write!(f, "{file}: warning: {}", self.message),
+            _ => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+// The instructions of each function, preceded by any code before the first
+// function
+pub fn function_scopes(file: &VMFile) -> Vec<Vec<&SourceInstruction>> {
+    let mut scopes: Vec<Vec<_>> = vec![vec![]];
+    for instr in &file.instructions {
+        if let ParsedVMInstruction::Function { .. } = instr.instruction {
+            scopes.push(vec![]);
+        }
+        scopes.last_mut().unwrap().push(instr);
+    }
+    scopes
+}
+
+fn check_labels(file: &VMFile, diagnostics: &mut Vec<Diagnostic>) {
+    // Labels are scoped to the function they're declared in, so each function
+    // (and any code preceding the first function) is checked on its own
+    for scope in function_scopes(file) {
+        let mut declared = HashSet::new();
+        for instr in &scope {
+            if let ParsedVMInstruction::Label { label } = &instr.instruction {
+                if !declared.insert(label.as_str()) {
+                    diagnostics.push(Diagnostic {
+                        file: Some(file.name.clone()),
+                        line: Some(instr.line),
+                        message: format!("duplicate label {label}"),
+                    });
+                }
+            }
+        }
+        for instr in &scope {
+            match &instr.instruction {
+                ParsedVMInstruction::Goto { label } | ParsedVMInstruction::IfGoto { label }
+                    if !declared.contains(label.as_str()) =>
+                {
+                    diagnostics.push(Diagnostic {
+                        file: Some(file.name.clone()),
+                        line: Some(instr.line),
+                        message: format!("jump to undefined label {label}"),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// What is known about a value on the stack: whether it's the result of a
+// comparison (true is -1, false 0), a number, or the bitwise `not` of a number
+// computed at the given line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ValueKind {
+    Boolean,
+    Number,
+    NotOfNumber(usize),
+    Unknown,
+}
+
+impl ValueKind {
+    fn join(self, other: ValueKind) -> ValueKind {
+        if self == other {
+            self
+        } else {
+            ValueKind::Unknown
+        }
+    }
+}
+
+fn pop_kind(stack: &mut Vec<ValueKind>) -> ValueKind {
+    stack.pop().unwrap_or(ValueKind::Unknown)
+}
+
+// Applies the instruction to the kinds of the values on the stack. Values
+// below those the function pushed are unknown.
+fn apply_kinds(stack: &mut Vec<ValueKind>, instr: &SourceInstruction) {
+    use ValueKind::*;
+    let result = match instr.instruction {
+        // 0 and 1 double as false and, negated, true
+        ParsedVMInstruction::Push {
+            segment: MemorySegment::Constant,
+            idx,
+        } if idx > 1 => Number,
+        ParsedVMInstruction::Push { .. } => Unknown,
+        ParsedVMInstruction::Pop { .. } | ParsedVMInstruction::IfGoto { .. } => {
+            pop_kind(stack);
+            return;
+        }
+        ParsedVMInstruction::Add | ParsedVMInstruction::Sub => {
+            pop_kind(stack);
+            pop_kind(stack);
+            Number
+        }
+        ParsedVMInstruction::Eq | ParsedVMInstruction::Gt | ParsedVMInstruction::Lt => {
+            pop_kind(stack);
+            pop_kind(stack);
+            Boolean
+        }
+        ParsedVMInstruction::And | ParsedVMInstruction::Or => {
+            match (pop_kind(stack), pop_kind(stack)) {
+                (Boolean, Boolean) => Boolean,
+                (Number | NotOfNumber(_), _) | (_, Number | NotOfNumber(_)) => Number,
+                _ => Unknown,
+            }
+        }
+        ParsedVMInstruction::Not => match pop_kind(stack) {
+            Boolean => Boolean,
+            Number => NotOfNumber(instr.line),
+            NotOfNumber(_) => Number,
+            Unknown => Unknown,
+        },
+        ParsedVMInstruction::Neg => match pop_kind(stack) {
+            Number => Number,
+            _ => Unknown,
+        },
+        ParsedVMInstruction::ShiftLeft | ParsedVMInstruction::ShiftRight => {
+            pop_kind(stack);
+            Number
+        }
+        ParsedVMInstruction::Call { num_args, .. } => {
+            for _ in 0..num_args {
+                pop_kind(stack);
+            }
+            Unknown
+        }
+        _ => return,
+    };
+    stack.push(result);
+}
+
+// The instructions control can go to after each instruction of a function,
+// by index, where the function's length stands for the code that follows it
+pub struct ControlFlow<'a> {
+    function: &'a [&'a SourceInstruction],
+    labels: HashMap<Name, usize>,
+}
+
+impl<'a> ControlFlow<'a> {
+    pub fn new(function: &'a [&'a SourceInstruction]) -> Self {
+        let labels = function
+            .iter()
+            .enumerate()
+            .filter_map(|(i, instr)| match instr.instruction {
+                ParsedVMInstruction::Label { label } => Some((label, i)),
+                _ => None,
+            })
+            .collect();
+        Self { function, labels }
+    }
+
+    pub fn successors(&self, i: usize) -> Vec<usize> {
+        match self.function[i].instruction {
+            ParsedVMInstruction::Return | ParsedVMInstruction::Halt { .. } => vec![],
+            ParsedVMInstruction::Goto { label } => {
+                self.labels.get(&label).copied().into_iter().collect()
+            }
+            ParsedVMInstruction::IfGoto { label } => {
+                let mut successors: Vec<usize> =
+                    self.labels.get(&label).copied().into_iter().collect();
+                successors.push(i + 1);
+                successors
+            }
+            _ => vec![i + 1],
+        }
+    }
+
+    // Whether control can run past the last instruction, into the code that
+    // follows, from the instruction after the declaration
+    fn falls_off_end(&self) -> bool {
+        let mut visited = vec![false; self.function.len()];
+        let mut pending = vec![1];
+        while let Some(i) = pending.pop() {
+            if i == self.function.len() {
+                return true;
+            }
+            if std::mem::replace(&mut visited[i], true) {
+                continue;
+            }
+            pending.extend(self.successors(i));
+        }
+        false
+    }
+
+    // The locals that may not have been popped to yet before each
+    // instruction, by a forward dataflow analysis. None for unreachable
+    // instructions.
+    fn unwritten_locals(&self, num_locals: Word) -> Vec<Option<Vec<bool>>> {
+        let mut unwritten: Vec<Option<Vec<bool>>> = vec![None; self.function.len() + 1];
+        unwritten[1] = Some(vec![true; num_locals as usize]);
+        let mut pending = vec![1];
+        while let Some(i) = pending.pop() {
+            if i == self.function.len() {
+                continue;
+            }
+            let mut after = unwritten[i].clone().unwrap();
+            if let ParsedVMInstruction::Pop {
+                segment: MemorySegment::Local,
+                idx,
+            } = self.function[i].instruction
+            {
+                if let Some(local) = after.get_mut(idx as usize) {
+                    *local = false;
+                }
+            }
+            for successor in self.successors(i) {
+                let changed = match &mut unwritten[successor] {
+                    Some(state) => {
+                        let mut changed = false;
+                        for (local, unwritten) in state.iter_mut().zip(&after) {
+                            if *unwritten && !*local {
+                                *local = true;
+                                changed = true;
+                            }
+                        }
+                        changed
+                    }
+                    state => {
+                        *state = Some(after.clone());
+                        true
+                    }
+                };
+                if changed {
+                    pending.push(successor);
+                }
+            }
+        }
+        unwritten
+    }
+
+    // The kinds of the values on the stack before each instruction, by a
+    // forward dataflow analysis. Where paths meet, their stacks are matched
+    // from the top, and only what they agree on is kept. None for unreachable
+    // instructions.
+    fn stack_kinds(&self) -> Vec<Option<Vec<ValueKind>>> {
+        let mut kinds: Vec<Option<Vec<ValueKind>>> = vec![None; self.function.len() + 1];
+        kinds[1] = Some(vec![]);
+        let mut pending = vec![1];
+        while let Some(i) = pending.pop() {
+            if i == self.function.len() {
+                continue;
+            }
+            let mut after = kinds[i].clone().unwrap();
+            apply_kinds(&mut after, self.function[i]);
+            for successor in self.successors(i) {
+                let changed = match &mut kinds[successor] {
+                    Some(state) => {
+                        let len = state.len().min(after.len());
+                        let mut changed = len < state.len();
+                        state.drain(..state.len() - len);
+                        for (kind, other) in state.iter_mut().zip(&after[after.len() - len..]) {
+                            let joined = kind.join(*other);
+                            changed |= joined != *kind;
+                            *kind = joined;
+                        }
+                        changed
+                    }
+                    state => {
+                        *state = Some(after.clone());
+                        true
+                    }
+                };
+                if changed {
+                    pending.push(successor);
+                }
+            }
+        }
+        kinds
+    }
+}
+
+// Warns where the result of a comparison is used as a number, or where `not`
+// of a number decides a jump: `not` is bitwise, so it's only false for -1
+fn check_value_kinds(
+    file: &VMFile,
+    function: &[&SourceInstruction],
+    control_flow: &ControlFlow,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for (instr, kinds) in function.iter().zip(control_flow.stack_kinds()) {
+        let Some(kinds) = kinds else {
+            continue;
+        };
+        let top = |n: usize| kinds.len().checked_sub(n).map(|i| kinds[i]);
+        match instr.instruction {
+            ParsedVMInstruction::Add | ParsedVMInstruction::Sub
+                if top(1) == Some(ValueKind::Boolean) || top(2) == Some(ValueKind::Boolean) =>
+            {
+                diagnostics.push(Diagnostic {
+                    file: Some(file.name.clone()),
+                    line: Some(instr.line),
+                    message: format!(
+                        "{} uses the result of a comparison (-1 or 0) as a number",
+                        instr.instruction.command()
+                    ),
+                });
+            }
+            ParsedVMInstruction::IfGoto { .. } => {
+                if let Some(ValueKind::NotOfNumber(line)) = top(1) {
+                    diagnostics.push(Diagnostic {
+                        file: Some(file.name.clone()),
+                        line: Some(line),
+                        message: String::from(
+                            "not of a number decides an if-goto; not is bitwise, so the jump \
+                             is taken unless the number is -1",
+                        ),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_functions(file: &VMFile, diagnostics: &mut Vec<Diagnostic>) {
+    for function in function_scopes(file).into_iter().skip(1) {
+        let ParsedVMInstruction::Function {
+            name,
+            num_local_vars,
+        } = function[0].instruction
+        else {
+            unreachable!();
+        };
+        let mut used = HashSet::new();
+        for instr in &function {
+            match instr.instruction {
+                ParsedVMInstruction::Push {
+                    segment: MemorySegment::Local,
+                    idx,
+                }
+                | ParsedVMInstruction::Pop {
+                    segment: MemorySegment::Local,
+                    idx,
+                } => {
+                    used.insert(idx);
+                }
+                _ => {}
+            }
+        }
+        let unused: Vec<String> = (0..num_local_vars)
+            .filter(|idx| !used.contains(idx))
+            .map(|idx| idx.to_string())
+            .collect();
+        if !unused.is_empty() {
+            diagnostics.push(Diagnostic {
+                file: Some(file.name.clone()),
+                line: Some(function[0].line),
+                message: format!(
+                    "function {name} never uses local{} {}",
+                    if unused.len() > 1 { "s" } else { "" },
+                    unused.join(", ")
+                ),
+            });
+        }
+        let control_flow = ControlFlow::new(&function);
+        let unwritten = control_flow.unwritten_locals(num_local_vars);
+        let mut reported = HashSet::new();
+        for (instr, unwritten) in function.iter().zip(&unwritten) {
+            if let (
+                ParsedVMInstruction::Push {
+                    segment: MemorySegment::Local,
+                    idx,
+                },
+                Some(unwritten),
+            ) = (instr.instruction, unwritten)
+            {
+                if unwritten.get(idx as usize) == Some(&true) && reported.insert(idx) {
+                    diagnostics.push(Diagnostic {
+                        file: Some(file.name.clone()),
+                        line: Some(instr.line),
+                        message: format!(
+                            "local {idx} of function {name} may be pushed before it's popped to"
+                        ),
+                    });
+                }
+            }
+        }
+        check_value_kinds(file, &function, &control_flow, diagnostics);
+        if control_flow.falls_off_end() {
+            diagnostics.push(Diagnostic {
+                file: Some(file.name.clone()),
+                line: Some(function.last().unwrap().line),
+                message: format!(
+                    "function {name} can run past its end without returning, into the code \
+                     that follows"
+                ),
+            });
+        }
+    }
+}
+
+fn check_calls(files: &[VMFile], call_graph: &CallGraph, diagnostics: &mut Vec<Diagnostic>) {
+    if call_graph.get(Name::new("Sys.init")).is_none() {
+        diagnostics.push(Diagnostic {
+            file: None,
+            line: None,
+            message: String::from("no Sys.init function is defined for the bootstrap to call"),
+        });
+    }
+    for file in files {
+        for instr in &file.instructions {
+            match &instr.instruction {
+                ParsedVMInstruction::Call { name, .. } if call_graph.get(*name).is_none() => {
+                    diagnostics.push(Diagnostic {
+                        file: Some(file.name.clone()),
+                        line: Some(instr.line),
+                        message: format!("call to undefined function {name}"),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Warns about recursive functions, whose stack usage has no bound, at the
+// first function of each cycle
+fn check_recursion(files: &[VMFile], call_graph: &CallGraph, diagnostics: &mut Vec<Diagnostic>) {
+    for cycle in &call_graph.recursion_cycles {
+        let first = call_graph.get(cycle[0]).unwrap();
+        let message = if let [name] = cycle[..] {
+            format!("function {name} calls itself recursively; the stack it uses")
+        } else {
+            let names: Vec<&str> = cycle.iter().map(|name| name.as_str()).collect();
+            format!(
+                "functions {} call each other recursively; the stack they use",
+                names.join(", ")
+            )
+        };
+        diagnostics.push(Diagnostic {
+            file: Some(files[first.file].name.clone()),
+            line: Some(first.line),
+            message: format!(
+                "{message} can't be bounded, and deep recursion overflows into the heap"
+            ),
+        });
+    }
+}
+
+// Warns about entry points whose deepest chain of calls alone needs more than
+// the stack region holds. Recursive functions aren't estimated.
+fn check_stack_depth(
+    call_graph: &CallGraph,
+    layout: &MemoryLayout,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let stack_size = layout.heap_base - layout.stack_base;
+    for entry in call_graph.entry_points() {
+        if let Some((depth, chain)) = call_graph.stack_depth(entry) {
+            if depth > stack_size {
+                let chain: Vec<&str> = chain.iter().map(|name| name.as_str()).collect();
+                diagnostics.push(Diagnostic {
+                    file: None,
+                    line: None,
+                    message: format!(
+                        "calls from {entry} can use {depth} words of stack, more than the \
+                         {stack_size} between stack_base and heap_base: {}",
+                        chain.join(" -> ")
+                    ),
+                });
+            }
+        }
+    }
+}
+
+// Checks a program for likely mistakes. Calls can only be checked when the
+// whole program is being translated, i.e. in directory mode.
+pub fn check(files: &[VMFile], whole_program: bool, layout: &MemoryLayout) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for file in files {
+        check_labels(file, &mut diagnostics);
+        check_functions(file, &mut diagnostics);
+    }
+    if whole_program {
+        let call_graph = CallGraph::build(files);
+        check_calls(files, &call_graph, &mut diagnostics);
+        check_recursion(files, &call_graph, &mut diagnostics);
+        check_stack_depth(&call_graph, layout, &mut diagnostics);
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use crate::target::MemoryLayout;
+    use crate::vm_translator::parse_source;
+
+    // The warnings about Main.vm made of the lines
+    fn warnings(lines: &[&str], whole_program: bool, layout: &MemoryLayout) -> Vec<String> {
+        let file = parse_source("Main.vm", &lines.join("\n"));
+        check(&[file], whole_program, layout)
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_labels_are_scoped_to_functions() {
+        let lines = [
+            "function Main.a 0",
+            "label LOOP",
+            "goto LOOP",
+            "function Main.b 0",
+            "goto LOOP",
+        ];
+        let messages = warnings(&lines, false, &MemoryLayout::default());
+        assert_eq!(
+            messages,
+            vec!["Main.vm:5: warning: jump to undefined label LOOP"]
+        );
+    }
+
+    #[test]
+    fn test_calls_checked_for_whole_program() {
+        let lines = ["function Sys.init 0", "call Main.missing 0", "return"];
+        let layout = MemoryLayout::default();
+        assert!(warnings(&["call Main.missing 0"], false, &layout).is_empty());
+        let messages = warnings(&lines, true, &layout);
+        assert_eq!(
+            messages,
+            vec!["Main.vm:2: warning: call to undefined function Main.missing"]
+        );
+    }
+
+    #[test]
+    fn test_stack_depth_exceeds_stack() {
+        let lines = [
+            "function Sys.init 0",
+            "call Main.main 300",
+            "return",
+            "function Main.main 0",
+            "return",
+        ];
+        let mut layout = MemoryLayout::default();
+        assert!(warnings(&lines, true, &layout).is_empty());
+        layout.set_all("heap_base=512");
+        let messages = warnings(&lines, true, &layout);
+        assert_eq!(
+            messages,
+            vec![
+                "warning: calls from Sys.init can use 310 words of stack, more than the 256 \
+                 between stack_base and heap_base: Sys.init -> Main.main"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        let lines = [
+            "function Sys.init 0",
+            "call Math.a 0",
+            "return",
+            "function Math.a 0",
+            "call Math.b 0",
+            "return",
+            "function Math.b 0",
+            "call Math.a 0",
+            "call Math.b 0",
+            "return",
+            "function Main.fib 0",
+            "call Main.fib 1",
+            "return",
+        ];
+        let messages = warnings(&lines, true, &MemoryLayout::default());
+        assert_eq!(
+            messages,
+            vec![
+                "Main.vm:4: warning: functions Math.a, Math.b call each other recursively; the \
+                 stack they use can't be bounded, and deep recursion overflows into the heap",
+                "Main.vm:11: warning: function Main.fib calls itself recursively; the stack \
+                 it uses can't be bounded, and deep recursion overflows into the heap"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unused_locals_and_missing_return() {
+        let lines = [
+            "function Main.f 3",
+            "push local 1",
+            "if-goto DONE",
+            "push constant 0",
+            "pop local 0",
+            "label DONE",
+            "function Main.g 0",
+            "label LOOP",
+            "goto LOOP",
+        ];
+        let messages = warnings(&lines, false, &MemoryLayout::default());
+        assert_eq!(
+            messages,
+            vec![
+                "Main.vm:1: warning: function Main.f never uses local 2",
+                "Main.vm:2: warning: local 1 of function Main.f may be pushed before it's popped to",
+                "Main.vm:6: warning: function Main.f can run past its end without returning, \
+                 into the code that follows"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_use_before_initialization() {
+        let lines = [
+            "function Main.f 2",
+            "push argument 0",
+            "if-goto ELSE",
+            "push constant 1",
+            "pop local 0",
+            "pop local 1",
+            "goto END",
+            "label ELSE",
+            "push constant 2",
+            "pop local 1",
+            "label END",
+            "push local 1",
+            "push local 0",
+            "return",
+        ];
+        let messages = warnings(&lines, false, &MemoryLayout::default());
+        assert_eq!(
+            messages,
+            vec!["Main.vm:13: warning: local 0 of function Main.f may be pushed before it's popped to"]
+        );
+    }
+
+    #[test]
+    fn test_boolean_misuse() {
+        let lines = [
+            "function Main.f 0",
+            "push argument 0",
+            "push constant 5",
+            "lt",
+            "push constant 1",
+            "add",
+            "push constant 7",
+            "not",
+            "if-goto DONE",
+            "push constant 0",
+            "not",
+            "if-goto DONE",
+            "push argument 0",
+            "push constant 3",
+            "eq",
+            "not",
+            "if-goto DONE",
+            "label DONE",
+            "return",
+        ];
+        let messages = warnings(&lines, false, &MemoryLayout::default());
+        assert_eq!(
+            messages,
+            vec![
+                "Main.vm:6: warning: add uses the result of a comparison (-1 or 0) as a number",
+                "Main.vm:8: warning: not of a number decides an if-goto; not is bitwise, so the \
+                 jump is taken unless the number is -1"
+            ]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::borrow::Cow;
+
+// Variations of the assembly syntax expected by third-party assemblers and
+// emulators, applied to each line as the assembly is written. The translator
+// itself, and everything derived from its output, such as listings and source
+// maps, always uses the standard syntax; the dialect changes no line's
+// position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dialect {
+    // Computations, destinations and jumps in lower case, e.g. `d=m+1`.
+    // Symbols keep their case.
+    pub lowercase: bool,
+    // Between a computation and its jump, `;` in the standard syntax
+    pub jump_separator: &'static str,
+    // Starts comments, `//` in the standard syntax
+    pub comment: String,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self {
+            lowercase: false,
+            jump_separator: ";",
+            comment: String::from("//"),
+        }
+    }
+}
+
+impl Dialect {
+    // Parses a comma-separated list of settings: case=upper|lower,
+    // jump-spacing=none|after|around and comment=<marker>
+    pub fn parse(settings: &str) -> Self {
+        let mut dialect = Self::default();
+        for setting in settings.split(',') {
+            match setting.split_once('=') {
+                Some(("case", "upper")) => dialect.lowercase = false,
+                Some(("case", "lower")) => dialect.lowercase = true,
+                Some(("jump-spacing", "none")) => dialect.jump_separator = ";",
+                Some(("jump-spacing", "after")) => dialect.jump_separator = "; ",
+                Some(("jump-spacing", "around")) => dialect.jump_separator = " ; ",
+                Some(("comment", marker)) if !marker.is_empty() => {
+                    dialect.comment = marker.to_owned()
+                }
+                _ => panic!("Invalid assembly dialect setting: {setting}"),
+            }
+        }
+        dialect
+    }
+
+    pub fn is_standard(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // The line in this dialect
+    pub fn apply<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        if self.is_standard() {
+            return Cow::Borrowed(line);
+        }
+        let (code, comment) = match line.find("//") {
+            Some(start) => (&line[..start], Some(&line[start + 2..])),
+            None => (line, None),
+        };
+        let instruction = code.trim();
+        let mut output = if instruction.is_empty()
+            || instruction.starts_with('@')
+            || instruction.starts_with('(')
+        {
+            code.to_owned()
+        } else {
+            let code = code.replace(';', self.jump_separator);
+            if self.lowercase {
+                code.to_lowercase()
+            } else {
+                code
+            }
+        };
+        if let Some(comment) = comment {
+            output.push_str(&self.comment);
+            output.push_str(comment);
+        }
+        Cow::Owned(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dialect;
+
+    #[test]
+    fn test_apply() {
+        let dialect = Dialect::parse("case=lower,jump-spacing=after,comment=#");
+        assert_eq!(dialect.apply("D=M+1"), "d=m+1");
+        assert_eq!(dialect.apply("D;JGT // compare"), "d; jgt # compare");
+        assert_eq!(dialect.apply("@Main.main$LOOP"), "@Main.main$LOOP");
+        assert_eq!(dialect.apply("(Main.main$LOOP)"), "(Main.main$LOOP)");
+        assert_eq!(dialect.apply("// push constant 1"), "# push constant 1");
+        let standard = Dialect::parse("case=upper,jump-spacing=none,comment=//");
+        assert!(standard.is_standard());
+        assert_eq!(standard.apply("0;JMP // halt"), "0;JMP // halt");
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::hash::sha256;
+use crate::json::Json;
+use crate::target::{MemoryLayout, Target, Word};
+use crate::vm_translator::BREAKPOINT;
+
+// A Hack CPU running assembled code, for checking what translated programs
+// do. The RAM and word width are the target's; words are held sign-extended
+// in an i32, which fits every supported width, and every result is wrapped to
+// the target's width.
+
+const FIRST_VARIABLE: i32 = 16;
+const SNAPSHOT_VERSION: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shift {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compute {
+    // Whether the ALU's y input is M rather than A
+    use_m: bool,
+    // zx, nx, zy, ny, f and no, as in the Hack ALU
+    control: u8,
+    // A shift of the extended ALU replaces the computation; it shifts D or y
+    shift: Option<(Shift, bool)>,
+    pub dest_a: bool,
+    dest_d: bool,
+    dest_m: bool,
+    // Jumps if the output is less than, equal to, or greater than zero
+    pub jump: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Instruction {
+    Load(i32),
+    Compute(Compute),
+}
+
+// ALU control bits of each computation, written with A for y; M takes A's
+// place for the same bits
+const COMPUTATIONS: [(&str, u8); 23] = [
+    ("0", 0b101010),
+    ("1", 0b111111),
+    ("-1", 0b111010),
+    ("D", 0b001100),
+    ("A", 0b110000),
+    ("!D", 0b001101),
+    ("!A", 0b110001),
+    ("-D", 0b001111),
+    ("-A", 0b110011),
+    ("D+1", 0b011111),
+    ("1+D", 0b011111),
+    ("A+1", 0b110111),
+    ("1+A", 0b110111),
+    ("D-1", 0b001110),
+    ("A-1", 0b110010),
+    ("D+A", 0b000010),
+    ("A+D", 0b000010),
+    ("D-A", 0b010011),
+    ("A-D", 0b000111),
+    ("D&A", 0b000000),
+    ("A&D", 0b000000),
+    ("D|A", 0b010101),
+    ("A|D", 0b010101),
+];
+
+const JUMPS: [(&str, u8); 7] = [
+    ("JGT", 0b001),
+    ("JEQ", 0b010),
+    ("JGE", 0b011),
+    ("JLT", 0b100),
+    ("JNE", 0b101),
+    ("JLE", 0b110),
+    ("JMP", 0b111),
+];
+
+pub fn parse_compute(text: &str) -> Option<Compute> {
+    let (dest, rest) = text.split_once('=').unwrap_or(("", text));
+    let (comp, jump) = rest.split_once(';').unwrap_or((rest, ""));
+    let use_m = comp.contains('M');
+    let comp = comp.replace('M', "A");
+    let (control, shift) = match comp.as_str() {
+        "D<<" | "A<<" => (0, Some((Shift::Left, comp.starts_with('D')))),
+        "D>>" | "A>>" => (0, Some((Shift::Right, comp.starts_with('D')))),
+        _ => (COMPUTATIONS.iter().find(|(name, _)| *name == comp)?.1, None),
+    };
+    if !dest.chars().all(|c| "AMD".contains(c)) {
+        return None;
+    }
+    let jump = match jump {
+        "" => 0,
+        _ => JUMPS.iter().find(|(name, _)| *name == jump)?.1,
+    };
+    Some(Compute {
+        use_m,
+        control,
+        shift,
+        dest_a: dest.contains('A'),
+        dest_d: dest.contains('D'),
+        dest_m: dest.contains('M'),
+        jump,
+    })
+}
+
+// The address of a symbol the assembler predefines. SCREEN and KBD are those
+// of the target's memory layout.
+pub fn predefined(symbol: &str, layout: &MemoryLayout) -> Option<Word> {
+    let address = match symbol {
+        "SP" => 0,
+        "LCL" => 1,
+        "ARG" => 2,
+        "THIS" => 3,
+        "THAT" => 4,
+        "SCREEN" => layout.screen,
+        "KBD" => layout.keyboard,
+        _ => symbol.strip_prefix('R')?.parse().ok().filter(|r| *r < 16)?,
+    };
+    Some(address)
+}
+
+impl Instruction {
+    // The instruction's 16-bit machine code. The extended ALU's shifts have
+    // no encoding in the standard instruction set.
+    fn encode(self) -> Option<u16> {
+        match self {
+            Instruction::Load(value) => u16::try_from(value).ok(),
+            Instruction::Compute(Compute { shift: Some(_), .. }) => None,
+            Instruction::Compute(compute) => Some(
+                0b111 << 13
+                    | u16::from(compute.use_m) << 12
+                    | u16::from(compute.control) << 6
+                    | u16::from(compute.dest_a) << 5
+                    | u16::from(compute.dest_d) << 4
+                    | u16::from(compute.dest_m) << 3
+                    | u16::from(compute.jump),
+            ),
+        }
+    }
+}
+
+// An assembled program, with the addresses of its labels and variables
+struct Image {
+    rom: Vec<Instruction>,
+    symbols: HashMap<String, i32>,
+    // Addresses of the instructions following a breakpoint marker
+    breakpoints: HashSet<usize>,
+    // Identifies the assembled code and the machine it runs on, so that
+    // snapshots are only restored into the program and target they were
+    // taken from
+    hash: String,
+}
+
+// Why the emulator stopped running
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    // Halted or ran off the end of its code
+    Finished,
+    Breakpoint,
+    // Out of steps
+    Running,
+}
+
+// Assembles the program. Variables are allocated from address 16 in order of
+// first use, as by the standard assembler.
+fn assemble<L: AsRef<str>>(lines: &[L], target: &Target) -> Image {
+    let mut code = vec![];
+    let mut symbols = HashMap::new();
+    let mut breakpoints = HashSet::new();
+    for line in lines {
+        let line = line.as_ref();
+        if line.trim() == BREAKPOINT {
+            breakpoints.insert(code.len());
+            continue;
+        }
+        let line = line.split_once("//").map_or(line, |(code, _)| code);
+        let text: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+        if let Some(label) = text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            symbols.insert(label.to_owned(), code.len() as i32);
+        } else if !text.is_empty() {
+            code.push(text);
+        }
+    }
+    let mut next_variable = FIRST_VARIABLE;
+    let rom: Vec<Instruction> = code
+        .iter()
+        .map(|text| match text.strip_prefix('@') {
+            Some(symbol) if symbol.starts_with(|c: char| c.is_ascii_digit()) => Instruction::Load(
+                (symbol.parse::<Word>().ok())
+                    .filter(|&value| value <= target.max_constant())
+                    .unwrap_or_else(|| panic!("Invalid constant: {text}")) as i32,
+            ),
+            Some(symbol) => Instruction::Load(predefined(symbol, &target.layout).map_or_else(
+                || {
+                    *symbols.entry(symbol.to_owned()).or_insert_with(|| {
+                        next_variable += 1;
+                        next_variable - 1
+                    })
+                },
+                |address| address as i32,
+            )),
+            None => Instruction::Compute(
+                parse_compute(text).unwrap_or_else(|| panic!("Invalid instruction: {text}")),
+            ),
+        })
+        .collect();
+    Image {
+        hash: sha256(format!("{rom:?} {} {}", target.word_width, target.ram_size).as_bytes()),
+        rom,
+        symbols,
+        breakpoints,
+    }
+}
+
+// The program's machine code, one word per instruction, for the standard
+// 16-bit platform
+pub fn machine_code<L: AsRef<str>>(lines: &[L]) -> Vec<u16> {
+    let image = assemble(lines, &Target::default());
+    (image.rom.iter().enumerate())
+        .map(|(address, instruction)| {
+            instruction.encode().unwrap_or_else(|| {
+                panic!(
+                    "Cannot emit machine code: the instruction at ROM address {address} is a \
+                     shift, which the standard Hack instruction set can't encode"
+                )
+            })
+        })
+        .collect()
+}
+
+// The value as a word of the given width, sign-extended
+fn wrap(value: i64, word_width: u32) -> i32 {
+    let unused = 64 - word_width;
+    ((value << unused) >> unused) as i32
+}
+
+pub struct Emulator {
+    image: Image,
+    word_width: u32,
+    pub ram: Vec<i32>,
+    pub pc: usize,
+    pub a: i32,
+    pub d: i32,
+    // Instructions executed since the program was loaded
+    pub steps: usize,
+}
+
+impl Emulator {
+    pub fn new<L: AsRef<str>>(asm: &[L], target: &Target) -> Self {
+        Self {
+            image: assemble(asm, target),
+            word_width: target.word_width,
+            ram: vec![0; target.ram_size as usize],
+            pc: 0,
+            a: 0,
+            d: 0,
+            steps: 0,
+        }
+    }
+
+    // Replaces the running program. If every label and variable keeps its
+    // address, the instructions that changed are patched in place and the
+    // program carries on with its RAM; otherwise it's restarted from a
+    // cleared RAM. Returns the number of instructions patched, or None if
+    // the program was restarted.
+    pub fn load<L: AsRef<str>>(&mut self, asm: &[L], target: &Target) -> Option<usize> {
+        let image = assemble(asm, target);
+        if image.rom.len() == self.image.rom.len() && image.symbols == self.image.symbols {
+            let patched = (self.image.rom.iter().zip(&image.rom))
+                .filter(|(old, new)| old != new)
+                .count();
+            self.image = image;
+            return Some(patched);
+        }
+        *self = Self {
+            image,
+            ..Self::new::<&str>(&[], target)
+        };
+        None
+    }
+
+    // The complete state of the machine as JSON, with the RAM as the
+    // `[address, value]` pairs of its nonzero words
+    pub fn snapshot(&self) -> Json {
+        let ram: Vec<Json> = (self.ram.iter().enumerate())
+            .filter(|(_, &value)| value != 0)
+            .map(|(address, &value)| Json::from(vec![Json::from(address), Json::from(value)]))
+            .collect();
+        Json::object(vec![
+            ("version", Json::from(SNAPSHOT_VERSION)),
+            ("program", Json::from(self.image.hash.as_str())),
+            ("pc", Json::from(self.pc)),
+            ("a", Json::from(self.a)),
+            ("d", Json::from(self.d)),
+            ("steps", Json::from(self.steps)),
+            ("ram", Json::from(ram)),
+        ])
+    }
+
+    // Puts the machine back in the state of a snapshot of the same program
+    pub fn restore(&mut self, snapshot: &Json) -> Result<(), &'static str> {
+        if snapshot.get("version") != Some(&Json::from(SNAPSHOT_VERSION)) {
+            return Err("isn't a version 1 snapshot");
+        }
+        if snapshot.get("program").and_then(Json::as_str) != Some(&self.image.hash) {
+            return Err("was taken from a different program or target");
+        }
+        let number = |name| snapshot.get(name).and_then(Json::as_number);
+        let word_width = self.word_width;
+        let word = |value: Option<i64>| {
+            value
+                .filter(|&value| i64::from(wrap(value, word_width)) == value)
+                .map(|value| value as i32)
+        };
+        let invalid = "is invalid";
+        let mut ram = vec![0; self.ram.len()];
+        for pair in snapshot
+            .get("ram")
+            .and_then(Json::as_array)
+            .ok_or(invalid)?
+        {
+            let [address, value] = pair.as_array().ok_or(invalid)? else {
+                return Err(invalid);
+            };
+            let address = (address.as_number())
+                .and_then(|address| usize::try_from(address).ok())
+                .filter(|&address| address < ram.len());
+            match (address, word(value.as_number())) {
+                (Some(address), Some(value)) => ram[address] = value,
+                _ => return Err(invalid),
+            }
+        }
+        let count = |name| number(name).and_then(|value| usize::try_from(value).ok());
+        self.pc = count("pc").ok_or(invalid)?;
+        self.a = word(number("a")).ok_or(invalid)?;
+        self.d = word(number("d")).ok_or(invalid)?;
+        self.steps = count("steps").ok_or(invalid)?;
+        self.ram = ram;
+        Ok(())
+    }
+
+    fn wrap(&self, value: i64) -> i32 {
+        wrap(value, self.word_width)
+    }
+
+    fn address(&self) -> usize {
+        usize::try_from(self.a)
+            .ok()
+            .filter(|address| *address < self.ram.len())
+            .unwrap_or_else(|| panic!("RAM address {} out of range at PC {}", self.a, self.pc))
+    }
+
+    fn alu(&self, x: i32, y: i32, control: u8) -> i32 {
+        let bit = |n: u8| control & (1 << (5 - n)) != 0;
+        let x = if bit(0) { 0 } else { x };
+        let x = if bit(1) { !x } else { x };
+        let y = if bit(2) { 0 } else { y };
+        let y = if bit(3) { !y } else { y };
+        let out = if bit(4) {
+            self.wrap(i64::from(x) + i64::from(y))
+        } else {
+            x & y
+        };
+        if bit(5) {
+            !out
+        } else {
+            out
+        }
+    }
+
+    // Executes one instruction. False once the program has run off the end
+    // of its code.
+    pub fn step(&mut self) -> bool {
+        let Some(&instruction) = self.image.rom.get(self.pc) else {
+            return false;
+        };
+        match instruction {
+            Instruction::Load(value) => {
+                self.a = value;
+                self.pc += 1;
+            }
+            Instruction::Compute(compute) => {
+                let y = if compute.use_m {
+                    self.ram[self.address()]
+                } else {
+                    self.a
+                };
+                let out = match compute.shift {
+                    Some((shift, of_d)) => {
+                        let x = if of_d { self.d } else { y };
+                        match shift {
+                            Shift::Left => self.wrap(i64::from(x) << 1),
+                            Shift::Right => x >> 1,
+                        }
+                    }
+                    None => self.alu(self.d, y, compute.control),
+                };
+                let jump_target = self.a;
+                if compute.dest_m {
+                    let address = self.address();
+                    self.ram[address] = out;
+                }
+                if compute.dest_a {
+                    self.a = out;
+                }
+                if compute.dest_d {
+                    self.d = out;
+                }
+                let condition = match out.signum() {
+                    -1 => 0b100,
+                    0 => 0b010,
+                    _ => 0b001,
+                };
+                if compute.jump & condition != 0 {
+                    self.pc = usize::try_from(jump_target).unwrap_or(usize::MAX);
+                } else {
+                    self.pc += 1;
+                }
+            }
+        }
+        self.steps += 1;
+        true
+    }
+
+    // Whether the program is stuck in a loop of the conventional halt, such
+    // as `(END) @END 0;JMP`
+    pub fn halted(&self) -> bool {
+        let at = |pc: usize| self.image.rom.get(pc).copied();
+        match (at(self.pc), at(self.pc + 1)) {
+            (Some(Instruction::Load(target)), Some(Instruction::Compute(jump))) => {
+                let writes = jump.dest_a || jump.dest_d || jump.dest_m;
+                target as usize == self.pc && jump.jump == 0b111 && !writes
+            }
+            _ => false,
+        }
+    }
+
+    // Runs until the program runs off the end of its code or halts, for at
+    // most the given number of steps. False if it's still running after them.
+    pub fn run(&mut self, steps: usize) -> bool {
+        for _ in 0..steps {
+            if self.halted() || !self.step() {
+                return true;
+            }
+        }
+        self.halted() || self.pc >= self.image.rom.len()
+    }
+
+    // Like run, but also stops on reaching a breakpoint, other than the one
+    // it starts at
+    pub fn run_to_breakpoint(&mut self, steps: usize) -> Stop {
+        for i in 0..steps {
+            if self.halted() {
+                return Stop::Finished;
+            }
+            if i > 0 && self.image.breakpoints.contains(&self.pc) {
+                return Stop::Breakpoint;
+            }
+            if !self.step() {
+                return Stop::Finished;
+            }
+        }
+        if self.halted() || self.pc >= self.image.rom.len() {
+            Stop::Finished
+        } else if steps > 0 && self.image.breakpoints.contains(&self.pc) {
+            Stop::Breakpoint
+        } else {
+            Stop::Running
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{machine_code, Emulator, Stop};
+    use crate::json::Json;
+    use crate::target::Target;
+
+    #[test]
+    fn test_emulator() {
+        // Multiplies R0 by R1 into R2 by repeated addition
+        let program = [
+            "@R2", "M=0", "(LOOP)", "@R1", "D=M", "@END", "D;JEQ", "@R0", "D=M", "@R2", "M=D+M",
+            "@R1", "M=M-1", "@LOOP", "0;JMP", "(END)", "@i", "M=-1", "D=M<<",
+        ];
+        let mut emulator = Emulator::new(&program, &Target::default());
+        emulator.ram[0] = -7;
+        emulator.ram[1] = 6;
+        assert!(emulator.run(1000));
+        assert_eq!(emulator.ram[2], -42);
+        assert_eq!(emulator.ram[16], -1);
+        assert_eq!(emulator.d, -2);
+        assert_eq!(emulator.pc, 17);
+
+        let mut emulator =
+            Emulator::new(&["@1", "D=A", "(END)", "@END", "0;JMP"], &Target::default());
+        assert!(emulator.run(10));
+        assert_eq!(emulator.pc, 2);
+        let mut emulator = Emulator::new(&["(LOOP)", "@LOOP", "D=D+1;JMP"], &Target::default());
+        assert!(!emulator.run(10));
+
+        let mut emulator = Emulator::new(
+            &["(LOOP)", "@R0", "M=M+1", "// @break", "@LOOP", "0;JMP"],
+            &Target::default(),
+        );
+        assert_eq!(emulator.run_to_breakpoint(100), Stop::Breakpoint);
+        assert_eq!((emulator.pc, emulator.ram[0]), (2, 1));
+        assert_eq!(emulator.run_to_breakpoint(100), Stop::Breakpoint);
+        assert_eq!(emulator.ram[0], 2);
+        assert_eq!(emulator.run_to_breakpoint(3), Stop::Running);
+    }
+
+    #[test]
+    fn test_screen_and_keyboard_of_layout() {
+        let mut target = Target::default();
+        target.layout.set_all("screen=8192,keyboard=16384");
+        let mut emulator = Emulator::new(&["@SCREEN", "D=A", "@KBD", "D=D+A"], &target);
+        emulator.run(10);
+        assert_eq!(emulator.d, 8192 + 16384);
+    }
+
+    #[test]
+    fn test_word_width_and_ram_size() {
+        // Adds 30000 to itself, which overflows a 16-bit word
+        let program = ["@30000", "D=A", "D=D+A", "@20000", "M=D", "D=D<<"];
+        let mut emulator = Emulator::new(&program, &Target::default());
+        assert_eq!(emulator.ram.len(), 24577);
+        emulator.run(10);
+        assert_eq!(emulator.ram[20000], -5536);
+        assert_eq!(emulator.d, -11072);
+
+        let target =
+            Target::from_profile("word_width = 32\nram_size = 0x100000\nrom_size = 0x100000");
+        let mut emulator = Emulator::new(&program, &target);
+        assert_eq!(emulator.ram.len(), 0x100000);
+        emulator.run(10);
+        assert_eq!(emulator.ram[20000], 60000);
+        assert_eq!(emulator.d, 120000);
+        let mut emulator = Emulator::new(&["@2147483647", "D=A", "D=D+1"], &target);
+        emulator.run(10);
+        assert_eq!(emulator.d, i32::MIN);
+    }
+
+    #[test]
+    fn test_machine_code() {
+        let program = ["(LOOP)", "@2", "D=A", "@R0", "M=D+M", "@LOOP", "0;JMP"];
+        assert_eq!(
+            machine_code(&program),
+            [
+                0b0000000000000010,
+                0b1110110000010000,
+                0b0000000000000000,
+                0b1111000010001000,
+                0b0000000000000000,
+                0b1110101010000111,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_patches_or_restarts() {
+        let mut emulator = Emulator::new(
+            &["(LOOP)", "@x", "M=M+1", "@LOOP", "0;JMP"],
+            &Target::default(),
+        );
+        emulator.run(40);
+        assert_eq!(emulator.ram[16], 10);
+        // Same labels and variables: RAM and PC are kept
+        assert_eq!(
+            emulator.load(
+                &["(LOOP)", "@x", "M=M-1", "@LOOP", "0;JMP"],
+                &Target::default()
+            ),
+            Some(1)
+        );
+        emulator.run(8);
+        assert_eq!(emulator.ram[16], 8);
+        // A new variable moves nothing, but is a change of layout
+        assert_eq!(
+            emulator.load(
+                &["(LOOP)", "@y", "M=M-1", "@LOOP", "0;JMP"],
+                &Target::default()
+            ),
+            None
+        );
+        assert_eq!((emulator.pc, emulator.ram[16]), (0, 0));
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let program = ["(LOOP)", "@x", "M=M-1", "D=M", "@LOOP", "0;JMP"];
+        let mut emulator = Emulator::new(&program, &Target::default());
+        emulator.run(13);
+        let snapshot = Json::parse(&emulator.snapshot().to_string()).unwrap();
+        emulator.run(100);
+
+        let mut resumed = Emulator::new(&program, &Target::default());
+        resumed.restore(&snapshot).unwrap();
+        assert_eq!(resumed.steps, 13);
+        resumed.run(100);
+        assert_eq!(
+            (resumed.pc, resumed.a, resumed.d, resumed.steps),
+            (emulator.pc, emulator.a, emulator.d, emulator.steps)
+        );
+        assert_eq!(resumed.ram, emulator.ram);
+
+        let mut other = Emulator::new(
+            &["(LOOP)", "@x", "M=M+1", "D=M", "@LOOP", "0;JMP"],
+            &Target::default(),
+        );
+        assert_eq!(
+            other.restore(&snapshot),
+            Err("was taken from a different program or target")
+        );
+        let target =
+            Target::from_profile("word_width = 32\nram_size = 0x100000\nrom_size = 0x100000");
+        let mut wider = Emulator::new(&program, &target);
+        assert_eq!(
+            wider.restore(&snapshot),
+            Err("was taken from a different program or target")
+        );
+        assert_eq!(wider.steps, 0);
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{OnceLock, RwLock};
+
+// An interned function, label or symbol name. Names are compared and hashed by
+// ID; the text lives in a process-wide arena that is never freed, so it can be
+// shared by the parser, the analyses and the translator threads. Only
+// identifiers are interned, but they include the generated symbols, such as
+// return labels and mangled names, so the arena grows with every distinct
+// name translated during the process, which in the daemon means across
+// requests. Use lookup rather than new to find a name without adding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Name(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, Name>,
+    names: Vec<&'static str>,
+}
+
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+impl Name {
+    pub fn new(name: &str) -> Self {
+        if let Some(id) = interner().read().unwrap().ids.get(name) {
+            return *id;
+        }
+        let mut interner = interner().write().unwrap();
+        if let Some(id) = interner.ids.get(name) {
+            return *id;
+        }
+        let id = Name(interner.names.len() as u32);
+        let name: &'static str = Box::leak(name.into());
+        interner.ids.insert(name, id);
+        interner.names.push(name);
+        id
+    }
+
+    // The name, if it has been interned
+    pub fn lookup(name: &str) -> Option<Self> {
+        interner().read().unwrap().ids.get(name).copied()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        interner().read().unwrap().names[self.0 as usize]
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.as_str().is_empty()
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Name;
+
+    #[test]
+    fn test_intern() {
+        let name = Name::new("Main.fib");
+        assert_eq!(Name::new("Main.fib"), name);
+        assert_ne!(Name::new("Main.fib$LOOP"), name);
+        assert_eq!(name.as_str(), "Main.fib");
+        assert_eq!(format!("{name}$ret.0"), "Main.fib$ret.0");
+        assert_eq!(Name::lookup("Main.fib"), Some(name));
+        // Looking a name up doesn't intern it
+        assert_eq!(Name::lookup("Main.never_interned"), None);
+        assert_eq!(Name::lookup("Main.never_interned"), None);
+    }
+}
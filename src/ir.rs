@@ -0,0 +1,354 @@
+use crate::intern::Name;
+use crate::json::Json;
+use crate::target::Word;
+use crate::vm_translator::parser::{parse_instruction, ParsedVMInstruction};
+use crate::vm_translator::{SourceInstruction, VMFile};
+
+// Dumps the parsed VM program, before any code generation, so that it can be
+// consumed by external tools without re-implementing the parser.
+#[derive(Debug, PartialEq)]
+pub enum IrFormat {
+    Json,
+    Xml,
+}
+
+impl IrFormat {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "json" => IrFormat::Json,
+            "xml" => IrFormat::Xml,
+            _ => panic!("Invalid IR format: {name}"),
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        match *self {
+            IrFormat::Json => "ir.json",
+            IrFormat::Xml => "ir.xml",
+        }
+    }
+}
+
+struct FunctionBody<'a> {
+    line: usize,
+    name: Name,
+    num_local_vars: Word,
+    instructions: Vec<&'a SourceInstruction>,
+}
+
+// Splits a file into the instructions preceding its first function and the
+// bodies of each of its functions
+fn group_functions(file: &VMFile) -> (Vec<&SourceInstruction>, Vec<FunctionBody<'_>>) {
+    let mut toplevel = vec![];
+    let mut functions: Vec<FunctionBody> = vec![];
+    for instr in &file.instructions {
+        if let ParsedVMInstruction::Function {
+            name,
+            num_local_vars,
+        } = &instr.instruction
+        {
+            functions.push(FunctionBody {
+                line: instr.line,
+                name: *name,
+                num_local_vars: *num_local_vars,
+                instructions: vec![],
+            });
+        } else if let Some(function) = functions.last_mut() {
+            function.instructions.push(instr);
+        } else {
+            toplevel.push(instr);
+        }
+    }
+    (toplevel, functions)
+}
+
+fn instruction_json(instr: &SourceInstruction) -> Json {
+    let mut fields = vec![
+        ("line", Json::from(instr.line)),
+        ("command", Json::from(instr.instruction.command())),
+    ];
+    match &instr.instruction {
+        ParsedVMInstruction::Pop { segment, idx } | ParsedVMInstruction::Push { segment, idx } => {
+            fields.push(("segment", Json::from(segment.name())));
+            fields.push(("index", Json::from(*idx)));
+        }
+        ParsedVMInstruction::Label { label }
+        | ParsedVMInstruction::Goto { label }
+        | ParsedVMInstruction::IfGoto { label } => {
+            fields.push(("label", Json::from(label.as_str())));
+        }
+        ParsedVMInstruction::Function {
+            name,
+            num_local_vars,
+        } => {
+            fields.push(("name", Json::from(name.as_str())));
+            fields.push(("num_local_vars", Json::from(*num_local_vars)));
+        }
+        ParsedVMInstruction::Call { name, num_args } => {
+            fields.push(("name", Json::from(name.as_str())));
+            fields.push(("num_args", Json::from(*num_args)));
+        }
+        ParsedVMInstruction::Const { name, value } => {
+            fields.push(("name", Json::from(name.as_str())));
+            fields.push(("value", Json::from(*value)));
+        }
+        ParsedVMInstruction::Halt { code } => fields.push(("value", Json::from(*code))),
+        _ => {}
+    }
+    Json::object(fields)
+}
+
+fn function_json(function: FunctionBody) -> Json {
+    Json::object(vec![
+        ("line", Json::from(function.line)),
+        ("name", Json::from(function.name.as_str())),
+        ("num_local_vars", Json::from(function.num_local_vars)),
+        (
+            "instructions",
+            Json::Array(
+                function
+                    .instructions
+                    .into_iter()
+                    .map(instruction_json)
+                    .collect(),
+            ),
+        ),
+    ])
+}
+
+fn file_json(file: &VMFile) -> Json {
+    let (toplevel, functions) = group_functions(file);
+    let functions = functions.into_iter().map(function_json).collect();
+    Json::object(vec![
+        ("name", Json::from(file.name.as_str())),
+        ("static_base", Json::from(file.static_base.as_str())),
+        (
+            "instructions",
+            Json::Array(toplevel.into_iter().map(instruction_json).collect()),
+        ),
+        ("functions", Json::Array(functions)),
+    ])
+}
+
+pub fn to_json(files: &[VMFile]) -> Json {
+    Json::object(vec![
+        ("version", Json::Number(1)),
+        ("files", Json::Array(files.iter().map(file_json).collect())),
+    ])
+}
+
+// The operands of an instruction, in the order they're written in VM code
+const OPERANDS: [&str; 7] = [
+    "segment",
+    "index",
+    "label",
+    "name",
+    "num_local_vars",
+    "num_args",
+    "value",
+];
+
+// The text of an instruction of the IR, as it's written in VM code
+fn instruction_text(json: &Json) -> Option<String> {
+    let mut text = json.get("command")?.as_str()?.to_owned();
+    for operand in OPERANDS.iter().filter_map(|key| json.get(key)) {
+        text.push(' ');
+        match operand {
+            Json::Number(value) => text.push_str(&value.to_string()),
+            _ => text.push_str(operand.as_str()?),
+        }
+    }
+    Some(text)
+}
+
+// Appends the instruction, which is attributed to the line before it if it
+// has none, as code inserted by a pass would be
+fn push_instruction(file: &mut VMFile, json: &Json, text: String) -> Option<()> {
+    let line = match json.get("line") {
+        Some(line) => usize::try_from(line.as_number()?).ok()?,
+        None => file.instructions.last().map_or(0, |instr| instr.line),
+    };
+    file.instructions.push(SourceInstruction {
+        line,
+        instruction: parse_instruction(&text),
+    });
+    Some(())
+}
+
+fn file_from_json(json: &Json) -> Option<VMFile> {
+    let mut file = VMFile {
+        name: json.get("name")?.as_str()?.to_owned(),
+        static_base: json.get("static_base")?.as_str()?.to_owned(),
+        ..Default::default()
+    };
+    for instr in json.get("instructions")?.as_array()? {
+        push_instruction(&mut file, instr, instruction_text(instr)?)?;
+    }
+    for function in json.get("functions")?.as_array()? {
+        let declaration = format!(
+            "function {} {}",
+            function.get("name")?.as_str()?,
+            function.get("num_local_vars")?.as_number()?
+        );
+        push_instruction(&mut file, function, declaration)?;
+        for instr in function.get("instructions")?.as_array()? {
+            push_instruction(&mut file, instr, instruction_text(instr)?)?;
+        }
+    }
+    Some(file)
+}
+
+// Reads a program in the JSON format, such as the output of a plugin pass.
+// Comments aren't part of the IR, so the files have none. None if the JSON
+// isn't a program; instructions are checked as in VM source.
+pub fn from_json(json: &Json) -> Option<Vec<VMFile>> {
+    if json.get("version")?.as_number()? != 1 {
+        return None;
+    }
+    json.get("files")?
+        .as_array()?
+        .iter()
+        .map(file_from_json)
+        .collect()
+}
+
+// The XML output follows the conventions of the course's Jack analyzer: one
+// element per construct, terminals written as `<kind> value </kind>` and two
+// spaces of indentation per level
+struct XmlWriter {
+    xml: String,
+    depth: usize,
+}
+
+impl XmlWriter {
+    fn open(&mut self, tag: &str) {
+        self.line(&format!("<{tag}>"));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, tag: &str) {
+        self.depth -= 1;
+        self.line(&format!("</{tag}>"));
+    }
+
+    fn terminal(&mut self, tag: &str, value: &str) {
+        let value = value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        self.line(&format!("<{tag}> {value} </{tag}>"));
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.depth {
+            self.xml.push_str("  ");
+        }
+        self.xml.push_str(line);
+        self.xml.push('\n');
+    }
+}
+
+fn instruction_xml(xml: &mut XmlWriter, instr: &SourceInstruction) {
+    let tag = match instr.instruction {
+        ParsedVMInstruction::Pop { .. } => "popCommand",
+        ParsedVMInstruction::Push { .. } => "pushCommand",
+        ParsedVMInstruction::Label { .. } => "labelCommand",
+        ParsedVMInstruction::Goto { .. } => "gotoCommand",
+        ParsedVMInstruction::IfGoto { .. } => "ifGotoCommand",
+        ParsedVMInstruction::Function { .. } => "functionCommand",
+        ParsedVMInstruction::Call { .. } => "callCommand",
+        ParsedVMInstruction::Return => "returnCommand",
+        ParsedVMInstruction::Const { .. } => "constCommand",
+        ParsedVMInstruction::Halt { .. } => "haltCommand",
+        ParsedVMInstruction::Break => "breakCommand",
+        _ => "arithmeticCommand",
+    };
+    xml.open(tag);
+    xml.terminal("keyword", instr.instruction.command());
+    match &instr.instruction {
+        ParsedVMInstruction::Pop { segment, idx } | ParsedVMInstruction::Push { segment, idx } => {
+            xml.terminal("segment", segment.name());
+            xml.terminal("integerConstant", &idx.to_string());
+        }
+        ParsedVMInstruction::Label { label }
+        | ParsedVMInstruction::Goto { label }
+        | ParsedVMInstruction::IfGoto { label } => xml.terminal("identifier", label.as_str()),
+        ParsedVMInstruction::Function {
+            name,
+            num_local_vars: num,
+        }
+        | ParsedVMInstruction::Call {
+            name,
+            num_args: num,
+        }
+        | ParsedVMInstruction::Const { name, value: num } => {
+            xml.terminal("identifier", name.as_str());
+            xml.terminal("integerConstant", &num.to_string());
+        }
+        ParsedVMInstruction::Halt { code } => xml.terminal("integerConstant", &code.to_string()),
+        _ => {}
+    }
+    xml.close(tag);
+}
+
+fn statements_xml(xml: &mut XmlWriter, instructions: Vec<&SourceInstruction>) {
+    xml.open("statements");
+    for instr in instructions {
+        instruction_xml(xml, instr);
+    }
+    xml.close("statements");
+}
+
+pub fn to_xml(files: &[VMFile]) -> String {
+    let mut xml = XmlWriter {
+        xml: String::new(),
+        depth: 0,
+    };
+    xml.open("program");
+    for file in files {
+        let (toplevel, functions) = group_functions(file);
+        xml.open("file");
+        xml.terminal("fileName", &file.name);
+        if !toplevel.is_empty() {
+            statements_xml(&mut xml, toplevel);
+        }
+        for function in functions {
+            xml.open("function");
+            xml.terminal("keyword", "function");
+            xml.terminal("identifier", function.name.as_str());
+            xml.terminal("integerConstant", &function.num_local_vars.to_string());
+            statements_xml(&mut xml, function.instructions);
+            xml.close("function");
+        }
+        xml.close("file");
+    }
+    xml.close("program");
+    xml.xml
+}
+
+pub fn render(files: &[VMFile], format: &IrFormat) -> String {
+    match *format {
+        IrFormat::Json => to_json(files).to_string(),
+        IrFormat::Xml => to_xml(files),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_json;
+    use crate::vm_translator::parse_source;
+
+    #[test]
+    fn test_to_json_groups_functions() {
+        let file = parse_source("Main.vm", "push constant 1\nfunction Main.main 1\nreturn\n");
+        assert_eq!(
+            to_json(&[file]).to_string(),
+            concat!(
+                r#"{"version":1,"files":[{"name":"Main.vm","static_base":"Main","#,
+                r#""instructions":[{"line":1,"command":"push","segment":"constant","index":1}],"#,
+                r#""functions":[{"line":2,"name":"Main.main","num_local_vars":1,"#,
+                r#""instructions":[{"line":3,"command":"return"}]}]}]}"#
+            )
+        );
+    }
+}
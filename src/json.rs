@@ -0,0 +1,467 @@
+use std::fmt;
+
+// Minimal JSON value used for the machine-readable output files. Objects keep
+// their keys in insertion order so that the generated files are stable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<K: Into<String>>(fields: Vec<(K, Json)>) -> Self {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.into(), value))
+                .collect(),
+        )
+    }
+
+    // Parses JSON text, such as an earlier build manifest. None if the text
+    // isn't valid JSON.
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        (parser.pos == parser.text.len()).then_some(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(field, _)| field == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<i64> {
+        match self {
+            Json::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+// Deeper nesting is rejected rather than risking the stack on hostile input,
+// such as a daemon request
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    // Arrays and objects the parser is inside
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        (self.peek()? == byte).then(|| self.pos += 1)
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Option<Json> {
+        let end = self.pos + literal.len();
+        (self.text.get(self.pos..end)? == literal.as_bytes()).then(|| {
+            self.pos = end;
+            value
+        })
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        match self.peek()? {
+            b'[' | b'{' if self.depth == MAX_DEPTH => None,
+            b'[' | b'{' => {
+                self.depth += 1;
+                let value = self.container();
+                self.depth -= 1;
+                value
+            }
+            _ => self.scalar(),
+        }
+    }
+
+    fn scalar(&mut self) -> Option<Json> {
+        match self.peek()? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            _ => self.number(),
+        }
+    }
+
+    fn container(&mut self) -> Option<Json> {
+        match self.peek()? {
+            b'[' => {
+                self.pos += 1;
+                let mut values = vec![];
+                if self.peek()? == b']' {
+                    self.pos += 1;
+                    return Some(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b']' => {
+                            self.pos += 1;
+                            return Some(Json::Array(values));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut fields = vec![];
+                if self.peek()? == b'}' {
+                    self.pos += 1;
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    self.peek()?;
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b'}' => {
+                            self.pos += 1;
+                            return Some(Json::Object(fields));
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Skips the digits at the position, returning how many there were
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    fn skip(&mut self, bytes: &[u8]) -> bool {
+        let found = self.text.get(self.pos).is_some_and(|c| bytes.contains(c));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    // -?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        self.skip(b"-");
+        if !self.skip(b"0") && self.digits() == 0 {
+            return None;
+        }
+        if self.skip(b".") && self.digits() == 0 {
+            return None;
+        }
+        if self.skip(b"eE") {
+            self.skip(b"+-");
+            if self.digits() == 0 {
+                return None;
+            }
+        }
+        let number = std::str::from_utf8(&self.text[start..self.pos]).ok()?;
+        match number.parse::<i64>() {
+            Ok(integer) => Some(Json::Number(integer)),
+            Err(_) => number.parse::<f64>().ok().map(Json::Float),
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.text.get(self.pos) != Some(&b'"') {
+            return None;
+        }
+        self.pos += 1;
+        let mut bytes = vec![];
+        loop {
+            let byte = *self.text.get(self.pos)?;
+            self.pos += 1;
+            match byte {
+                b'"' => return String::from_utf8(bytes).ok(),
+                b'\\' => {
+                    let escaped = match *self.text.get(self.pos)? {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        other @ (b'"' | b'\\' | b'/') => other as char,
+                        b'u' => {
+                            let code = self.code_unit()?;
+                            // Characters beyond the BMP are escaped as a
+                            // surrogate pair
+                            let code = if (0xD800..0xDC00).contains(&code) {
+                                self.pos += 1;
+                                if self.text.get(self.pos..self.pos + 2)? != b"\\u" {
+                                    return None;
+                                }
+                                self.pos += 1;
+                                let low = self.code_unit()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return None;
+                                }
+                                0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)
+                            } else {
+                                code
+                            };
+                            char::from_u32(code)?
+                        }
+                        _ => return None,
+                    };
+                    self.pos += 1;
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(escaped.encode_utf8(&mut buffer).as_bytes());
+                }
+                // Control characters must be escaped
+                0..0x20 => return None,
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    // The four hex digits following the `u` of a \u escape at the position,
+    // leaving the position on the last
+    fn code_unit(&mut self) -> Option<u32> {
+        let hex = self.text.get(self.pos + 1..self.pos + 5)?;
+        if !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        self.pos += 4;
+        u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(value: i32) -> Self {
+        Json::Number(value.into())
+    }
+}
+
+impl From<u32> for Json {
+    fn from(value: u32) -> Self {
+        Json::Number(value.into())
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Number(value as i64)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => Json::Null,
+        }
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(values: Vec<T>) -> Self {
+        Json::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(value) => write!(f, "{value}"),
+            Json::Number(value) => write!(f, "{value}"),
+            Json::Float(value) => write!(f, "{value}"),
+            Json::String(value) => write_escaped(f, value),
+            Json::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_escaped(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn test_display() {
+        let value = Json::object(vec![
+            ("name", Json::from("Main.vm")),
+            ("line", Json::from(3usize)),
+            ("function", Json::Null),
+            ("flags", Json::from(vec![true, false])),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"Main.vm","line":3,"function":null,"flags":[true,false]}"#
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let value = Json::object(vec![
+            ("path", Json::from("a \"quoted\"\\path\n")),
+            ("sizes", Json::from(vec![1usize, 20])),
+            ("function", Json::Null),
+            ("ok", Json::from(true)),
+        ]);
+        assert_eq!(Json::parse(&value.to_string()), Some(value.clone()));
+        let spaced = " { \"path\" : \"\\u0041\" , \"sizes\": [ ] } ";
+        let parsed = Json::parse(spaced).unwrap();
+        assert_eq!(parsed.get("path").and_then(Json::as_str), Some("A"));
+        assert_eq!(parsed.get("sizes").and_then(Json::as_array), Some(&[][..]));
+        assert_eq!(Json::parse("{\"a\":1,}"), None);
+    }
+
+    #[test]
+    fn test_parse_numbers_and_escapes() {
+        let parsed = Json::parse(r#"[0, -12, 1.5e3, -0.25, 2E-2, "\/\"\ud83d\ude00\u00e9"]"#);
+        assert_eq!(
+            parsed,
+            Some(Json::Array(vec![
+                Json::Number(0),
+                Json::Number(-12),
+                Json::Float(1500.0),
+                Json::Float(-0.25),
+                Json::Float(0.02),
+                Json::from("/\"\u{1F600}\u{e9}"),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let malformed = [
+            "",
+            "nul",
+            "[1,]",
+            "[1 2]",
+            "{\"a\"}",
+            "{a:1}",
+            "{\"a\":1",
+            "\"unterminated",
+            "01",
+            "+1",
+            "1.",
+            ".5",
+            "1e",
+            "-",
+            "\"\\x\"",
+            "\"\\u12\"",
+            "\"\\ud83d\"",
+            "\"\\ude00\"",
+            "\"tab\there\"",
+            "[1] [2]",
+        ];
+        for text in malformed {
+            assert_eq!(Json::parse(text), None, "{text:?}");
+        }
+        // Nesting is bounded
+        assert!(Json::parse(&format!("{}{}", "[".repeat(100), "]".repeat(100))).is_some());
+        assert_eq!(Json::parse(&"[".repeat(100_000)), None);
+    }
+
+    #[test]
+    fn test_escape() {
+        let value = Json::from("a \"quoted\"\\path\n");
+        assert_eq!(value.to_string(), r#""a \"quoted\"\\path\n""#);
+    }
+}
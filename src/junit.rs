@@ -0,0 +1,105 @@
+use std::fs::write;
+use std::path::Path;
+use std::time::Duration;
+
+// Test results as JUnit XML, which CI systems such as GitHub Actions, GitLab
+// and Jenkins display per test
+
+pub struct TestResult {
+    pub name: String,
+    // Groups the tests, e.g. by configuration
+    pub class: String,
+    pub time: Duration,
+    // Why the test failed, the first divergence first
+    pub errors: Vec<String>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn render(suite: &str, results: &[TestResult]) -> String {
+    let failures = results
+        .iter()
+        .filter(|result| !result.errors.is_empty())
+        .count();
+    let time: Duration = results.iter().map(|result| result.time).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+    xml.push_str(&format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{failures}\" time=\"{:.3}\">\n",
+        escape(suite),
+        results.len(),
+        time.as_secs_f64()
+    ));
+    for result in results {
+        let attributes = format!(
+            "name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&result.name),
+            escape(&result.class),
+            result.time.as_secs_f64()
+        );
+        match result.errors.first() {
+            None => xml.push_str(&format!("    <testcase {attributes}/>\n")),
+            Some(first) => {
+                xml.push_str(&format!("    <testcase {attributes}>\n"));
+                xml.push_str(&format!(
+                    "      <failure message=\"{}\">{}</failure>\n",
+                    escape(first),
+                    escape(&result.errors.join("\n"))
+                ));
+                xml.push_str("    </testcase>\n");
+            }
+        }
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+pub fn write_results(path: &Path, suite: &str, results: &[TestResult]) {
+    write(path, render(suite, results))
+        .unwrap_or_else(|_| panic!("Failed to write test results to {}", path.to_str().unwrap()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, TestResult};
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let results = [
+            TestResult {
+                name: String::from("SimpleAdd"),
+                class: String::from("default"),
+                time: Duration::from_millis(2),
+                errors: vec![],
+            },
+            TestResult {
+                name: String::from("StackTest"),
+                class: String::from("default"),
+                time: Duration::from_millis(15),
+                errors: vec![
+                    String::from("RAM[256] is 0, expected -1"),
+                    String::from("RAM[265] is 91, expected -91 & <more>"),
+                ],
+            },
+        ];
+        assert_eq!(
+            render("self-test", &results),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites>
+  <testsuite name="self-test" tests="2" failures="1" time="0.017">
+    <testcase name="SimpleAdd" classname="default" time="0.002"/>
+    <testcase name="StackTest" classname="default" time="0.015">
+      <failure message="RAM[256] is 0, expected -1">RAM[256] is 0, expected -1
+RAM[265] is 91, expected -91 &amp; &lt;more&gt;</failure>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+}
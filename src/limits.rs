@@ -0,0 +1,101 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::vm_translator::VMFile;
+
+// Bounds on the work done for an input, for running the translator on
+// untrusted programs, e.g. in a grading server. An exceeded limit stops the
+// run with a limit-exceeded message and exit status 2, rather than letting a
+// huge input exhaust memory.
+pub const EXIT_LIMIT_EXCEEDED: i32 = 2;
+
+#[derive(Debug, PartialEq)]
+pub struct LimitExceeded {
+    // The option setting the limit, e.g. max-instructions
+    pub limit: &'static str,
+    pub max: usize,
+    pub actual: usize,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is over --{}={}", self.actual, self.limit, self.max)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    // Total size in bytes of the input files, checked before any is read
+    pub max_input_size: Option<usize>,
+    // VM instructions in the whole program, after any passes
+    pub max_instructions: Option<usize>,
+}
+
+pub fn parse_limit(option: &str, value: &str) -> usize {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid value for {option}: {value}"))
+}
+
+fn check(limit: &'static str, max: Option<usize>, actual: usize) -> Result<(), LimitExceeded> {
+    match max {
+        Some(max) if actual > max => Err(LimitExceeded { limit, max, actual }),
+        _ => Ok(()),
+    }
+}
+
+impl Limits {
+    pub fn check_input_size(&self, inputs: &[PathBuf]) -> Result<(), LimitExceeded> {
+        if self.max_input_size.is_none() {
+            return Ok(());
+        }
+        let size = inputs
+            .iter()
+            .map(|path| {
+                path.metadata()
+                    .unwrap_or_else(|_| panic!("Failed to open {}", path.to_str().unwrap()))
+                    .len() as usize
+            })
+            .sum();
+        check("max-input-size", self.max_input_size, size)
+    }
+
+    pub fn check_instructions(&self, files: &[VMFile]) -> Result<(), LimitExceeded> {
+        let instructions = files.iter().map(|file| file.instructions.len()).sum();
+        check("max-instructions", self.max_instructions, instructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LimitExceeded, Limits};
+    use crate::vm_translator::parse_source;
+
+    #[test]
+    fn test_check_instructions() {
+        let files = [parse_source(
+            "Main.vm",
+            "push constant 1\npush constant 2\nadd",
+        )];
+        let limits = Limits {
+            max_instructions: Some(3),
+            ..Limits::default()
+        };
+        assert_eq!(limits.check_instructions(&files), Ok(()));
+        let limits = Limits {
+            max_instructions: Some(2),
+            ..Limits::default()
+        };
+        let exceeded = limits.check_instructions(&files).unwrap_err();
+        assert_eq!(
+            exceeded,
+            LimitExceeded {
+                limit: "max-instructions",
+                max: 2,
+                actual: 3
+            }
+        );
+        assert_eq!(exceeded.to_string(), "3 is over --max-instructions=2");
+        assert_eq!(Limits::default().check_instructions(&files), Ok(()));
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use crate::cycles::{describe, Cycles};
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{Translation, VMFile};
+
+// Renders a listing of the generated assembly where every instruction is
+// prefixed by its ROM address, and each block of instructions is preceded by
+// the VM source line it was translated from and its estimated cycles.
+// Function declarations are followed by the estimates for a call, and the
+// listing ends with the estimate for the whole program.
+pub fn render(translation: &Translation, files: &[VMFile], cycles: &Cycles) -> String {
+    // Source map sources are registered in the same order as the files
+    let vm_lines: Vec<HashMap<usize, &ParsedVMInstruction>> = files
+        .iter()
+        .map(|file| {
+            file.instructions
+                .iter()
+                .map(|instr| (instr.line, &instr.instruction))
+                .collect()
+        })
+        .collect();
+    let sources = translation.source_map.sources();
+    let mut listing = String::new();
+    let mut prev_origin = None;
+    for (instr, entry) in translation.asm.iter().zip(translation.source_map.entries()) {
+        let origin = (entry.source, entry.vm_line);
+        if prev_origin != Some(origin) {
+            let header = match origin {
+                (Some(source), Some(line)) => match vm_lines[source].get(&line) {
+                    Some(ParsedVMInstruction::Function { .. }) => {
+                        let function = &cycles.functions[entry.function.unwrap()];
+                        format!(
+                            "{}:{}: {} (cycles per call: {}, {} with callees)",
+                            sources[source],
+                            line,
+                            vm_lines[source][&line],
+                            describe(function.own),
+                            describe(function.total)
+                        )
+                    }
+                    Some(instr) => match cycles.instructions[source].get(&line) {
+                        Some(n) => format!("{}:{}: {} ({n} cycles)", sources[source], line, instr),
+                        None => format!("{}:{}: {}", sources[source], line, instr),
+                    },
+                    // A comment line kept with --keep-comments
+                    None => format!("{}:{}", sources[source], line),
+                },
+                _ => String::from("(generated)"),
+            };
+            listing.push_str(&format!("{:5}  // {header}\n", ""));
+            prev_origin = Some(origin);
+        }
+        match entry.rom_addr {
+            Some(rom_addr) => listing.push_str(&format!("{rom_addr:5}  {instr}\n")),
+            None => listing.push_str(&format!("{:5}  {instr}\n", "")),
+        }
+    }
+    listing.push_str(&format!(
+        "{:5}  // Estimated cycles of the program: {}\n",
+        "",
+        describe(cycles.program)
+    ));
+    listing
+}
@@ -0,0 +1,93 @@
+// Machine code output for loading programs onto Hack hardware, such as FPGA
+// implementations: the course's .hack text format, Intel HEX and raw binary.
+// Words are written little-endian in the byte-oriented formats.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachineCodeFormat {
+    Hack,
+    Hex,
+    Bin,
+}
+
+// Data bytes per Intel HEX record
+const HEX_RECORD_SIZE: usize = 16;
+
+impl MachineCodeFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hack" => Some(MachineCodeFormat::Hack),
+            "hex" => Some(MachineCodeFormat::Hex),
+            "bin" => Some(MachineCodeFormat::Bin),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        match *self {
+            MachineCodeFormat::Hack => "hack",
+            MachineCodeFormat::Hex => "hex",
+            MachineCodeFormat::Bin => "bin",
+        }
+    }
+
+    pub fn render(&self, words: &[u16]) -> Vec<u8> {
+        match *self {
+            MachineCodeFormat::Hack => words
+                .iter()
+                .map(|word| format!("{word:016b}\n"))
+                .collect::<String>()
+                .into_bytes(),
+            MachineCodeFormat::Hex => intel_hex(&bytes(words)).into_bytes(),
+            MachineCodeFormat::Bin => bytes(words),
+        }
+    }
+}
+
+fn bytes(words: &[u16]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+fn record(address: u16, kind: u8, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(sum.wrapping_neg());
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+    format!(":{hex}\n")
+}
+
+// Data records from address 0, then the end of file record. A full 32K-word
+// ROM is exactly the 64K bytes that 16-bit record addresses reach.
+fn intel_hex(bytes: &[u8]) -> String {
+    let mut hex: String = (bytes.chunks(HEX_RECORD_SIZE).enumerate())
+        .map(|(i, chunk)| record((i * HEX_RECORD_SIZE) as u16, 0, chunk))
+        .collect();
+    hex.push_str(&record(0, 1, &[]));
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MachineCodeFormat;
+
+    #[test]
+    fn test_render() {
+        let words = [
+            0x0002, 0xEC10, 0x0000, 0xF088, 0x0000, 0xEA87, 0x0010, 0xE308, 0x0001,
+        ];
+        assert_eq!(
+            String::from_utf8(MachineCodeFormat::Hack.render(&words[..2])).unwrap(),
+            "0000000000000010\n1110110000010000\n"
+        );
+        assert_eq!(
+            MachineCodeFormat::Bin.render(&words[..2]),
+            [0x02, 0x00, 0x10, 0xEC]
+        );
+        assert_eq!(
+            String::from_utf8(MachineCodeFormat::Hex.render(&words)).unwrap(),
+            ":10000000020010EC000088F0000087EA100008E30E\n:020010000100ED\n:00000001FF\n"
+        );
+    }
+}
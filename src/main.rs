@@ -1,42 +1,533 @@
+mod asm_diff;
+mod asm_normalize;
+mod batch;
+mod cache;
+mod call_graph;
+mod cfg_report;
+mod clean;
+mod cycles;
+mod daemon;
+mod diagnostics;
+mod dialect;
+mod emulator;
+mod hash;
+mod intern;
+mod ir;
+mod json;
+mod junit;
+mod limits;
+mod listing;
+mod machine_code;
+mod mangle;
+mod manifest;
+mod messages;
+mod options;
+mod plugin;
+mod reduce;
+mod report;
+mod run;
+mod self_test;
+mod size;
+mod source_map;
+mod split;
+mod stats;
+mod symbols;
+mod target;
+mod vcs;
+mod verify_asm;
 mod vm_translator;
 
+use std::any::Any;
 use std::env;
-use std::fs::write;
+use std::fs::{create_dir_all, read_to_string, write, File};
+use std::io::{self, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process;
 
-fn write_lines(outfile: &PathBuf, asm_output: &[String]) {
-    write(outfile, asm_output.join("\n")).expect(&format!(
-        "Failed to write hack assembly output to {}",
-        outfile.to_str().unwrap()
-    ));
+use dialect::Dialect;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use limits::{LimitExceeded, EXIT_LIMIT_EXCEEDED};
+use messages::{Artifact, Messages};
+use options::Options;
+use stats::Stats;
+use vm_translator::{Translation, VMFile};
+
+// Writes the lines separated by newlines, one at a time rather than joined
+// into a single string first
+fn stream_lines<W: Write, L: AsRef<str>>(mut writer: W, lines: &[L]) -> io::Result<W> {
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b"\n")?;
+        }
+        writer.write_all(line.as_ref().as_bytes())?;
+    }
+    Ok(writer)
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Usage: vm_translator_rs <infile or directory>");
+fn write_lines<L: AsRef<str>>(outfile: &Path, asm_output: &[L]) {
+    File::create(outfile)
+        .and_then(|file| stream_lines(BufWriter::new(file), asm_output)?.flush())
+        .unwrap_or_else(|_| {
+            panic!(
+                "Failed to write hack assembly output to {}",
+                outfile.to_str().unwrap()
+            )
+        });
+}
+
+fn gzip<L: AsRef<str>>(outfile: &Path, lines: &[L]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(outfile)?);
+    let encoder = stream_lines(GzEncoder::new(file, Compression::default()), lines)?;
+    encoder.finish()?.flush()
+}
+
+fn write_compressed<L: AsRef<str>>(outfile: &Path, asm_output: &[L]) {
+    gzip(outfile, asm_output).unwrap_or_else(|_| {
+        panic!(
+            "Failed to write compressed hack assembly output to {}",
+            outfile.to_str().unwrap()
+        )
+    });
+}
+
+// Reads a file written by write_compressed
+fn read_compressed(infile: &Path) -> String {
+    let mut text = String::new();
+    File::open(infile)
+        .and_then(|file| GzDecoder::new(file).read_to_string(&mut text))
+        .unwrap_or_else(|_| {
+            panic!(
+                "Failed to read compressed file {}",
+                infile.to_str().unwrap()
+            )
+        });
+    text
+}
+
+// Translates the infile while reading it, with --stream
+fn stream_translation(
+    infile: &Path,
+    compress: bool,
+    translate_options: &vm_translator::TranslateOptions,
+    dialect: &Dialect,
+    asm_path: &Path,
+) -> usize {
+    let translate = || -> io::Result<usize> {
+        let mut file = BufWriter::new(File::create(asm_path)?);
+        if compress {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            let rom_instructions =
+                vm_translator::translate_stream(infile, &mut encoder, translate_options, dialect)?;
+            encoder.finish()?.flush()?;
+            Ok(rom_instructions)
+        } else {
+            let rom_instructions =
+                vm_translator::translate_stream(infile, &mut file, translate_options, dialect)?;
+            file.flush()?;
+            Ok(rom_instructions)
+        }
+    };
+    translate().unwrap_or_else(|_| {
+        panic!(
+            "Failed to write hack assembly output to {}",
+            asm_path.to_str().unwrap()
+        )
+    })
+}
+
+fn write_artifact(
+    messages: &Messages,
+    artifact: Artifact,
+    path: &Path,
+    contents: impl AsRef<[u8]>,
+) {
+    write(path, contents).unwrap_or_else(|_| {
+        panic!(
+            "Failed to write {} to {}",
+            artifact.description(),
+            path.to_str().unwrap()
+        )
+    });
+    messages.artifact_written(artifact, path);
+}
+
+fn write_fragments(
+    messages: &Messages,
+    outfile: &Path,
+    translation: &Translation,
+    files: &[VMFile],
+    dialect: &Dialect,
+) {
+    let fragments_dir = outfile.with_extension("functions");
+    create_dir_all(&fragments_dir).unwrap_or_else(|_| {
+        panic!(
+            "Failed to create fragment directory {}",
+            fragments_dir.to_str().unwrap()
+        )
+    });
+    let mut index = vec![];
+    for fragment in split::split(translation, files) {
+        let fragment_file = format!("{}.asm", fragment.name);
+        let asm: Vec<_> = fragment
+            .asm
+            .iter()
+            .map(|line| dialect.apply(line))
+            .collect();
+        write(fragments_dir.join(&fragment_file), asm.join("\n")).unwrap_or_else(|_| {
+            panic!(
+                "Failed to write fragment {} to {}",
+                fragment_file,
+                fragments_dir.to_str().unwrap()
+            )
+        });
+        index.push(fragment_file);
     }
-    let infile_or_directory = Path::new(&args[1]);
-    let outfile = if infile_or_directory.is_dir() {
+    write_lines(&fragments_dir.join("index.txt"), &index);
+    messages.artifact_written(Artifact::Fragments, &fragments_dir);
+}
+
+// The options of the translation itself, without a bootstrap, which depends
+// on the input
+fn translate_options(options: &Options, outfile: &Path) -> vm_translator::TranslateOptions {
+    let header = match &options.header_file {
+        Some(header_file) => read_to_string(header_file)
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Failed to read header file {}",
+                    header_file.to_str().unwrap()
+                )
+            })
+            .lines()
+            .map(str::to_owned)
+            .collect(),
+        None => vec![],
+    };
+    vm_translator::TranslateOptions {
+        header,
+        test_harness: options.test_harness.clone(),
+        bootstrap: false,
+        end_loop: options.end_loop,
+        annotate: options.annotate,
+        keep_comments: options.keep_comments,
+        mangle: options.mangle,
+        stable_symbols: options.stable_symbols,
+        target: options.target.clone(),
+        instrument: options.instrument.clone(),
+        extensions: options.extensions.clone(),
+        cache: options
+            .cache
+            .then(|| outfile.parent().unwrap().join(cache::CACHE_DIR)),
+    }
+}
+
+// The assembly written for a file or directory: Dir/Dir.asm for a directory
+fn asm_outfile(infile_or_directory: &Path) -> PathBuf {
+    if infile_or_directory.is_dir() {
         infile_or_directory
             .join(infile_or_directory.file_name().unwrap())
             .with_extension("asm")
     } else {
         infile_or_directory.with_extension("asm")
+    }
+}
+
+// The message a panic was raised with, for reporting a caught panic
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    (payload.downcast_ref::<String>().cloned())
+        .or_else(|| {
+            payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+        })
+        .unwrap_or_default()
+}
+
+// Stops the run with exit status 2 if the input exceeded a limit
+fn exit_if_exceeded(messages: &Messages, result: Result<(), LimitExceeded>) {
+    if let Err(exceeded) = result {
+        messages.limit_exceeded(&exceeded);
+        process::exit(EXIT_LIMIT_EXCEEDED);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("diff") => {
+            if !asm_diff::run(&args[1..]) {
+                process::exit(1);
+            }
+            return;
+        }
+        Some("normalize") => return asm_normalize::run(&args[1..]),
+        Some("clean") => return clean::run(&args[1..]),
+        Some("self-test") => return self_test::run(&args[1..]),
+        Some("run") => return run::run(&args[1..]),
+        Some("reduce") => return reduce::run(&args[1..]),
+        Some("size") => return size::run(&args[1..]),
+        Some("batch") => return batch::run(&args[1..]),
+        Some("daemon") => return daemon::run(&args[1..]),
+        _ => {}
+    }
+    // Options from the environment, less those the command line gives
+    let args: Vec<String> = options::env_args(env::vars(), &args)
+        .into_iter()
+        .chain(args)
+        .collect();
+    let message_format = args
+        .iter()
+        .rev()
+        .find_map(|arg| arg.strip_prefix("--message-format="));
+    if message_format == Some("ndjson") {
+        messages::report_errors();
+    }
+    let options = Options::from_args(&args);
+    let messages = Messages::new(options.message_format);
+    let infile_or_directory = options.input.as_path();
+    let outfile = asm_outfile(infile_or_directory);
+    let asm_path = if options.compress {
+        outfile.with_extension("asm.gz")
+    } else {
+        outfile.clone()
+    };
+    let asm_outfile = options.emit_asm.then_some(asm_path.as_path());
+    if let Some(since) = &options.since {
+        let other_inputs: Vec<&Path> = [&options.header_file, &options.target_profile]
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+            .collect();
+        if asm_path.exists()
+            && vcs::changed_since(since, infile_or_directory, &other_inputs).is_empty()
+        {
+            messages.up_to_date(since, &asm_path);
+            return;
+        }
+    }
+    messages.started(infile_or_directory, asm_outfile);
+    let mut translate_options = translate_options(&options, &outfile);
+    let is_directory = infile_or_directory.is_dir();
+    if options.stream {
+        if is_directory {
+            panic!("--stream translates a single file, not a directory");
+        }
+        let input_size = options
+            .limits
+            .check_input_size(&[infile_or_directory.to_path_buf()]);
+        exit_if_exceeded(&messages, input_size);
+        messages.file_started(infile_or_directory);
+        let rom_instructions = stream_translation(
+            infile_or_directory,
+            options.compress,
+            &translate_options,
+            &options.dialect,
+            &asm_path,
+        );
+        options.target.check_rom(rom_instructions);
+        messages.artifact_written(Artifact::Asm, &asm_path);
+        messages.finished(asm_outfile);
+        return;
+    }
+    let input_files = if is_directory {
+        vm_translator::list_directory(infile_or_directory)
+    } else {
+        vec![infile_or_directory.to_path_buf()]
     };
-    println!(
-        "Translating {} and writing hack assembly output to {} ...",
-        infile_or_directory.to_str().unwrap(),
-        outfile.to_str().unwrap()
-    );
-    let asm_output = if infile_or_directory.is_dir() {
-        vm_translator::translate_directory(infile_or_directory)
+    exit_if_exceeded(&messages, options.limits.check_input_size(&input_files));
+    // Directories and bundles hold whole programs, which need a bootstrap.
+    // The files of a directory are translated while they're read, unless
+    // plugins need the whole program first, or it must be counted before any
+    // of it is translated.
+    let parse_first = !options.passes.is_empty()
+        || options.backend.is_some()
+        || options.limits.max_instructions.is_some();
+    let (files, is_bundle, translation) = if is_directory {
+        for file in &input_files {
+            messages.file_started(file);
+        }
+        translate_options.bootstrap = true;
+        if parse_first {
+            let files = input_files
+                .iter()
+                .map(|file| vm_translator::parse_file(file))
+                .collect();
+            (files, false, None)
+        } else {
+            let (files, translation) =
+                vm_translator::translate_paths(&input_files, &translate_options);
+            (files, false, Some(translation))
+        }
     } else {
-        vm_translator::translate_file(infile_or_directory)
+        let (files, is_bundle) = vm_translator::parse_input(infile_or_directory);
+        if is_bundle {
+            for file in &files {
+                messages.file_started(Path::new(&file.name));
+            }
+        } else {
+            messages.file_started(infile_or_directory);
+        }
+        translate_options.bootstrap = is_bundle;
+        (files, is_bundle, None)
     };
-    write_lines(&outfile, &asm_output);
-    println!(
-        "Translation successful; output written to {}",
-        outfile.to_str().unwrap()
-    );
+    let files = plugin::run_passes(&options.passes, files);
+    vm_translator::check_extensions(&files, &options.extensions);
+    exit_if_exceeded(&messages, options.limits.check_instructions(&files));
+    let whole_program = is_directory || is_bundle;
+    let diagnostics = diagnostics::check(&files, whole_program, &options.target.layout);
+    for diagnostic in &diagnostics {
+        messages.diagnostic(diagnostic);
+    }
+    for format in &options.emit_ir {
+        write_artifact(
+            &messages,
+            Artifact::Ir,
+            &outfile.with_extension(format.extension()),
+            ir::render(&files, format),
+        );
+    }
+    if let Some(backend) = &options.backend {
+        write_artifact(
+            &messages,
+            Artifact::Asm,
+            &asm_path,
+            plugin::run_backend(backend, &files),
+        );
+        write_manifest(&messages, &options, &args, &input_files, &translate_options);
+        messages.finished(asm_outfile);
+        return;
+    }
+    let translation =
+        translation.unwrap_or_else(|| vm_translator::translate(&files, &translate_options));
+    if let Some(dir) = &translate_options.cache {
+        cache::prune(dir, cache::MAX_SIZE);
+    }
+    let stats = Stats::collect(&translation, &files, &options.target);
+    options.target.check_rom(stats.rom_instructions);
+    if options.verify_asm {
+        let problems = verify_asm::verify(&translation, &files, &options.target);
+        if !problems.is_empty() {
+            panic!(
+                "The generated assembly failed verification; this is a bug in the translator:\n{}",
+                problems.join("\n")
+            );
+        }
+    }
+    if options.emit_asm {
+        let asm: Vec<_> = (translation.asm.iter())
+            .map(|line| options.dialect.apply(line))
+            .collect();
+        if options.compress {
+            write_compressed(&asm_path, &asm);
+        } else {
+            write_lines(&asm_path, &asm);
+        }
+        messages.artifact_written(Artifact::Asm, &asm_path);
+    }
+    if !options.emit_machine_code.is_empty() {
+        let words = emulator::machine_code(&translation.asm);
+        for format in &options.emit_machine_code {
+            write_artifact(
+                &messages,
+                Artifact::MachineCode,
+                &outfile.with_extension(format.extension()),
+                format.render(&words),
+            );
+        }
+    }
+    if options.source_map {
+        let asm_file = asm_path.file_name().unwrap().to_str().unwrap();
+        write_artifact(
+            &messages,
+            Artifact::SourceMap,
+            &outfile.with_extension("asm.map"),
+            translation.source_map.to_json(asm_file).to_string(),
+        );
+    }
+    if options.listing {
+        write_artifact(
+            &messages,
+            Artifact::Listing,
+            &outfile.with_extension("lst"),
+            listing::render(&translation, &files, &stats.cycles),
+        );
+    }
+    if options.emit_symbols {
+        write_artifact(
+            &messages,
+            Artifact::Symbols,
+            &outfile.with_extension("sym"),
+            translation.symbols.render(),
+        );
+    }
+    if let (Some(map_file), Some(mangler)) = (&options.mangle_map, &translation.mangler) {
+        write_artifact(
+            &messages,
+            Artifact::MangleMap,
+            map_file,
+            mangler.render_map(),
+        );
+    }
+    if options.split_functions {
+        write_fragments(&messages, &outfile, &translation, &files, &options.dialect);
+    }
+    if let Some(stats_file) = &options.stats_json {
+        write_artifact(
+            &messages,
+            Artifact::Stats,
+            stats_file,
+            stats.to_json().to_string(),
+        );
+    }
+    if let Some(report_file) = &options.report {
+        let input = infile_or_directory.to_str().unwrap();
+        write_artifact(
+            &messages,
+            Artifact::Report,
+            report_file,
+            report::render(input, &args, &stats, &files, &diagnostics),
+        );
+    }
+    if let Some(cfg_report_file) = &options.cfg_report {
+        write_artifact(
+            &messages,
+            Artifact::CfgReport,
+            cfg_report_file,
+            cfg_report::render(&translation, &files),
+        );
+    }
+    write_manifest(&messages, &options, &args, &input_files, &translate_options);
+    messages.stats(&stats);
+    messages.finished(asm_outfile);
+}
+
+// Written last, so that it covers every other artifact
+fn write_manifest(
+    messages: &Messages,
+    options: &Options,
+    args: &[String],
+    input_files: &[PathBuf],
+    translate_options: &vm_translator::TranslateOptions,
+) {
+    if let Some(manifest_file) = &options.manifest {
+        let mut inputs = input_files.to_vec();
+        inputs.extend(options.header_file.iter().cloned());
+        inputs.extend(options.target_profile.iter().cloned());
+        let mut artifacts = messages.artifacts();
+        // The cache is the translator's too, for clean to remove
+        artifacts.extend(
+            translate_options
+                .cache
+                .iter()
+                .filter(|dir| dir.is_dir())
+                .map(|dir| ("cache", dir.clone())),
+        );
+        write_artifact(
+            messages,
+            Artifact::Manifest,
+            manifest_file,
+            manifest::render(args, &inputs, &artifacts).to_string(),
+        );
+    }
 }
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+
+use crate::intern::Name;
+
+// Replaces function, label and static symbol names with short opaque ones, so
+// that distributed assembly doesn't reveal the structure of the VM program.
+// The same name always maps to the same symbol.
+#[derive(Default)]
+pub struct Mangler {
+    symbols: HashMap<Name, Name>,
+    // (mangled, original) in order of first use
+    names: Vec<(Name, Name)>,
+}
+
+impl Mangler {
+    pub fn mangle(&mut self, name: Name) -> Name {
+        if let Some(symbol) = self.symbols.get(&name) {
+            return *symbol;
+        }
+        // Unlike the predefined and other generated symbols, these start with `$`
+        let symbol = Name::new(&format!("${}", self.names.len()));
+        self.symbols.insert(name, symbol);
+        self.names.push((symbol, name));
+        symbol
+    }
+
+    // The symbol already given to the name, if any
+    pub fn mangled(&self, name: Name) -> Option<Name> {
+        self.symbols.get(&name).copied()
+    }
+
+    // The de-mangling map: one `mangled original` pair per line
+    pub fn render_map(&self) -> String {
+        self.names
+            .iter()
+            .map(|(symbol, name)| format!("{symbol} {name}\n"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mangler;
+    use crate::intern::Name;
+
+    #[test]
+    fn test_mangle() {
+        let mut mangler = Mangler::default();
+        let mut mangle = |name| mangler.mangle(Name::new(name)).as_str();
+        assert_eq!(mangle("Main.fib"), "$0");
+        assert_eq!(mangle("Main.fib$LOOP"), "$1");
+        assert_eq!(mangle("Main.fib"), "$0");
+        assert_eq!(mangler.render_map(), "$0 Main.fib\n$1 Main.fib$LOOP\n");
+    }
+}
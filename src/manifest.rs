@@ -0,0 +1,68 @@
+use std::fs::{read, read_dir};
+use std::path::{Path, PathBuf};
+
+use crate::hash::sha256;
+use crate::json::Json;
+
+fn file_hash(path: &Path) -> Json {
+    let contents =
+        read(path).unwrap_or_else(|_| panic!("Failed to read {}", path.to_str().unwrap()));
+    Json::from(sha256(&contents))
+}
+
+fn path_json(path: &Path) -> Json {
+    Json::from(path.to_str().unwrap())
+}
+
+// Artifacts written as directories, like the per-function fragments, are
+// recorded file by file
+fn artifact_files(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+    let mut files: Vec<PathBuf> = read_dir(path)
+        .unwrap_or_else(|_| panic!("Failed to read directory {}", path.to_str().unwrap()))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    files.sort();
+    files
+}
+
+// Records what went into and came out of a translation. The schema is
+// documented in the README.
+pub fn render(args: &[String], inputs: &[PathBuf], artifacts: &[(&str, PathBuf)]) -> Json {
+    let inputs = inputs
+        .iter()
+        .map(|input| {
+            Json::object(vec![
+                ("path", path_json(input)),
+                ("sha256", file_hash(input)),
+            ])
+        })
+        .collect();
+    let artifacts = artifacts
+        .iter()
+        .flat_map(|(kind, path)| {
+            artifact_files(path).into_iter().map(move |file| {
+                Json::object(vec![
+                    ("kind", Json::from(*kind)),
+                    ("path", path_json(&file)),
+                    ("sha256", file_hash(&file)),
+                ])
+            })
+        })
+        .collect();
+    Json::object(vec![
+        ("version", Json::from(1usize)),
+        (
+            "tool",
+            Json::object(vec![
+                ("name", Json::from(env!("CARGO_PKG_NAME"))),
+                ("version", Json::from(env!("CARGO_PKG_VERSION"))),
+            ]),
+        ),
+        ("arguments", Json::from(args.to_vec())),
+        ("inputs", Json::Array(inputs)),
+        ("artifacts", Json::Array(artifacts)),
+    ])
+}
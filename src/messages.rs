@@ -0,0 +1,238 @@
+use std::cell::RefCell;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostics::Diagnostic;
+use crate::json::Json;
+use crate::limits::LimitExceeded;
+use crate::stats::Stats;
+
+// Progress and results are either printed as human-readable text, or as
+// newline-delimited JSON events for wrappers and editor integrations. The
+// events are documented in the README.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageFormat {
+    Human,
+    Ndjson,
+}
+
+impl MessageFormat {
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "human" => MessageFormat::Human,
+            "ndjson" => MessageFormat::Ndjson,
+            _ => panic!("Invalid message format: {name}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Artifact {
+    Asm,
+    SourceMap,
+    Listing,
+    Symbols,
+    MangleMap,
+    Ir,
+    Fragments,
+    MachineCode,
+    Stats,
+    Report,
+    CfgReport,
+    Manifest,
+}
+
+impl Artifact {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Artifact::Asm => "asm",
+            Artifact::SourceMap => "source-map",
+            Artifact::Listing => "listing",
+            Artifact::Symbols => "symbols",
+            Artifact::MangleMap => "mangle-map",
+            Artifact::Ir => "ir",
+            Artifact::Fragments => "fragments",
+            Artifact::MachineCode => "machine-code",
+            Artifact::Stats => "stats",
+            Artifact::Report => "report",
+            Artifact::CfgReport => "cfg-report",
+            Artifact::Manifest => "manifest",
+        }
+    }
+
+    pub fn description(&self) -> &str {
+        match *self {
+            Artifact::Asm => "hack assembly output",
+            Artifact::SourceMap => "source map",
+            Artifact::Listing => "listing",
+            Artifact::Symbols => "symbol table",
+            Artifact::MangleMap => "de-mangling map",
+            Artifact::Ir => "parsed program",
+            Artifact::Fragments => "per-function fragments",
+            Artifact::MachineCode => "machine code",
+            Artifact::Stats => "statistics",
+            Artifact::Report => "report",
+            Artifact::CfgReport => "control flow report",
+            Artifact::Manifest => "build manifest",
+        }
+    }
+}
+
+// Reports fatal errors, which are raised as panics anywhere in the
+// translator, as `error` events too, ahead of the usual message on stderr.
+// Installed before the options are parsed, so that invalid options are
+// reported.
+pub fn report_errors() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = crate::panic_message(info.payload());
+        let message = message.split("\nUsage:").next().unwrap_or_default();
+        let fields = vec![
+            ("event", Json::from("error")),
+            ("message", Json::from(message)),
+        ];
+        println!("{}", Json::object(fields));
+        hook(info);
+    }));
+}
+
+pub struct Messages {
+    format: MessageFormat,
+    // The kind and path of every artifact reported, for the build manifest
+    artifacts: RefCell<Vec<(&'static str, PathBuf)>>,
+}
+
+fn path_json(path: &Path) -> Json {
+    Json::from(path.to_str().unwrap())
+}
+
+impl Messages {
+    pub fn new(format: MessageFormat) -> Self {
+        Self {
+            format,
+            artifacts: RefCell::new(vec![]),
+        }
+    }
+
+    fn event(&self, event: &str, mut fields: Vec<(&str, Json)>) {
+        fields.insert(0, ("event", Json::from(event)));
+        println!("{}", Json::object(fields));
+    }
+
+    // The outfile is None when the assembly itself isn't being written
+    pub fn started(&self, input: &Path, outfile: Option<&Path>) {
+        match (&self.format, outfile) {
+            (MessageFormat::Human, Some(outfile)) => println!(
+                "Translating {} and writing hack assembly output to {} ...",
+                input.to_str().unwrap(),
+                outfile.to_str().unwrap()
+            ),
+            (MessageFormat::Human, None) => {
+                println!("Translating {} ...", input.to_str().unwrap())
+            }
+            (MessageFormat::Ndjson, _) => self.event(
+                "translation-started",
+                vec![
+                    ("input", path_json(input)),
+                    ("output", Json::from(outfile.map(path_json))),
+                ],
+            ),
+        }
+    }
+
+    pub fn file_started(&self, file: &Path) {
+        if self.format == MessageFormat::Ndjson {
+            self.event("file-started", vec![("file", path_json(file))]);
+        }
+    }
+
+    pub fn diagnostic(&self, diagnostic: &Diagnostic) {
+        match self.format {
+            MessageFormat::Human => eprintln!("{diagnostic}"),
+            MessageFormat::Ndjson => self.event(
+                "diagnostic",
+                vec![
+                    ("severity", Json::from("warning")),
+                    ("file", Json::from(diagnostic.file.clone())),
+                    ("line", Json::from(diagnostic.line)),
+                    ("message", Json::from(diagnostic.message.as_str())),
+                ],
+            ),
+        }
+    }
+
+    pub fn artifact_written(&self, artifact: Artifact, path: &Path) {
+        self.artifacts
+            .borrow_mut()
+            .push((artifact.kind(), path.to_path_buf()));
+        match self.format {
+            // The assembly output is reported by `finished`
+            MessageFormat::Human if artifact == Artifact::Asm => {}
+            MessageFormat::Human => println!(
+                "Wrote {} to {}",
+                artifact.description(),
+                path.to_str().unwrap()
+            ),
+            MessageFormat::Ndjson => self.event(
+                "artifact-written",
+                vec![
+                    ("kind", Json::from(artifact.kind())),
+                    ("path", path_json(path)),
+                ],
+            ),
+        }
+    }
+
+    pub fn artifacts(&self) -> Vec<(&'static str, PathBuf)> {
+        self.artifacts.borrow().clone()
+    }
+
+    pub fn stats(&self, stats: &Stats) {
+        if self.format == MessageFormat::Ndjson {
+            self.event("stats", vec![("stats", stats.to_json())]);
+        }
+    }
+
+    // Nothing was translated, as the input hasn't changed since the revision
+    pub fn up_to_date(&self, since: &str, outfile: &Path) {
+        match self.format {
+            MessageFormat::Human => println!(
+                "No input files changed since {since}; {} is up to date",
+                outfile.to_str().unwrap()
+            ),
+            MessageFormat::Ndjson => self.event(
+                "up-to-date",
+                vec![("since", Json::from(since)), ("output", path_json(outfile))],
+            ),
+        }
+    }
+
+    // The run is stopped, with no outputs but those already written
+    pub fn limit_exceeded(&self, exceeded: &LimitExceeded) {
+        match self.format {
+            MessageFormat::Human => eprintln!("Limit exceeded: {exceeded}"),
+            MessageFormat::Ndjson => self.event(
+                "limit-exceeded",
+                vec![
+                    ("limit", Json::from(exceeded.limit)),
+                    ("max", Json::from(exceeded.max)),
+                    ("actual", Json::from(exceeded.actual)),
+                ],
+            ),
+        }
+    }
+
+    pub fn finished(&self, outfile: Option<&Path>) {
+        match (&self.format, outfile) {
+            (MessageFormat::Human, Some(outfile)) => println!(
+                "Translation successful; output written to {}",
+                outfile.to_str().unwrap()
+            ),
+            (MessageFormat::Human, None) => println!("Translation successful"),
+            (MessageFormat::Ndjson, _) => self.event(
+                "translation-finished",
+                vec![("output", Json::from(outfile.map(path_json)))],
+            ),
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use std::path::{Path, PathBuf};
+
+use crate::dialect::Dialect;
+use crate::ir::IrFormat;
+use crate::limits::{parse_limit, Limits};
+use crate::machine_code::MachineCodeFormat;
+use crate::messages::MessageFormat;
+use crate::target::Target;
+use crate::vm_translator::parser::Extension;
+use crate::vm_translator::{Instrumentation, TestHarness};
+
+// Command-line options. Anything that isn't an option is taken to be the
+// infile or directory to translate.
+const USAGE: &str = "Usage: vm_translator_rs [options] <infile or directory>
+       vm_translator_rs diff <a.asm> <b.asm>
+       vm_translator_rs normalize <in.asm> [<out.asm>]
+       vm_translator_rs clean <manifest.json>
+       vm_translator_rs self-test [--max-steps=<n>] [--junit=<path>]
+       vm_translator_rs run [--watch] [--max-steps=<n>] [--until-step=<n>] [--ram=<addresses>]
+                           [--resume=<snapshot>] [--snapshot=<path>] [--target-profile=<path>]
+                           [--layout=<key=addr,...>] <infile, directory, .asm or .asm.gz>
+       vm_translator_rs reduce (--test=<command> | --assert=<addr=value,...>) [--max-steps=<n>]
+                               [--target-profile=<path>] [--layout=<key=addr,...>]
+                               <infile or directory> <out.vm>
+       vm_translator_rs size [--target-profile=<path>] [--layout=<key=addr,...>]
+                             <infile or directory>
+       vm_translator_rs batch [--max-steps=<n>] [--junit=<path>] <manifest.json>
+       vm_translator_rs daemon [--socket=<path>]
+
+Options:
+  --emit=<kinds>                Comma-separated outputs to write: asm, map, listing,
+                                symbols, ir-json, ir-xml, fragments, and machine code as
+                                hack, hex (Intel HEX) or bin (default: asm)
+  --source-map                  Same as adding map to --emit
+  --listing                     Same as adding listing to --emit
+  --emit-symbols                Same as adding symbols to --emit
+  --split-functions             Same as adding fragments to --emit
+  --emit-ir=json|xml            Same as adding ir-json or ir-xml to --emit
+  --compress                    Write the assembly gzip-compressed to <out>.asm.gz
+  --test-harness[=<reg=value,...>]
+                                Set SP, LCL, ARG, THIS and THAT first, as the course's
+                                single-function tests do (default: 256, 300, 400, 3000, 3010)
+  --end-loop                    Append a halt loop to the output
+  --annotate                    Precede each VM instruction's code with an @vm comment
+  --keep-comments               Carry comments from the VM source into the assembly
+  --instrument=<checks>         Add runtime checks: stack-guard (at function entry),
+                                stack-guard-pushes (after every push too),
+                                pointer-guard (this/that accesses), trace (VM line in R14)
+  --extensions=<names>          Accept VM instructions beyond the spec: shift (shiftleft,
+                                shiftright), const, halt (halt [<code>]), break
+  -g                            Debug build: same as --source-map --annotate
+                                --instrument=stack-guard,pointer-guard,trace
+  --stable-symbols              Number return addresses per calling function
+  --mangle                      Replace function, label and static names with opaque symbols
+  --mangle-map=<path>           Mangle, and write the de-mangling map to <path>
+  --verify-asm                  Check the generated assembly for code generation bugs before
+                                writing it
+  --stream                      Translate a single file while reading it, writing only
+                                the assembly
+  --since=<git-ref>             Skip the translation if no .vm file of the input, header or
+                                target profile changed since the revision and the output exists
+  --cache                       Reuse translations of unchanged files from earlier runs,
+                                kept in .vm_translator_cache/ next to the output
+  --pass=<command>              Transform the program with a plugin pass, reading and
+                                writing IR JSON; may be given more than once
+  --backend=<command>           Generate the assembly with a plugin backend, reading IR JSON
+  --header-file=<path>          Put the text of <path> at the top of the assembly as comments
+  --asm-dialect=<key=value,...> Write the assembly for other assemblers: case=upper|lower,
+                                jump-spacing=none|after|around, comment=<marker>
+  --target-profile=<path>       Target a Hack variant described by a TOML profile
+  --layout=<key=addr,...>       Override the memory layout: temp_base, static_base,
+                                stack_base, heap_base, screen, keyboard
+  --pin-statics=<File.i=addr,...>
+                                Put the static variables at fixed RAM addresses, allocating
+                                the others around them
+  --stats-json=<path>           Write code-size statistics as JSON
+  --report=<path>               Write a Markdown translation report
+  --cfg-report=<path>           Write a Markdown report of each function's control flow
+  --manifest=<path>             Write a JSON build manifest with input and output hashes
+  --max-input-size=<bytes>      Stop with exit status 2 if the input files are larger
+  --max-instructions=<n>        Stop with exit status 2 if the program has more VM instructions
+  --message-format=human|ndjson Format of progress and diagnostic messages";
+
+// Environment variables named VM_TRANSLATOR_<OPTION> set options for build
+// systems that can't change the command line, e.g. VM_TRANSLATOR_END_LOOP=1
+// for --end-loop or VM_TRANSLATOR_LAYOUT=heap_base=4096 for --layout=...
+pub const ENV_PREFIX: &str = "VM_TRANSLATOR_";
+
+// The options listed in the usage, e.g. --emit or -g, and whether each
+// takes a value, rather than being a flag
+fn known_options() -> impl Iterator<Item = (&'static str, bool)> {
+    USAGE.lines().filter_map(|line| {
+        let line = line.trim_start();
+        let end = line.find(['=', '[', ' ']).unwrap_or(line.len());
+        let takes_value = line[end..].starts_with('=');
+        line.starts_with('-').then_some((&line[..end], takes_value))
+    })
+}
+
+// The options set by environment variables, as arguments to go before those
+// on the command line. An option given on the command line replaces the
+// environment's value entirely, even one that may be given more than once,
+// like --pass. Flags are set by 1 or true and left unset by 0, false or an
+// empty value; one set by the environment can't be unset on the command line.
+// Options taking a value get any value but an empty one, which leaves them
+// unset. Variables not naming an option are ignored with a warning, as they
+// may belong to something else.
+pub fn env_args(
+    vars: impl Iterator<Item = (String, String)>,
+    command_line: &[String],
+) -> Vec<String> {
+    let mut vars: Vec<(String, String)> = vars
+        .filter(|(key, _)| key.starts_with(ENV_PREFIX))
+        .collect();
+    vars.sort();
+    let mut args = vec![];
+    for (key, value) in vars {
+        let name = key[ENV_PREFIX.len()..].to_lowercase().replace('_', "-");
+        let option = if name.len() == 1 {
+            format!("-{name}")
+        } else {
+            format!("--{name}")
+        };
+        let Some((_, takes_value)) = known_options().find(|(known, _)| *known == option) else {
+            eprintln!("warning: ignoring {key}, as {option} isn't an option");
+            continue;
+        };
+        let overridden = command_line.iter().any(|arg| {
+            arg.strip_prefix(option.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+        });
+        if overridden {
+            continue;
+        }
+        match value.as_str() {
+            "" => {}
+            _ if takes_value => args.push(format!("{option}={value}")),
+            "1" | "true" => args.push(option),
+            "0" | "false" => {}
+            _ => args.push(format!("{option}={value}")),
+        }
+    }
+    args
+}
+
+pub struct Options {
+    pub input: PathBuf,
+    pub emit_asm: bool,
+    pub compress: bool,
+    pub source_map: bool,
+    pub listing: bool,
+    pub emit_symbols: bool,
+    pub split_functions: bool,
+    pub test_harness: Option<TestHarness>,
+    pub end_loop: bool,
+    pub verify_asm: bool,
+    pub annotate: bool,
+    pub keep_comments: bool,
+    pub mangle: bool,
+    pub mangle_map: Option<PathBuf>,
+    pub stable_symbols: bool,
+    pub instrument: Instrumentation,
+    pub extensions: Vec<Extension>,
+    pub cache: bool,
+    pub since: Option<String>,
+    pub stream: bool,
+    pub passes: Vec<String>,
+    pub backend: Option<String>,
+    pub header_file: Option<PathBuf>,
+    pub dialect: Dialect,
+    pub target_profile: Option<PathBuf>,
+    pub target: Target,
+    pub emit_ir: Vec<IrFormat>,
+    pub emit_machine_code: Vec<MachineCodeFormat>,
+    pub stats_json: Option<PathBuf>,
+    pub report: Option<PathBuf>,
+    pub cfg_report: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub limits: Limits,
+    pub message_format: MessageFormat,
+}
+
+impl Options {
+    pub fn from_args(args: &[String]) -> Self {
+        let mut input = None;
+        // Without --emit, the assembly is always written
+        let mut emit_asm = None;
+        let mut compress = false;
+        let mut source_map = false;
+        let mut listing = false;
+        let mut emit_symbols = false;
+        let mut split_functions = false;
+        let mut test_harness = None;
+        let mut end_loop = false;
+        let mut verify_asm = false;
+        let mut annotate = false;
+        let mut keep_comments = false;
+        let mut mangle = false;
+        let mut stable_symbols = false;
+        let mut instrument = Instrumentation::default();
+        let mut extensions = vec![];
+        let mut mangle_map = None;
+        let mut cache = false;
+        let mut since = None;
+        let mut stream = false;
+        let mut passes = vec![];
+        let mut backend = None;
+        let mut header_file = None;
+        let mut dialect = Dialect::default();
+        let mut target_profile = None;
+        let mut target = Target::default();
+        // Layout overrides apply on top of the target profile
+        let mut layout_settings = vec![];
+        let mut pins = vec![];
+        let mut emit_ir = vec![];
+        let mut emit_machine_code = vec![];
+        let mut stats_json = None;
+        let mut report = None;
+        let mut cfg_report = None;
+        let mut manifest = None;
+        let mut limits = Limits::default();
+        let mut message_format = MessageFormat::Human;
+        for arg in args {
+            // Options taking a value are given as --option=value
+            let (option, value) = match arg.split_once('=') {
+                Some((option, value)) if arg.starts_with("--") => (option, Some(value)),
+                _ => (arg.as_str(), None),
+            };
+            match (option, value) {
+                ("--emit", Some(value)) => {
+                    for kind in value.split(',') {
+                        match kind {
+                            "asm" => emit_asm = Some(true),
+                            "map" => source_map = true,
+                            "listing" => listing = true,
+                            "symbols" => emit_symbols = true,
+                            "ir-json" => emit_ir.push(IrFormat::Json),
+                            "ir-xml" => emit_ir.push(IrFormat::Xml),
+                            "fragments" => split_functions = true,
+                            _ => match MachineCodeFormat::from_name(kind) {
+                                Some(format) if emit_machine_code.contains(&format) => {}
+                                Some(format) => emit_machine_code.push(format),
+                                None => panic!("Invalid output kind: {kind}\n{USAGE}"),
+                            },
+                        }
+                    }
+                    emit_asm.get_or_insert(false);
+                }
+                ("--compress", None) => compress = true,
+                ("--source-map", None) => source_map = true,
+                ("--listing", None) => listing = true,
+                ("--emit-symbols", None) => emit_symbols = true,
+                ("--split-functions", None) => split_functions = true,
+                ("--test-harness", None) => test_harness = Some(TestHarness::default()),
+                ("--test-harness", Some(value)) => {
+                    test_harness
+                        .get_or_insert_with(TestHarness::default)
+                        .set_all(value);
+                }
+                ("--end-loop", None) => end_loop = true,
+                ("--verify-asm", None) => verify_asm = true,
+                ("--annotate", None) => annotate = true,
+                ("--keep-comments", None) => keep_comments = true,
+                ("--mangle", None) => mangle = true,
+                ("--stable-symbols", None) => stable_symbols = true,
+                ("--instrument", Some(value)) => instrument.set_all(value),
+                ("--extensions", Some(value)) => {
+                    extensions.extend(value.split(',').map(Extension::from_name))
+                }
+                // Everything that helps debugging the translated program
+                ("-g", None) => {
+                    source_map = true;
+                    annotate = true;
+                    instrument.set_all("stack-guard,pointer-guard,trace");
+                }
+                ("--mangle-map", Some(value)) => {
+                    mangle = true;
+                    mangle_map = Some(PathBuf::from(value));
+                }
+                ("--cache", None) => cache = true,
+                ("--since", Some(value)) => since = Some(value.to_owned()),
+                ("--stream", None) => stream = true,
+                ("--pass", Some(value)) => passes.push(value.to_owned()),
+                ("--backend", Some(value)) => backend = Some(value.to_owned()),
+                ("--header-file", Some(value)) => header_file = Some(PathBuf::from(value)),
+                ("--asm-dialect", Some(value)) => dialect = Dialect::parse(value),
+                ("--target-profile", Some(value)) => {
+                    target = Target::load_profile(Path::new(value));
+                    target_profile = Some(PathBuf::from(value));
+                }
+                ("--layout", Some(value)) => layout_settings.push(value),
+                ("--pin-statics", Some(value)) => pins.push(value),
+                ("--emit-ir", Some(value)) => emit_ir.push(IrFormat::from_name(value)),
+                ("--stats-json", Some(value)) => stats_json = Some(PathBuf::from(value)),
+                ("--report", Some(value)) => report = Some(PathBuf::from(value)),
+                ("--cfg-report", Some(value)) => cfg_report = Some(PathBuf::from(value)),
+                ("--manifest", Some(value)) => manifest = Some(PathBuf::from(value)),
+                ("--max-input-size", Some(value)) => {
+                    limits.max_input_size = Some(parse_limit(option, value))
+                }
+                ("--max-instructions", Some(value)) => {
+                    limits.max_instructions = Some(parse_limit(option, value))
+                }
+                ("--message-format", Some(value)) => {
+                    message_format = MessageFormat::from_name(value)
+                }
+                _ if arg.starts_with("--") => panic!("Invalid option: {arg}\n{USAGE}"),
+                _ if input.is_none() => input = Some(PathBuf::from(arg)),
+                _ => panic!("{USAGE}"),
+            }
+        }
+        emit_ir.dedup();
+        for settings in layout_settings {
+            target.layout.set_all(settings);
+        }
+        for pins in pins {
+            target.layout.pin_statics(pins);
+        }
+        target.validate();
+        if !emit_machine_code.is_empty() && target.word_width != 16 {
+            panic!(
+                "Machine code can only be emitted for 16-bit words, not the {} bits of {}",
+                target.word_width, target.name
+            );
+        }
+        let emit_asm = emit_asm.unwrap_or(true);
+        // Nothing but the assembly is kept while streaming
+        if stream
+            && (!emit_asm
+                || source_map
+                || listing
+                || emit_symbols
+                || split_functions
+                || !emit_ir.is_empty()
+                || !emit_machine_code.is_empty()
+                || mangle_map.is_some()
+                || cache
+                || stats_json.is_some()
+                || report.is_some()
+                || cfg_report.is_some()
+                || manifest.is_some())
+        {
+            panic!("--stream can only write the assembly\n{USAGE}");
+        }
+        if stream && (!passes.is_empty() || limits.max_instructions.is_some() || verify_asm) {
+            panic!("--stream can't run passes, count instructions or verify the assembly\n{USAGE}");
+        }
+        // A backend's code is opaque, so nothing is derived from it
+        if backend.is_some()
+            && (!emit_asm
+                || compress
+                || stream
+                || source_map
+                || listing
+                || emit_symbols
+                || split_functions
+                || mangle
+                || cache
+                || stats_json.is_some()
+                || report.is_some()
+                || cfg_report.is_some()
+                || verify_asm
+                || !emit_machine_code.is_empty()
+                || !dialect.is_standard())
+        {
+            panic!("--backend can only write the assembly, IR and manifest\n{USAGE}");
+        }
+        Self {
+            input: input.unwrap_or_else(|| panic!("{USAGE}")),
+            emit_asm,
+            compress,
+            source_map,
+            listing,
+            emit_symbols,
+            split_functions,
+            test_harness,
+            end_loop,
+            verify_asm,
+            annotate,
+            keep_comments,
+            mangle,
+            mangle_map,
+            stable_symbols,
+            instrument,
+            extensions,
+            cache,
+            since,
+            stream,
+            passes,
+            backend,
+            header_file,
+            dialect,
+            target_profile,
+            target,
+            emit_ir,
+            emit_machine_code,
+            stats_json,
+            report,
+            cfg_report,
+            manifest,
+            limits,
+            message_format,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{env_args, Options};
+    use crate::ir::IrFormat;
+    use crate::machine_code::MachineCodeFormat;
+
+    fn parse(args: &[&str]) -> Options {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Options::from_args(&args)
+    }
+
+    #[test]
+    fn test_emit_selects_outputs() {
+        let options = parse(&["--emit=map,symbols,ir-xml", "Main.vm"]);
+        assert!(!options.emit_asm);
+        assert!(options.source_map);
+        assert!(options.emit_symbols);
+        assert!(!options.listing);
+        assert_eq!(options.emit_ir, vec![IrFormat::Xml]);
+
+        let options = parse(&["--listing", "Main.vm"]);
+        assert!(options.emit_asm);
+        assert!(options.listing);
+    }
+
+    #[test]
+    fn test_test_harness() {
+        assert_eq!(parse(&["Main.vm"]).test_harness, None);
+        let harness = parse(&["--test-harness=LCL=317,THAT=3020", "Main.vm"])
+            .test_harness
+            .unwrap();
+        assert_eq!(harness.sp, 256);
+        assert_eq!(harness.lcl, 317);
+        assert_eq!(harness.that, 3020);
+    }
+
+    #[test]
+    fn test_debug_build() {
+        let options = parse(&["-g", "Main.vm"]);
+        assert!(options.emit_asm);
+        assert!(options.source_map);
+        assert!(options.annotate);
+        assert!(options.instrument.stack_guard);
+        assert!(!options.instrument.stack_guard_pushes);
+        assert!(options.instrument.pointer_guard);
+        assert!(options.instrument.trace);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = parse(&["Main.vm"]).limits;
+        assert_eq!(limits.max_input_size, None);
+        assert_eq!(limits.max_instructions, None);
+        let limits = parse(&["--max-input-size=4096", "--max-instructions=100", "Main.vm"]).limits;
+        assert_eq!(limits.max_input_size, Some(4096));
+        assert_eq!(limits.max_instructions, Some(100));
+    }
+
+    #[test]
+    fn test_env_args() {
+        let vars = [
+            ("VM_TRANSLATOR_LAYOUT", "heap_base=4096"),
+            ("VM_TRANSLATOR_END_LOOP", "1"),
+            ("VM_TRANSLATOR_MANGLE", "0"),
+            ("VM_TRANSLATOR_G", "true"),
+            ("VM_TRANSLATOR_PASS", "python3 fold.py"),
+            ("VM_TRANSLATOR_MAX_INSTRUCTIONS", "1"),
+            ("VM_TRANSLATOR_MAX_INPUT_SIZE", ""),
+            ("VM_TRANSLATOR_HOME", "/opt/vm_translator"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(key, value)| (key.to_owned(), value.to_owned()));
+        let args = env_args(vars.clone().into_iter(), &[]);
+        assert_eq!(
+            args,
+            [
+                "--end-loop",
+                "-g",
+                "--layout=heap_base=4096",
+                "--max-instructions=1",
+                "--pass=python3 fold.py"
+            ]
+        );
+        // Options on the command line replace the environment's
+        let command_line =
+            ["--pass=./peephole", "--layout=heap_base=8192", "Main.vm"].map(String::from);
+        let mut args = env_args(vars.into_iter(), &command_line);
+        args.extend(command_line);
+        let options = parse(&args.iter().map(String::as_str).collect::<Vec<_>>());
+        assert!(options.end_loop);
+        assert!(!options.mangle);
+        assert_eq!(options.passes, ["./peephole"]);
+        assert_eq!(options.target.layout.heap_base, 8192);
+        assert_eq!(options.limits.max_instructions, Some(1));
+        assert_eq!(options.limits.max_input_size, None);
+    }
+
+    #[test]
+    fn test_emit_machine_code() {
+        let options = parse(&["--emit=asm,hex,bin,hex", "Main.vm"]);
+        assert!(options.emit_asm);
+        assert_eq!(
+            options.emit_machine_code,
+            [MachineCodeFormat::Hex, MachineCodeFormat::Bin]
+        );
+    }
+}
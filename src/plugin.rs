@@ -0,0 +1,157 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::ir;
+use crate::json::Json;
+use crate::vm_translator::VMFile;
+
+// Passes and backends written outside the crate. A plugin is a program that
+// reads the parsed program as IR JSON (the --emit-ir=json format) on stdin: a
+// pass writes the transformed program to stdout in the same format, and a
+// backend writes the code generated for it. Commands are split into words as
+// by a shell, so plugins can take arguments, with spaces if quoted.
+//
+// Processes rather than WASM modules or dynamic libraries, on purpose: the IR
+// JSON is the whole plugin API.
+
+// Splits the command into words at unquoted whitespace. Single quotes keep
+// everything up to the next one, double quotes everything but a backslash
+// escaping `"` or `\`, and a backslash outside quotes escapes any character.
+fn split_command(command: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    let unterminated = || -> ! { panic!("Unterminated quote in plugin command {command}") };
+    while let Some(c) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => unterminated(),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => unterminated(),
+                        },
+                        Some(c) => word.push(c),
+                        None => unterminated(),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            _ => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+// Runs the command with the program's IR on stdin, returning its stdout
+fn run_plugin(command: &str, files: &[VMFile]) -> String {
+    let words = split_command(command);
+    let [program, args @ ..] = words.as_slice() else {
+        panic!("Empty plugin command");
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|_| panic!("Failed to run plugin {command}"));
+    // Written from another thread, so a plugin writing its output before it
+    // has read all of its input can't block on a full pipe
+    let input = ir::to_json(files).to_string();
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .unwrap_or_else(|_| panic!("Failed to read the output of plugin {command}"));
+    // A plugin may stop reading once it has what it needs
+    let _ = writer.join();
+    if !output.status.success() {
+        panic!("Plugin {command} failed with {}", output.status);
+    }
+    String::from_utf8(output.stdout)
+        .unwrap_or_else(|_| panic!("Plugin {command} wrote invalid UTF-8"))
+}
+
+// Runs the passes in order, each on the output of the one before
+pub fn run_passes(commands: &[String], files: Vec<VMFile>) -> Vec<VMFile> {
+    commands.iter().fold(files, |files, command| {
+        let output = run_plugin(command, &files);
+        let json = Json::parse(&output)
+            .unwrap_or_else(|| panic!("Pass {command} didn't write valid JSON"));
+        ir::from_json(&json).unwrap_or_else(|| panic!("Pass {command} wrote an invalid program"))
+    })
+}
+
+pub fn run_backend(command: &str, files: &[VMFile]) -> String {
+    run_plugin(command, files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_passes, split_command};
+    use crate::vm_translator::parse_source;
+
+    #[test]
+    fn test_identity_pass() {
+        let files = vec![parse_source(
+            "Main.vm",
+            "function Main.main 0\npush constant 1\nreturn\n",
+        )];
+        let files = run_passes(&[String::from("cat")], files);
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].static_base, "Main");
+        let lines: Vec<_> = files[0]
+            .instructions
+            .iter()
+            .map(|instr| (instr.line, instr.instruction.to_string()))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (1, String::from("function Main.main 0")),
+                (2, String::from("push constant 1")),
+                (3, String::from("return")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_command() {
+        assert_eq!(
+            split_command(r#"  python3 "my passes/fold.py" --level='2 3' a\ b "" x"\"y" "#),
+            [
+                "python3",
+                "my passes/fold.py",
+                "--level=2 3",
+                "a b",
+                "",
+                r#"x"y"#
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unterminated quote in plugin command")]
+    fn test_split_command_unterminated() {
+        split_command("python3 'fold.py");
+    }
+}
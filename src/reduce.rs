@@ -0,0 +1,271 @@
+use std::fs::{read_to_string, write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::emulator::Emulator;
+use crate::limits::parse_limit;
+use crate::run;
+use crate::target::Target;
+use crate::vm_translator;
+
+// Shrinks a failing VM program to a small one that still fails, by delta
+// debugging over its lines. Each candidate is written to the output file and
+// checked there, so the output always holds the smallest failing program
+// found so far.
+
+const USAGE: &str =
+    "Usage: vm_translator_rs reduce (--test=<command> | --assert=<addr=value,...>) \
+                     [--max-steps=<n>] [--target-profile=<path>] [--layout=<key=addr,...>] \
+                     <infile or directory> <out.vm>";
+
+const MAX_STEPS: usize = 1_000_000;
+
+enum Check {
+    // A command run with the candidate's path as its last argument, exiting
+    // with status 0 while the candidate still fails
+    Test(String),
+    // RAM values the program should end with, when translated for and run on
+    // the target; it fails while any differs
+    Assert(Vec<(usize, i32)>, Target),
+}
+
+fn parse_assertion(assertion: &str) -> (usize, i32) {
+    let parsed = assertion
+        .split_once('=')
+        .and_then(|(address, value)| Some((address.parse().ok()?, value.parse().ok()?)));
+    parsed.unwrap_or_else(|| panic!("Invalid assertion: {assertion}"))
+}
+
+impl Check {
+    // Whether the program at the path still fails. Programs that don't
+    // translate, crash the emulator or don't stop don't fail the assertions,
+    // so the reduction doesn't drift into a different failure.
+    fn fails(&self, path: &Path, max_steps: usize) -> bool {
+        match self {
+            Check::Test(command) => {
+                let mut parts = command.split_whitespace();
+                let program = parts.next().unwrap_or_else(|| panic!("Empty test command"));
+                Command::new(program)
+                    .args(parts)
+                    .arg(path)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .unwrap_or_else(|_| panic!("Failed to run test {command}"))
+                    .success()
+            }
+            Check::Assert(assertions, target) => {
+                let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut emulator = Emulator::new(&run::translate(path, target), target);
+                    let stopped = emulator.run(max_steps);
+                    stopped
+                        && assertions
+                            .iter()
+                            .any(|&(address, value)| emulator.ram.get(address) != Some(&value))
+                }));
+                outcome.unwrap_or(false)
+            }
+        }
+    }
+}
+
+// The program's lines; a directory is turned into a bundle of its files
+fn read_program(input: &Path) -> Vec<String> {
+    let read = |path: &Path| {
+        read_to_string(path).unwrap_or_else(|_| panic!("Failed to read {}", path.to_str().unwrap()))
+    };
+    if !input.is_dir() {
+        return read(input).lines().map(str::to_owned).collect();
+    }
+    let mut lines = vec![];
+    for path in vm_translator::list_directory(input) {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        lines.push(format!("{} {name}", vm_translator::BUNDLE_MARKER));
+        lines.extend(read(&path).lines().map(str::to_owned));
+    }
+    lines
+}
+
+struct Reducer {
+    check: Check,
+    max_steps: usize,
+    output: PathBuf,
+    // Bundle markers, which are kept so every line stays in its file, by
+    // index in the original program
+    markers: Vec<(usize, String)>,
+    checks: usize,
+}
+
+impl Reducer {
+    // Whether the program made of the markers and the given lines, by index
+    // in the original program, still fails
+    fn fails(&mut self, lines: &[(usize, String)]) -> bool {
+        self.write(lines);
+        self.checks += 1;
+        self.check.fails(&self.output, self.max_steps)
+    }
+
+    fn write(&self, lines: &[(usize, String)]) {
+        let mut program: Vec<&(usize, String)> = self.markers.iter().chain(lines).collect();
+        program.sort_by_key(|(i, _)| *i);
+        let text: Vec<&str> = program.iter().map(|(_, line)| line.as_str()).collect();
+        write(&self.output, text.join("\n") + "\n")
+            .unwrap_or_else(|_| panic!("Failed to write {}", self.output.to_str().unwrap()));
+    }
+
+    // Zeller's ddmin: removes ever smaller chunks of the lines while the
+    // program still fails without them
+    fn reduce(&mut self, mut lines: Vec<(usize, String)>) -> Vec<(usize, String)> {
+        let mut chunks = 2;
+        while lines.len() >= 2 {
+            let size = lines.len().div_ceil(chunks);
+            let mut reduced = false;
+            for start in (0..lines.len()).step_by(size) {
+                let end = (start + size).min(lines.len());
+                let candidate: Vec<(usize, String)> = [&lines[..start], &lines[end..]].concat();
+                if self.fails(&candidate) {
+                    lines = candidate;
+                    chunks = (chunks - 1).max(2);
+                    reduced = true;
+                    break;
+                }
+            }
+            if !reduced {
+                if chunks >= lines.len() {
+                    break;
+                }
+                chunks = (chunks * 2).min(lines.len());
+            }
+        }
+        lines
+    }
+}
+
+// Runs `vm_translator_rs reduce`, writing the reduced program to <out.vm>
+pub fn run(args: &[String]) {
+    let mut test = None;
+    let mut assertions = None;
+    let mut max_steps = MAX_STEPS;
+    let mut target = Target::default();
+    let mut layout_settings = vec![];
+    let mut paths = vec![];
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("--test", command)) => test = Some(command.to_owned()),
+            Some(("--assert", values)) => {
+                assertions = Some(values.split(',').map(parse_assertion).collect())
+            }
+            Some((option @ "--max-steps", value)) => max_steps = parse_limit(option, value),
+            Some(("--target-profile", value)) => target = Target::load_profile(Path::new(value)),
+            Some(("--layout", value)) => layout_settings.push(value),
+            _ if arg.starts_with("--") => panic!("{USAGE}"),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    for settings in layout_settings {
+        target.layout.set_all(settings);
+    }
+    target.validate();
+    let check = match (test, assertions) {
+        (Some(command), None) => Some(Check::Test(command)),
+        (None, Some(assertions)) => Some(Check::Assert(assertions, target)),
+        _ => None,
+    };
+    let (Some(check), [input, output]) = (check, paths.as_slice()) else {
+        panic!("{USAGE}");
+    };
+    let (markers, lines): (Vec<_>, Vec<_>) = read_program(input)
+        .into_iter()
+        .enumerate()
+        .partition(|(_, line)| vm_translator::bundle_marker(line).is_some());
+    let mut reducer = Reducer {
+        check,
+        max_steps,
+        output: output.clone(),
+        markers,
+        checks: 0,
+    };
+    // Candidates that don't translate would print a panic each
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let original = lines.len();
+    let failing = reducer.fails(&lines);
+    let reduced = if failing {
+        // Comments and blank lines go first, in one step
+        let code: Vec<_> = lines
+            .iter()
+            .filter(|(_, line)| {
+                let code = line
+                    .split_once("//")
+                    .map_or(line.as_str(), |(code, _)| code);
+                !code.trim().is_empty()
+            })
+            .cloned()
+            .collect();
+        let lines = if code.len() < lines.len() && reducer.fails(&code) {
+            code
+        } else {
+            lines
+        };
+        reducer.reduce(lines)
+    } else {
+        lines
+    };
+    panic::set_hook(hook);
+    if !failing {
+        panic!("{} doesn't fail the check", input.to_str().unwrap());
+    }
+    // The last candidate checked may not have failed
+    reducer.write(&reduced);
+    println!(
+        "Reduced {} from {original} to {} lines in {} checks; written to {}",
+        input.to_str().unwrap(),
+        reduced.len(),
+        reducer.checks,
+        output.to_str().unwrap()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Check, Reducer};
+    use crate::target::Target;
+
+    #[test]
+    fn test_reduce_to_failing_lines() {
+        let output =
+            std::env::temp_dir().join(format!("vm_translator_reduce_{}.vm", std::process::id()));
+        let lines = [
+            "// FILE: Sys.vm",
+            "function Sys.init 0",
+            "push constant 7",
+            "push constant 8",
+            "add",
+            "pop temp 0",
+            "push constant 2",
+            "pop temp 1",
+            "label END",
+            "goto END",
+        ];
+        let (markers, lines) = lines
+            .iter()
+            .map(|line| line.to_string())
+            .enumerate()
+            .partition(|(i, _)| *i == 0);
+        let mut reducer = Reducer {
+            // temp 0 should end up 0
+            check: Check::Assert(vec![(5, 0)], Target::default()),
+            max_steps: 1000,
+            output: output.clone(),
+            markers,
+            checks: 0,
+        };
+        assert!(reducer.fails(&lines));
+        let reduced = reducer.reduce(lines);
+        let reduced: Vec<&str> = reduced.iter().map(|(_, line)| line.as_str()).collect();
+        assert!(reduced.len() <= 3);
+        assert!(reduced.contains(&"pop temp 0"));
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+use std::cmp::Reverse;
+
+use crate::call_graph::CallGraph;
+use crate::diagnostics::Diagnostic;
+use crate::intern::Name;
+use crate::stats::Stats;
+use crate::vm_translator::VMFile;
+
+// Renders a human-readable Markdown summary of a translation, suitable for
+// attaching to a submission or a pull request
+pub fn render(
+    input: &str,
+    args: &[String],
+    stats: &Stats,
+    files: &[VMFile],
+    diagnostics: &[Diagnostic],
+) -> String {
+    let mut report = String::from("# VM translation report\n\n");
+
+    report.push_str("## Inputs\n\n");
+    report.push_str(&format!("Translated `{input}`.\n\n"));
+    report.push_str("| File | VM instructions | ROM instructions |\n");
+    report.push_str("| --- | ---: | ---: |\n");
+    for file in &stats.files {
+        report.push_str(&format!(
+            "| {} | {} | {} |\n",
+            file.name, file.vm_instructions, file.asm_instructions
+        ));
+    }
+
+    report.push_str("\n## Options\n\n");
+    report.push_str(&format!("```\nvm_translator_rs {}\n```\n", args.join(" ")));
+
+    report.push_str("\n## Functions\n\n");
+    if stats.functions.is_empty() {
+        report.push_str("No functions are defined.\n");
+    } else {
+        let mut functions: Vec<_> = stats.functions.iter().collect();
+        functions.sort_by_key(|function| Reverse(function.asm_instructions));
+        report.push_str("| Function | File | VM instructions | ROM instructions | Calls |\n");
+        report.push_str("| --- | --- | ---: | ---: | ---: |\n");
+        for function in functions {
+            report.push_str(&format!(
+                "| {} | {} | {} | {} | {} |\n",
+                function.name,
+                function.file.as_deref().unwrap_or("-"),
+                function.vm_instructions,
+                function.asm_instructions,
+                function.calls
+            ));
+        }
+    }
+
+    report.push_str("\n## Warnings\n\n");
+    if diagnostics.is_empty() {
+        report.push_str("None.\n");
+    }
+    for diagnostic in diagnostics {
+        report.push_str(&format!("- `{diagnostic}`\n"));
+    }
+
+    report.push_str("\n## Call graph\n\n");
+    let call_graph = CallGraph::build(files);
+    for function in &call_graph.functions {
+        if function.callees.is_empty() {
+            report.push_str(&format!("- `{}` calls nothing\n", function.name));
+        } else {
+            let callees: Vec<String> = function
+                .callees
+                .iter()
+                .map(|callee| format!("`{callee}`"))
+                .collect();
+            report.push_str(&format!(
+                "- `{}` calls {}\n",
+                function.name,
+                callees.join(", ")
+            ));
+        }
+    }
+    let sys_init = Name::new("Sys.init");
+    if call_graph.get(sys_init).is_some() {
+        let reachable = call_graph.reachable_from(sys_init);
+        let unreachable: Vec<String> = call_graph
+            .functions
+            .iter()
+            .filter(|function| !reachable.contains(&function.name))
+            .map(|function| format!("`{}`", function.name))
+            .collect();
+        if !unreachable.is_empty() {
+            report.push_str(&format!(
+                "\nNot reachable from `Sys.init`: {}\n",
+                unreachable.join(", ")
+            ));
+        }
+    }
+
+    report.push_str("\n## Stack usage\n\n");
+    report.push_str(
+        "Worst-case words of stack used by the calls from each entry point, counting \
+         arguments, saved frames and locals.\n\n",
+    );
+    for entry in call_graph.entry_points() {
+        match call_graph.stack_depth(entry) {
+            Some((depth, chain)) => {
+                let chain: Vec<String> = chain.iter().map(|name| format!("`{name}`")).collect();
+                report.push_str(&format!(
+                    "- `{entry}`: {depth} words, through {}\n",
+                    chain.join(" → ")
+                ));
+            }
+            None => report.push_str(&format!("- `{entry}`: unbounded, as it can recurse\n")),
+        }
+    }
+    for cycle in &call_graph.recursion_cycles {
+        let cycle: Vec<String> = cycle.iter().map(|name| format!("`{name}`")).collect();
+        report.push_str(&format!("\nRecursive: {}\n", cycle.join(", ")));
+    }
+
+    report.push_str("\n## ROM budget\n\n");
+    report.push_str(&format!(
+        "{} of {} instructions used ({:.2}%), {} remaining.\n",
+        stats.rom_instructions,
+        stats.rom_size,
+        stats.rom_utilization() * 100.0,
+        stats.rom_size.saturating_sub(stats.rom_instructions)
+    ));
+    report
+}
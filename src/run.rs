@@ -0,0 +1,330 @@
+use std::fs::{read_to_string, write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::emulator::{Emulator, Stop};
+use crate::json::Json;
+use crate::limits::parse_limit;
+use crate::target::Target;
+use crate::vm_translator::parser::Extension;
+use crate::vm_translator::{self, AsmLine, TranslateOptions, Translation, VMFile};
+
+// Runs a VM program, or an assembled one, possibly gzip-compressed as by
+// --compress, on the built-in emulator. With --watch, the sources are
+// polled while it runs, and changed programs are translated again and loaded
+// into the running emulator. Its state can be saved to a snapshot whenever it
+// stops, and a later run resumed from it. The emulator has the memory and
+// word width of the target, which is the standard platform unless
+// --target-profile or --layout say otherwise.
+
+const USAGE: &str = "Usage: vm_translator_rs run [--watch] [--max-steps=<n>] [--until-step=<n>] \
+    [--ram=<addresses>] [--resume=<snapshot>] [--snapshot=<path>] [--target-profile=<path>] \
+    [--layout=<key=addr,...>] <infile, directory, .asm or .asm.gz>";
+
+const MAX_STEPS: usize = 10_000_000;
+// Steps run between checks for changed sources
+const SLICE: usize = 100_000;
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+struct RunOptions {
+    input: PathBuf,
+    watch: bool,
+    max_steps: usize,
+    // The step count, including the steps of a resumed snapshot, at which to
+    // pause the program
+    until_step: Option<usize>,
+    // RAM addresses printed whenever the program stops
+    ram: Vec<usize>,
+    resume: Option<PathBuf>,
+    // Written whenever the program stops
+    snapshot: Option<PathBuf>,
+    target: Target,
+}
+
+impl RunOptions {
+    fn from_args(args: &[String]) -> Self {
+        let mut input = None;
+        let mut watch = false;
+        let mut max_steps = MAX_STEPS;
+        let mut until_step = None;
+        let mut ram = vec![0];
+        let mut resume = None;
+        let mut snapshot = None;
+        let mut target = Target::default();
+        let mut layout_settings = vec![];
+        for arg in args {
+            match arg.split_once('=') {
+                Some((option @ "--max-steps", value)) => max_steps = parse_limit(option, value),
+                Some((option @ "--until-step", value)) => {
+                    until_step = Some(parse_limit(option, value))
+                }
+                Some(("--resume", value)) => resume = Some(PathBuf::from(value)),
+                Some(("--snapshot", value)) => snapshot = Some(PathBuf::from(value)),
+                Some(("--target-profile", value)) => {
+                    target = Target::load_profile(Path::new(value))
+                }
+                Some(("--layout", value)) => layout_settings.push(value),
+                Some(("--ram", value)) => {
+                    ram = value
+                        .split(',')
+                        .map(|address| parse_limit("--ram", address))
+                        .collect()
+                }
+                _ if arg == "--watch" => watch = true,
+                _ if arg.starts_with("--") || input.is_some() => panic!("{USAGE}"),
+                _ => input = Some(PathBuf::from(arg)),
+            }
+        }
+        for settings in layout_settings {
+            target.layout.set_all(settings);
+        }
+        target.validate();
+        Self {
+            input: input.unwrap_or_else(|| panic!("{USAGE}")),
+            watch,
+            max_steps,
+            until_step,
+            ram,
+            resume,
+            snapshot,
+            target,
+        }
+    }
+}
+
+// The program's source files with their modification times, to notice edits
+pub fn source_times(input: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let paths = if input.is_dir() {
+        vm_translator::list_directory(input)
+    } else {
+        vec![input.to_path_buf()]
+    };
+    paths
+        .into_iter()
+        .map(|path| {
+            let modified = path
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+// Translates the program as the translator does by default: directories and
+// bundles start with the bootstrap. halt and break, which are meant for the
+// emulator, are always enabled.
+pub fn translate_program(input: &Path, target: &Target) -> (Vec<VMFile>, Translation) {
+    let (files, bootstrap) = if input.is_dir() {
+        let files = vm_translator::list_directory(input)
+            .iter()
+            .map(|path| vm_translator::parse_file(path))
+            .collect();
+        (files, true)
+    } else {
+        vm_translator::parse_input(input)
+    };
+    let options = TranslateOptions {
+        bootstrap,
+        extensions: vec![Extension::Halt, Extension::Break],
+        target: target.clone(),
+        ..Default::default()
+    };
+    let translation = vm_translator::translate(&files, &options);
+    (files, translation)
+}
+
+pub fn translate(input: &Path, target: &Target) -> Vec<AsmLine> {
+    translate_program(input, target).1.asm
+}
+
+// The program to run: the assembly of an .asm file, or of an .asm.gz file
+// decompressed, or else the translation of the VM program
+fn load_program(input: &Path, target: &Target) -> Vec<AsmLine> {
+    let name = input.to_str().unwrap();
+    let asm = if name.ends_with(".asm.gz") {
+        crate::read_compressed(input)
+    } else if name.ends_with(".asm") {
+        read_to_string(input).unwrap_or_else(|_| panic!("Failed to read {name}"))
+    } else {
+        return translate(input, target);
+    };
+    asm.lines()
+        .map(|line| AsmLine::from(line.to_owned()))
+        .collect()
+}
+
+fn print_state(emulator: &Emulator, addresses: &[usize]) {
+    for &address in addresses {
+        match emulator.ram.get(address) {
+            Some(value) => println!("RAM[{address}] = {value}"),
+            None => println!("RAM[{address}] is out of range"),
+        }
+    }
+}
+
+fn restore(emulator: &mut Emulator, path: &Path) {
+    let path_str = path.to_str().unwrap();
+    let text =
+        read_to_string(path).unwrap_or_else(|_| panic!("Failed to read snapshot {path_str}"));
+    let snapshot =
+        Json::parse(&text).unwrap_or_else(|| panic!("Snapshot {path_str} isn't valid JSON"));
+    if let Err(error) = emulator.restore(&snapshot) {
+        panic!("Snapshot {path_str} {error}");
+    }
+    println!("Resumed from {path_str} at step {}", emulator.steps);
+}
+
+// Prints the state of the stopped program, and saves it with --snapshot
+fn report_stop(emulator: &Emulator, options: &RunOptions) {
+    print_state(emulator, &options.ram);
+    if let Some(path) = &options.snapshot {
+        write(path, format!("{}\n", emulator.snapshot()))
+            .unwrap_or_else(|_| panic!("Failed to write snapshot {}", path.to_str().unwrap()));
+        println!("Saved snapshot to {}", path.to_str().unwrap());
+    }
+}
+
+// The step count to run the program to: --max-steps more than it started
+// at, or --until-step if that comes first
+fn step_limit(options: &RunOptions, start: usize) -> usize {
+    let limit = start + options.max_steps;
+    options.until_step.map_or(limit, |until| until.min(limit))
+}
+
+// What to say about a program that ran out of steps
+fn out_of_steps(emulator: &Emulator, options: &RunOptions) -> String {
+    match options.until_step {
+        Some(until) if emulator.steps >= until => format!("Paused at step {}", emulator.steps),
+        _ => format!("Still running after --max-steps={}", options.max_steps),
+    }
+}
+
+// Runs the program for at most the given number of steps, printing the state
+// at every breakpoint on the way. Whether it finished.
+fn run_steps(emulator: &mut Emulator, steps: usize, addresses: &[usize]) -> bool {
+    let last_step = emulator.steps + steps;
+    loop {
+        match emulator.run_to_breakpoint(last_step - emulator.steps) {
+            Stop::Finished => return true,
+            Stop::Running => return false,
+            Stop::Breakpoint => {
+                println!(
+                    "Breakpoint at PC {} after {} steps",
+                    emulator.pc, emulator.steps
+                );
+                print_state(emulator, addresses);
+            }
+        }
+    }
+}
+
+// Runs `vm_translator_rs run`, exiting with status 1 if the program is still
+// running after --max-steps (without --watch). Pausing at --until-step isn't
+// a failure.
+pub fn run(args: &[String]) {
+    let options = RunOptions::from_args(args);
+    let mut emulator = Emulator::new(
+        &load_program(&options.input, &options.target),
+        &options.target,
+    );
+    if let Some(path) = &options.resume {
+        restore(&mut emulator, path);
+    }
+    let mut limit = step_limit(&options, emulator.steps);
+    if !options.watch {
+        let steps = limit.saturating_sub(emulator.steps);
+        if !run_steps(&mut emulator, steps, &options.ram) {
+            println!("{}", out_of_steps(&emulator, &options));
+            report_stop(&emulator, &options);
+            if options
+                .until_step
+                .is_some_and(|until| emulator.steps >= until)
+            {
+                return;
+            }
+            process::exit(1);
+        }
+        println!("Stopped after {} steps", emulator.steps);
+        report_stop(&emulator, &options);
+        return;
+    }
+    let mut watched = source_times(&options.input);
+    let mut stopped = false;
+    println!(
+        "Running {}; watching for changes",
+        options.input.to_str().unwrap()
+    );
+    loop {
+        if !stopped {
+            let budget = SLICE.min(limit.saturating_sub(emulator.steps));
+            let finished = run_steps(&mut emulator, budget, &options.ram);
+            if finished || emulator.steps >= limit {
+                stopped = true;
+                if finished {
+                    println!("Stopped after {} steps", emulator.steps);
+                } else {
+                    println!("{}", out_of_steps(&emulator, &options));
+                }
+                report_stop(&emulator, &options);
+            }
+        } else {
+            thread::sleep(POLL_INTERVAL);
+        }
+        let current = source_times(&options.input);
+        if current == watched {
+            continue;
+        }
+        watched = current;
+        // A program that fails to translate leaves the loaded one running
+        let translated = panic::catch_unwind(AssertUnwindSafe(|| {
+            load_program(&options.input, &options.target)
+        }));
+        let Ok(asm) = translated else {
+            println!("Translation failed; keeping the loaded program");
+            continue;
+        };
+        match emulator.load(&asm, &options.target) {
+            Some(0) => {}
+            Some(patched) => {
+                let plural = if patched > 1 { "s" } else { "" };
+                println!("Patched {patched} instruction{plural}; RAM and PC kept");
+            }
+            None => {
+                println!("Code layout changed; restarted");
+                stopped = false;
+                limit = step_limit(&options, 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_program, translate};
+    use crate::emulator::Emulator;
+    use crate::target::Target;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_load_compressed() {
+        let dir = std::env::temp_dir().join(format!("vm_translator_run_{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let vm = dir.join("Main.vm");
+        write(&vm, "push constant 6\npush constant 7\nadd\npop temp 0\n").unwrap();
+        let target = Target::default();
+        let asm = translate(&vm, &target);
+        let compressed = dir.join("Main.asm.gz");
+        crate::write_compressed(&compressed, &asm);
+        let loaded = load_program(&compressed, &target);
+        assert_eq!(loaded, asm);
+        let mut emulator = Emulator::new(&loaded, &target);
+        emulator.ram[0] = 256;
+        assert!(emulator.run(100));
+        assert_eq!(emulator.ram[5], 13);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
+
+use crate::emulator::Emulator;
+use crate::junit::{self, TestResult};
+use crate::limits::parse_limit;
+use crate::verify_asm;
+use crate::vm_translator::{self, TestHarness, TranslateOptions};
+
+// The course's tests of projects 7 and 8, built into the binary. Each program
+// is translated, run on the emulator, and the RAM compared with the values the
+// course's test scripts expect.
+
+const USAGE: &str = "Usage: vm_translator_rs self-test [--max-steps=<n>] [--junit=<path>]";
+
+// Enough for every program to finish, or reach its final loop. A program still
+// running after --max-steps fails.
+const MAX_STEPS: usize = 100_000;
+
+struct Case {
+    name: &'static str,
+    files: &'static [(&'static str, &'static str)],
+    // Programs with Sys.init start with the bootstrap; the others are run
+    // with the segment pointers set by the test harness
+    harness: Option<TestHarness>,
+    // RAM set before running, as by the test scripts
+    inputs: &'static [(usize, i32)],
+    expected: &'static [(usize, i32)],
+}
+
+macro_rules! vm_files {
+    ($dir:literal: $($name:literal),+) => {
+        &[$(($name, include_str!(concat!("self_test/", $dir, "/", $name)))),+]
+    };
+}
+
+fn harness(sp: u32, lcl: u32, arg: u32, this: u32, that: u32) -> Option<TestHarness> {
+    Some(TestHarness {
+        sp,
+        lcl,
+        arg,
+        this,
+        that,
+    })
+}
+
+fn cases() -> Vec<Case> {
+    vec![
+        Case {
+            name: "SimpleAdd",
+            files: vm_files!("StackArithmetic": "SimpleAdd.vm"),
+            harness: Some(TestHarness::default()),
+            inputs: &[],
+            expected: &[(0, 257), (256, 15)],
+        },
+        Case {
+            name: "StackTest",
+            files: vm_files!("StackArithmetic": "StackTest.vm"),
+            harness: Some(TestHarness::default()),
+            inputs: &[],
+            expected: &[
+                (0, 266),
+                (256, -1),
+                (257, 0),
+                (258, 0),
+                (259, 0),
+                (260, -1),
+                (261, 0),
+                (262, -1),
+                (263, 0),
+                (264, 0),
+                (265, -91),
+            ],
+        },
+        Case {
+            name: "BasicTest",
+            files: vm_files!("MemoryAccess": "BasicTest.vm"),
+            harness: Some(TestHarness::default()),
+            inputs: &[],
+            expected: &[
+                (256, 472),
+                (300, 10),
+                (401, 21),
+                (402, 22),
+                (3006, 36),
+                (3012, 42),
+                (3015, 45),
+                (11, 510),
+            ],
+        },
+        Case {
+            name: "PointerTest",
+            files: vm_files!("MemoryAccess": "PointerTest.vm"),
+            harness: Some(TestHarness::default()),
+            inputs: &[],
+            expected: &[(256, 6084), (3, 3030), (4, 3040), (3032, 32), (3046, 46)],
+        },
+        Case {
+            name: "StaticTest",
+            files: vm_files!("MemoryAccess": "StaticTest.vm"),
+            harness: Some(TestHarness::default()),
+            inputs: &[],
+            expected: &[(256, 1110)],
+        },
+        Case {
+            name: "BasicLoop",
+            files: vm_files!("ProgramFlow": "BasicLoop.vm"),
+            harness: Some(TestHarness::default()),
+            inputs: &[(400, 3)],
+            expected: &[(0, 257), (256, 6)],
+        },
+        Case {
+            name: "FibonacciSeries",
+            files: vm_files!("ProgramFlow": "FibonacciSeries.vm"),
+            harness: Some(TestHarness::default()),
+            inputs: &[(400, 6), (401, 3000)],
+            expected: &[
+                (3000, 0),
+                (3001, 1),
+                (3002, 1),
+                (3003, 2),
+                (3004, 3),
+                (3005, 5),
+            ],
+        },
+        Case {
+            name: "SimpleFunction",
+            files: vm_files!("FunctionCalls": "SimpleFunction.vm"),
+            harness: harness(317, 317, 310, 3000, 4000),
+            // The frame of a call with two arguments, returning to address
+            // 1000, past the end of the program
+            inputs: &[
+                (310, 1234),
+                (311, 37),
+                (312, 1000),
+                (313, 305),
+                (314, 300),
+                (315, 3010),
+                (316, 4010),
+            ],
+            expected: &[
+                (0, 311),
+                (1, 305),
+                (2, 300),
+                (3, 3010),
+                (4, 4010),
+                (310, 1196),
+            ],
+        },
+        Case {
+            name: "NestedCall",
+            files: vm_files!("FunctionCalls/NestedCall": "Sys.vm"),
+            harness: None,
+            inputs: &[],
+            expected: &[
+                (0, 261),
+                (1, 261),
+                (2, 256),
+                (3, 4000),
+                (4, 5000),
+                (5, 135),
+                (6, 246),
+            ],
+        },
+        Case {
+            name: "FibonacciElement",
+            files: vm_files!("FunctionCalls/FibonacciElement": "Main.vm", "Sys.vm"),
+            harness: None,
+            inputs: &[],
+            expected: &[(0, 262), (261, 3)],
+        },
+        Case {
+            name: "StaticsTest",
+            files: vm_files!("FunctionCalls/StaticsTest": "Class1.vm", "Class2.vm", "Sys.vm"),
+            harness: None,
+            inputs: &[],
+            expected: &[(0, 263), (261, -2), (262, 8)],
+        },
+    ]
+}
+
+// Translates and runs the case, returning a description of every RAM value
+// that differs from the expected one
+fn run_case(case: &Case, options: &TranslateOptions, max_steps: usize) -> Vec<String> {
+    let files: Vec<_> = case
+        .files
+        .iter()
+        .map(|(name, source)| vm_translator::parse_source(name, source))
+        .collect();
+    let options = TranslateOptions {
+        test_harness: case.harness.clone(),
+        bootstrap: case.harness.is_none(),
+        ..options.clone()
+    };
+    let translation = vm_translator::translate(&files, &options);
+    let problems = verify_asm::verify(&translation, &files, &options.target);
+    if !problems.is_empty() {
+        return problems;
+    }
+    let mut emulator = Emulator::new(&translation.asm, &options.target);
+    for &(address, value) in case.inputs {
+        emulator.ram[address] = value;
+    }
+    if !emulator.run(max_steps) {
+        return vec![format!("Still running after --max-steps={max_steps}")];
+    }
+    case.expected
+        .iter()
+        .filter(|(address, value)| emulator.ram[*address] != *value)
+        .map(|(address, value)| {
+            format!(
+                "RAM[{address}] is {}, expected {value}",
+                emulator.ram[*address]
+            )
+        })
+        .collect()
+}
+
+// The option sets every case is translated with
+fn configurations() -> Vec<(&'static str, TranslateOptions)> {
+    vec![
+        ("default", TranslateOptions::default()),
+        (
+            "--mangle --stable-symbols",
+            TranslateOptions {
+                mangle: true,
+                stable_symbols: true,
+                ..Default::default()
+            },
+        ),
+    ]
+}
+
+// Runs every case in every configuration, printing a line for each
+fn run_all(max_steps: usize) -> Vec<TestResult> {
+    let mut results = vec![];
+    for (description, options) in configurations() {
+        for case in cases() {
+            let start = Instant::now();
+            let errors = run_case(&case, &options, max_steps);
+            if errors.is_empty() {
+                println!("ok      {} ({description})", case.name);
+            } else {
+                println!("FAILED  {} ({description})", case.name);
+                for error in &errors {
+                    println!("        {error}");
+                }
+            }
+            results.push(TestResult {
+                name: case.name.to_owned(),
+                class: description.to_owned(),
+                time: start.elapsed(),
+                errors,
+            });
+        }
+    }
+    results
+}
+
+// Runs `vm_translator_rs self-test [--max-steps=<n>] [--junit=<path>]`,
+// exiting with status 1 if any test fails
+pub fn run(args: &[String]) {
+    let mut max_steps = MAX_STEPS;
+    let mut junit = None;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((option @ "--max-steps", value)) => max_steps = parse_limit(option, value),
+            Some(("--junit", value)) => junit = Some(PathBuf::from(value)),
+            _ => panic!("{USAGE}"),
+        }
+    }
+    let results = run_all(max_steps);
+    if let Some(path) = &junit {
+        junit::write_results(path, "self-test", &results);
+    }
+    let failures = (results.iter())
+        .filter(|result| !result.errors.is_empty())
+        .count();
+    if failures > 0 {
+        println!("{failures} failed");
+        process::exit(1);
+    }
+    println!("All tests passed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_all, MAX_STEPS};
+
+    #[test]
+    fn test_self_test_passes() {
+        let results = run_all(MAX_STEPS);
+        assert_eq!(results.len(), 22);
+        assert!(results.iter().all(|result| result.errors.is_empty()));
+    }
+}
@@ -0,0 +1,160 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::run;
+use crate::target::Target;
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{Translation, VMFile};
+
+// Attributes the ROM a program takes to its files, functions and kinds of VM
+// instruction, largest first, to show what to shrink when it doesn't fit
+
+// The code not generated from any VM instruction, e.g. the bootstrap
+const GENERATED: &str = "(generated)";
+// The code preceding the first function of a file
+const NO_FUNCTION: &str = "(outside functions)";
+
+fn category(instruction: &ParsedVMInstruction) -> &'static str {
+    match instruction {
+        ParsedVMInstruction::Push { .. } | ParsedVMInstruction::Pop { .. } => "push/pop",
+        ParsedVMInstruction::Eq | ParsedVMInstruction::Gt | ParsedVMInstruction::Lt => {
+            "comparisons"
+        }
+        ParsedVMInstruction::Call { .. } => "calls",
+        ParsedVMInstruction::Return => "returns",
+        ParsedVMInstruction::Function { .. } => "function entries",
+        ParsedVMInstruction::Label { .. }
+        | ParsedVMInstruction::Goto { .. }
+        | ParsedVMInstruction::IfGoto { .. } => "branches",
+        ParsedVMInstruction::Halt { .. } | ParsedVMInstruction::Break => "halts",
+        _ => "arithmetic",
+    }
+}
+
+// One table of ROM instructions by name, sorted by size and then name
+fn section(report: &mut String, title: &str, sizes: BTreeMap<&str, usize>, total: usize) {
+    let mut sizes: Vec<_> = sizes.into_iter().collect();
+    sizes.sort_by_key(|&(_, size)| Reverse(size));
+    report.push_str(&format!("{title}:\n{:>7}  {:>6}\n", "ROM", "%"));
+    for (name, size) in sizes {
+        let percent = size as f64 * 100.0 / total.max(1) as f64;
+        report.push_str(&format!("{size:7}  {percent:5.1}%  {name}\n"));
+    }
+}
+
+pub fn render(translation: &Translation, files: &[VMFile], target: &Target) -> String {
+    let vm_lines: Vec<HashMap<usize, &ParsedVMInstruction>> = files
+        .iter()
+        .map(|file| {
+            file.instructions
+                .iter()
+                .map(|instr| (instr.line, &instr.instruction))
+                .collect()
+        })
+        .collect();
+    let sources = translation.source_map.sources();
+    let functions = translation.source_map.functions();
+    let mut by_file = BTreeMap::new();
+    let mut by_function = BTreeMap::new();
+    let mut by_category = BTreeMap::new();
+    let mut total = 0;
+    for entry in translation.source_map.entries() {
+        if entry.rom_addr.is_none() {
+            continue;
+        }
+        total += 1;
+        let instruction = entry
+            .source
+            .zip(entry.vm_line)
+            .and_then(|(source, line)| vm_lines[source].get(&line));
+        let file = entry
+            .source
+            .map_or(GENERATED, |source| sources[source].as_str());
+        let function = match (entry.function, instruction) {
+            (Some(function), _) => functions[function].as_str(),
+            (None, Some(_)) => NO_FUNCTION,
+            (None, None) => GENERATED,
+        };
+        *by_file.entry(file).or_default() += 1;
+        *by_function.entry(function).or_default() += 1;
+        *by_category
+            .entry(instruction.map_or(GENERATED, |instr| category(instr)))
+            .or_default() += 1;
+    }
+
+    let mut report = String::new();
+    section(&mut report, "Files", by_file, total);
+    report.push('\n');
+    section(&mut report, "Functions", by_function, total);
+    report.push('\n');
+    section(&mut report, "Categories", by_category, total);
+    report.push_str(&format!(
+        "\nTotal: {total} of {} ROM instructions ({:.1}%)\n",
+        target.rom_size,
+        total as f64 * 100.0 / target.rom_size as f64
+    ));
+    report
+}
+
+const USAGE: &str = "Usage: vm_translator_rs size [--target-profile=<path>] \
+                     [--layout=<key=addr,...>] <infile or directory>";
+
+// Runs `vm_translator_rs size <infile or directory>`, printing the breakdown
+// of the program as the translator translates it by default for the target
+pub fn run(args: &[String]) {
+    let mut input = None;
+    let mut target = Target::default();
+    let mut layout_settings = vec![];
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("--target-profile", value)) => target = Target::load_profile(Path::new(value)),
+            Some(("--layout", value)) => layout_settings.push(value),
+            _ if arg.starts_with("--") || input.is_some() => panic!("{USAGE}"),
+            _ => input = Some(Path::new(arg)),
+        }
+    }
+    for settings in layout_settings {
+        target.layout.set_all(settings);
+    }
+    target.validate();
+    let input = input.unwrap_or_else(|| panic!("{USAGE}"));
+    let (files, translation) = run::translate_program(input, &target);
+    print!("{}", render(&translation, &files, &target));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::target::Target;
+    use crate::vm_translator::{parse_source, translate, TranslateOptions};
+
+    #[test]
+    fn test_render() {
+        let files = vec![parse_source(
+            "Main.vm",
+            "push constant 1\nfunction Main.f 0\npush constant 1\npush constant 2\neq\nreturn\n",
+        )];
+        let translation = translate(&files, &TranslateOptions::default());
+        let report = render(&translation, &files, &Target::default());
+        let total = translation
+            .asm
+            .iter()
+            .filter(|line| !line.starts_with('('))
+            .count();
+        assert!(report.contains(&format!("{total:7}  100.0%  Main.vm\n")));
+        // The return (44 instructions) outweighs the pushes (3 of 6 each)
+        let categories = report.split("Categories:\n").nth(1).unwrap();
+        let order: Vec<&str> = categories
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .map(|line| line.rsplit("  ").next().unwrap())
+            .collect();
+        assert_eq!(order, ["returns", "push/pop", "comparisons"]);
+        assert!(report.contains("(outside functions)\n"));
+        assert!(report.ends_with(&format!(
+            "Total: {total} of 32768 ROM instructions (0.2%)\n"
+        )));
+    }
+}
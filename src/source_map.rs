@@ -0,0 +1,200 @@
+use crate::cache::{optional, parse_optional, Decoder, Encoder};
+use crate::intern::Name;
+use crate::json::Json;
+use crate::target::Word;
+
+// Records which VM source line and function every line of generated assembly
+// came from. Entries are kept in output order, so entry i describes asm line i.
+#[derive(Default)]
+pub struct SourceMap {
+    sources: Vec<String>,
+    functions: Vec<Name>,
+    entries: Vec<SourceMapEntry>,
+    cur_source: Option<usize>,
+    cur_function: Option<usize>,
+    cur_line: Option<usize>,
+}
+
+pub struct SourceMapEntry {
+    // None for label pseudo-instructions, which don't occupy ROM
+    pub rom_addr: Option<Word>,
+    pub source: Option<usize>,
+    pub vm_line: Option<usize>,
+    pub function: Option<usize>,
+}
+
+impl SourceMap {
+    pub fn set_source(&mut self, source: &str) {
+        self.cur_source = Some(self.sources.len());
+        self.sources.push(source.to_owned());
+        self.cur_function = None;
+        self.cur_line = None;
+    }
+
+    // Subsequent entries are attributed to generated code with no VM source
+    pub fn clear_source(&mut self) {
+        self.cur_source = None;
+        self.cur_function = None;
+        self.cur_line = None;
+    }
+
+    fn function_index(&mut self, name: Name) -> usize {
+        match self.functions.iter().position(|&f| f == name) {
+            Some(idx) => idx,
+            None => {
+                self.functions.push(name);
+                self.functions.len() - 1
+            }
+        }
+    }
+
+    pub fn set_function(&mut self, name: Name) {
+        self.cur_function = Some(self.function_index(name));
+    }
+
+    pub fn set_line(&mut self, vm_line: Option<usize>) {
+        self.cur_line = vm_line;
+    }
+
+    // Drops the entries recorded so far, keeping the current position
+    pub fn clear_entries(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn reserve(&mut self, entries: usize) {
+        self.entries.reserve(entries);
+    }
+
+    pub fn record(&mut self, rom_addr: Option<Word>) {
+        self.entries.push(SourceMapEntry {
+            rom_addr,
+            source: self.cur_source,
+            vm_line: self.cur_line,
+            function: self.cur_function,
+        });
+    }
+
+    // Appends the entries of a map built separately, whose ROM addresses
+    // start at rom_offset in this one
+    pub fn append(&mut self, other: SourceMap, rom_offset: Word) {
+        let sources: Vec<usize> = other
+            .sources
+            .into_iter()
+            .map(|source| {
+                self.sources.push(source);
+                self.sources.len() - 1
+            })
+            .collect();
+        let functions: Vec<usize> = other
+            .functions
+            .iter()
+            .map(|&function| self.function_index(function))
+            .collect();
+        self.entries
+            .extend(other.entries.into_iter().map(|entry| SourceMapEntry {
+                rom_addr: entry.rom_addr.map(|rom_addr| rom_addr + rom_offset),
+                source: entry.source.map(|source| sources[source]),
+                vm_line: entry.vm_line,
+                function: entry.function.map(|function| functions[function]),
+            }));
+        self.cur_source = other.cur_source.map(|source| sources[source]);
+        self.cur_function = other.cur_function.map(|function| functions[function]);
+        self.cur_line = other.cur_line;
+    }
+
+    pub fn encode(&self, encoder: &mut Encoder) {
+        encoder.count(self.sources.len());
+        for source in &self.sources {
+            encoder.line(source);
+        }
+        encoder.count(self.functions.len());
+        for function in &self.functions {
+            encoder.line(function);
+        }
+        encoder.count(self.entries.len());
+        for entry in &self.entries {
+            encoder.line(format!(
+                "{} {} {} {}",
+                optional(entry.rom_addr),
+                optional(entry.source),
+                optional(entry.vm_line),
+                optional(entry.function)
+            ));
+        }
+        encoder.line(format!(
+            "{} {} {}",
+            optional(self.cur_source),
+            optional(self.cur_function),
+            optional(self.cur_line)
+        ));
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> Option<Self> {
+        let mut map = SourceMap::default();
+        for _ in 0..decoder.count()? {
+            map.sources.push(decoder.line()?.to_owned());
+        }
+        for _ in 0..decoder.count()? {
+            map.functions.push(Name::new(decoder.line()?));
+        }
+        for _ in 0..decoder.count()? {
+            let mut fields = decoder.line()?.split(' ');
+            map.entries.push(SourceMapEntry {
+                rom_addr: parse_optional(fields.next()?)?,
+                source: parse_optional(fields.next()?)?,
+                vm_line: parse_optional(fields.next()?)?,
+                function: parse_optional(fields.next()?)?,
+            });
+        }
+        let mut fields = decoder.line()?.split(' ');
+        map.cur_source = parse_optional(fields.next()?)?;
+        map.cur_function = parse_optional(fields.next()?)?;
+        map.cur_line = parse_optional(fields.next()?)?;
+        Some(map)
+    }
+
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    pub fn functions(&self) -> &[Name] {
+        &self.functions
+    }
+
+    pub fn entries(&self) -> &[SourceMapEntry] {
+        &self.entries
+    }
+
+    pub fn to_json(&self, asm_file: &str) -> Json {
+        // Schema (version 1) is documented in the README
+        let mappings = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                Json::object(vec![
+                    ("line", Json::from(i + 1)),
+                    ("rom", Json::from(entry.rom_addr)),
+                    ("source", Json::from(entry.source)),
+                    ("vm_line", Json::from(entry.vm_line)),
+                    ("function", Json::from(entry.function)),
+                ])
+            })
+            .collect();
+        Json::object(vec![
+            ("version", Json::Number(1)),
+            ("file", Json::from(asm_file)),
+            ("sources", Json::from(self.sources.clone())),
+            (
+                "functions",
+                Json::Array(
+                    self.functions
+                        .iter()
+                        .map(|function| Json::from(function.as_str()))
+                        .collect(),
+                ),
+            ),
+            ("mappings", Json::Array(mappings)),
+        ])
+    }
+}
@@ -0,0 +1,29 @@
+use crate::vm_translator::{Translation, VMFile};
+
+pub struct Fragment<'a> {
+    pub name: String,
+    pub asm: Vec<&'a str>,
+}
+
+// Splits the generated assembly into one fragment per function, plus one for
+// the bootstrap and one for any code preceding the first function of a file.
+// Fragments are returned in output order.
+pub fn split<'a>(translation: &'a Translation, files: &[VMFile]) -> Vec<Fragment<'a>> {
+    let functions = translation.source_map.functions();
+    let mut fragments: Vec<Fragment> = vec![];
+    let mut prev_origin = None;
+    for (instr, entry) in translation.asm.iter().zip(translation.source_map.entries()) {
+        let origin = (entry.source, entry.function);
+        if prev_origin != Some(origin) {
+            let name = match origin {
+                (_, Some(function)) => functions[function].to_string(),
+                (Some(source), None) => files[source].static_base.clone(),
+                (None, None) => String::from("bootstrap"),
+            };
+            fragments.push(Fragment { name, asm: vec![] });
+            prev_origin = Some(origin);
+        }
+        fragments.last_mut().unwrap().asm.push(instr);
+    }
+    fragments
+}
@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cycles::Cycles;
+use crate::json::Json;
+use crate::target::{Target, Word};
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{MemorySegment, Translation, VMFile};
+
+pub struct FileStats {
+    pub name: String,
+    pub vm_instructions: usize,
+    pub asm_instructions: usize,
+    pub statics: BTreeSet<Word>,
+}
+
+pub struct FunctionStats {
+    pub name: String,
+    pub file: Option<String>,
+    pub vm_instructions: usize,
+    pub asm_instructions: usize,
+    pub calls: usize,
+}
+
+// Code-size metrics for a translated program, suitable for tracking across
+// builds
+pub struct Stats {
+    pub rom_instructions: usize,
+    pub rom_size: usize,
+    // Instructions not attributable to any VM file, e.g. the bootstrap
+    pub generated_instructions: usize,
+    pub files: Vec<FileStats>,
+    pub functions: Vec<FunctionStats>,
+    pub histogram: BTreeMap<String, usize>,
+    pub calls: BTreeMap<String, usize>,
+    pub cycles: Cycles,
+}
+
+impl Stats {
+    pub fn collect(translation: &Translation, files: &[VMFile], target: &Target) -> Self {
+        let source_map = &translation.source_map;
+        let mut file_stats: Vec<FileStats> = files
+            .iter()
+            .map(|file| FileStats {
+                name: file.name.clone(),
+                vm_instructions: 0,
+                asm_instructions: 0,
+                statics: BTreeSet::new(),
+            })
+            .collect();
+        let mut function_stats: Vec<FunctionStats> = source_map
+            .functions()
+            .iter()
+            .map(|name| FunctionStats {
+                name: name.to_string(),
+                file: None,
+                vm_instructions: 0,
+                asm_instructions: 0,
+                calls: 0,
+            })
+            .collect();
+        let mut generated_instructions = 0;
+        let mut rom_instructions = 0;
+        for entry in source_map.entries() {
+            if entry.rom_addr.is_none() {
+                continue;
+            }
+            rom_instructions += 1;
+            match entry.source {
+                Some(source) => file_stats[source].asm_instructions += 1,
+                None => generated_instructions += 1,
+            }
+            if let Some(function) = entry.function {
+                function_stats[function].asm_instructions += 1;
+                function_stats[function].file = entry.source.map(|file| files[file].name.clone());
+            }
+        }
+
+        let mut histogram: BTreeMap<String, usize> = BTreeMap::new();
+        let mut calls: BTreeMap<String, usize> = BTreeMap::new();
+        for (file, stats) in files.iter().zip(file_stats.iter_mut()) {
+            let mut cur_function = None;
+            for instr in &file.instructions {
+                stats.vm_instructions += 1;
+                *histogram
+                    .entry(instr.instruction.command().to_owned())
+                    .or_default() += 1;
+                match &instr.instruction {
+                    ParsedVMInstruction::Function { name, .. } => {
+                        cur_function = source_map.functions().iter().position(|f| f == name);
+                    }
+                    ParsedVMInstruction::Call { name, .. } => {
+                        *calls.entry(name.to_string()).or_default() += 1;
+                    }
+                    ParsedVMInstruction::Pop {
+                        segment: MemorySegment::Static,
+                        idx,
+                    }
+                    | ParsedVMInstruction::Push {
+                        segment: MemorySegment::Static,
+                        idx,
+                    } => {
+                        stats.statics.insert(*idx);
+                    }
+                    _ => {}
+                }
+                if let Some(function) = cur_function {
+                    function_stats[function].vm_instructions += 1;
+                }
+            }
+        }
+        for function in &mut function_stats {
+            function.calls = calls.get(&function.name).copied().unwrap_or(0);
+        }
+
+        Self {
+            rom_instructions,
+            rom_size: target.rom_size,
+            generated_instructions,
+            files: file_stats,
+            functions: function_stats,
+            histogram,
+            calls,
+            cycles: Cycles::estimate(translation, files),
+        }
+    }
+
+    pub fn rom_utilization(&self) -> f64 {
+        self.rom_instructions as f64 / self.rom_size as f64
+    }
+
+    // The schema is documented in the README
+    pub fn to_json(&self) -> Json {
+        let files = self
+            .files
+            .iter()
+            .map(|file| {
+                Json::object(vec![
+                    ("name", Json::from(file.name.as_str())),
+                    ("vm_instructions", Json::from(file.vm_instructions)),
+                    ("asm_instructions", Json::from(file.asm_instructions)),
+                ])
+            })
+            .collect();
+        let functions = self
+            .functions
+            .iter()
+            .zip(&self.cycles.functions)
+            .map(|(function, cycles)| {
+                Json::object(vec![
+                    ("name", Json::from(function.name.as_str())),
+                    ("file", Json::from(function.file.clone())),
+                    ("vm_instructions", Json::from(function.vm_instructions)),
+                    ("asm_instructions", Json::from(function.asm_instructions)),
+                    ("calls", Json::from(function.calls)),
+                    ("cycles", Json::from(cycles.own)),
+                    ("total_cycles", Json::from(cycles.total)),
+                ])
+            })
+            .collect();
+        let statics = self
+            .files
+            .iter()
+            .map(|file| {
+                Json::object(vec![
+                    ("file", Json::from(file.name.as_str())),
+                    ("count", Json::from(file.statics.len())),
+                    (
+                        "indices",
+                        Json::from(file.statics.iter().copied().collect::<Vec<_>>()),
+                    ),
+                ])
+            })
+            .collect();
+        let count_map = |counts: &BTreeMap<String, usize>| {
+            Json::object(
+                counts
+                    .iter()
+                    .map(|(key, count)| (key.as_str(), Json::from(*count)))
+                    .collect(),
+            )
+        };
+
+        Json::object(vec![
+            ("version", Json::Number(1)),
+            (
+                "rom",
+                Json::object(vec![
+                    ("instructions", Json::from(self.rom_instructions)),
+                    (
+                        "generated_instructions",
+                        Json::from(self.generated_instructions),
+                    ),
+                    ("size", Json::from(self.rom_size)),
+                    ("utilization", Json::Float(self.rom_utilization())),
+                ]),
+            ),
+            ("files", Json::Array(files)),
+            ("functions", Json::Array(functions)),
+            ("histogram", count_map(&self.histogram)),
+            ("calls", count_map(&self.calls)),
+            ("statics", Json::Array(statics)),
+            ("cycles", Json::from(self.cycles.program)),
+        ])
+    }
+}
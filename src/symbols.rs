@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use crate::cache::{optional, parse_optional, Decoder, Encoder};
+use crate::intern::Name;
+use crate::target::Word;
+
+// Every symbol the translator defines or references in the generated assembly.
+// Code symbols are recorded with the ROM address they label, and statics with
+// their RAM address when the translator allocates them itself.
+#[derive(Debug, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Label,
+    ReturnAddress,
+    Static,
+}
+
+impl SymbolKind {
+    pub fn name(&self) -> &str {
+        match *self {
+            SymbolKind::Function => "function",
+            SymbolKind::Label => "label",
+            SymbolKind::ReturnAddress => "return",
+            SymbolKind::Static => "static",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "function" => Some(SymbolKind::Function),
+            "label" => Some(SymbolKind::Label),
+            "return" => Some(SymbolKind::ReturnAddress),
+            "static" => Some(SymbolKind::Static),
+            _ => None,
+        }
+    }
+}
+
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: Name,
+    // The label as written in the VM source, for user labels
+    pub vm_label: Option<Name>,
+    pub address: Option<Word>,
+}
+
+#[derive(Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    statics: HashMap<Name, Option<Word>>,
+}
+
+impl SymbolTable {
+    pub fn add_code(
+        &mut self,
+        kind: SymbolKind,
+        name: Name,
+        vm_label: Option<Name>,
+        rom_addr: Word,
+    ) {
+        self.symbols.push(Symbol {
+            kind,
+            name,
+            vm_label,
+            address: Some(rom_addr),
+        });
+    }
+
+    // Returns the static's RAM address, if the translator allocates it,
+    // asking allocate for one on its first use
+    pub fn add_static(
+        &mut self,
+        name: Name,
+        allocate: impl FnOnce() -> Option<Word>,
+    ) -> Option<Word> {
+        if let Some(address) = self.statics.get(&name) {
+            return *address;
+        }
+        let address = allocate();
+        self.statics.insert(name, address);
+        self.symbols.push(Symbol {
+            kind: SymbolKind::Static,
+            name,
+            vm_label: None,
+            address,
+        });
+        address
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // Drops the symbols recorded so far, but keeps the addresses of statics
+    // allocated so far, so that later uses of them get the same address
+    pub fn clear_symbols(&mut self) {
+        self.symbols.clear();
+    }
+
+    // Appends the symbols of a table built separately, whose ROM addresses
+    // start at rom_offset in this one
+    pub fn append(&mut self, other: SymbolTable, rom_offset: Word) {
+        self.statics.extend(other.statics);
+        for mut symbol in other.symbols {
+            if symbol.kind != SymbolKind::Static {
+                symbol.address = symbol.address.map(|address| address + rom_offset);
+            }
+            self.symbols.push(symbol);
+        }
+    }
+
+    // Symbols are encoded like they are rendered, with the fields separated
+    // by single spaces
+    pub fn encode(&self, encoder: &mut Encoder) {
+        encoder.count(self.symbols.len());
+        for symbol in &self.symbols {
+            let mut line = format!(
+                "{} {} {}",
+                symbol.kind.name(),
+                optional(symbol.address),
+                symbol.name
+            );
+            if let Some(vm_label) = symbol.vm_label {
+                line.push_str(&format!(" {vm_label}"));
+            }
+            encoder.line(line);
+        }
+    }
+
+    pub fn decode(decoder: &mut Decoder) -> Option<Self> {
+        let mut table = SymbolTable::default();
+        for _ in 0..decoder.count()? {
+            let mut fields = decoder.line()?.split(' ');
+            let symbol = Symbol {
+                kind: SymbolKind::from_name(fields.next()?)?,
+                address: parse_optional(fields.next()?)?,
+                name: Name::new(fields.next()?),
+                vm_label: fields.next().map(Name::new),
+            };
+            if symbol.kind == SymbolKind::Static {
+                table.statics.insert(symbol.name, symbol.address);
+            }
+            table.symbols.push(symbol);
+        }
+        Some(table)
+    }
+
+    // One symbol per line: kind, address ("-" for statics left to the
+    // assembler), symbol name and, for user labels, the label as written in
+    // the VM source
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for symbol in &self.symbols {
+            let address = match symbol.address {
+                Some(address) => address.to_string(),
+                None => String::from("-"),
+            };
+            let line = match &symbol.vm_label {
+                Some(vm_label) => format!(
+                    "{:<8} {address:>5} {} {vm_label}",
+                    symbol.kind.name(),
+                    symbol.name
+                ),
+                None => format!("{:<8} {address:>5} {}", symbol.kind.name(), symbol.name),
+            };
+            output.push_str(&line);
+            output.push('\n');
+        }
+        output
+    }
+}
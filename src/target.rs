@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+// A machine word or address. Wide enough for every supported word width; the
+// target's actual width is checked where it matters.
+pub type Word = u32;
+
+// Memory layout of the target Hack platform. The defaults are those of the
+// standard platform; modified platforms with different memory maps can
+// override any of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryLayout {
+    // First of the 8 temp registers
+    pub temp_base: Word,
+    // First RAM address for static variables. When None, statics are emitted
+    // as symbols and the assembler allocates them from address 16.
+    pub static_region_base: Option<Word>,
+    // Initial stack pointer set by the bootstrap
+    pub stack_base: Word,
+    pub heap_base: Word,
+    pub screen: Word,
+    pub keyboard: Word,
+    // Static variables at fixed addresses, by name (e.g. Main.3), which the
+    // other statics are allocated around
+    pub pinned_statics: BTreeMap<String, Word>,
+}
+
+const TEMP_SIZE: Word = 8;
+const ASSEMBLER_STATIC_BASE: Word = 16;
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self {
+            temp_base: 5,
+            static_region_base: None,
+            stack_base: 256,
+            heap_base: 2048,
+            screen: 16384,
+            keyboard: 24576,
+            pinned_statics: BTreeMap::new(),
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn set(&mut self, key: &str, value: &str) {
+        let address = value
+            .parse::<Word>()
+            .unwrap_or_else(|_| panic!("Invalid address for {key}: {value}"));
+        self.set_address(key, address);
+    }
+
+    pub fn set_address(&mut self, key: &str, address: Word) {
+        match key {
+            "temp_base" => self.temp_base = address,
+            "static_base" => self.static_region_base = Some(address),
+            "stack_base" => self.stack_base = address,
+            "heap_base" => self.heap_base = address,
+            "screen" => self.screen = address,
+            "keyboard" => self.keyboard = address,
+            _ => panic!("Invalid memory layout setting: {key}"),
+        }
+    }
+
+    // Parses a comma-separated list of key=value settings
+    pub fn set_all(&mut self, settings: &str) {
+        for setting in settings.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid memory layout setting: {setting}"));
+            self.set(key, value);
+        }
+    }
+
+    pub fn static_base(&self) -> Word {
+        self.static_region_base.unwrap_or(ASSEMBLER_STATIC_BASE)
+    }
+
+    // Parses a comma-separated list of File.i=address pins
+    pub fn pin_statics(&mut self, pins: &str) {
+        for pin in pins.split(',') {
+            let (name, address) = pin
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid static pin: {pin}"));
+            let address = address
+                .parse::<Word>()
+                .unwrap_or_else(|_| panic!("Invalid address for {name}: {address}"));
+            self.pin_static(name, address);
+        }
+    }
+
+    pub fn pin_static(&mut self, name: &str, address: Word) {
+        let valid = name
+            .rsplit_once('.')
+            .is_some_and(|(file, idx)| !file.is_empty() && idx.parse::<Word>().is_ok());
+        if !valid {
+            panic!("Invalid static variable {name}: expected File.i");
+        }
+        self.pinned_statics.insert(name.to_owned(), address);
+    }
+
+    pub fn pinned_static(&self, static_base: &str, idx: Word) -> Option<Word> {
+        if self.pinned_statics.is_empty() {
+            return None;
+        }
+        self.pinned_statics
+            .get(&format!("{static_base}.{idx}"))
+            .copied()
+    }
+
+    // Where the translator allocates statics from, if it does: when
+    // static_base is set, or when statics are pinned, since the assembler
+    // wouldn't keep clear of the pinned addresses
+    pub fn static_region(&self) -> Option<Word> {
+        match self.static_region_base {
+            Some(base) => Some(base),
+            None if !self.pinned_statics.is_empty() => Some(ASSEMBLER_STATIC_BASE),
+            None => None,
+        }
+    }
+
+    // The address of the static allocated index-th, skipping pinned addresses
+    pub fn static_address(&self, index: Word) -> Option<Word> {
+        let base = self.static_region()?;
+        let mut pinned: Vec<Word> = self.pinned_statics.values().copied().collect();
+        pinned.sort_unstable();
+        let mut address = base + index;
+        for pin in pinned {
+            if pin >= base && pin <= address {
+                address += 1;
+            }
+        }
+        Some(address)
+    }
+
+    // The regions must not overlap and must be in the standard order
+    pub fn validate(&self) {
+        let regions = [
+            ("temp_base", self.temp_base, self.temp_base + TEMP_SIZE),
+            ("static_base", self.static_base(), self.stack_base),
+            ("stack_base", self.stack_base, self.heap_base),
+            ("heap_base", self.heap_base, self.screen),
+            ("screen", self.screen, self.keyboard),
+        ];
+        for window in regions.windows(2) {
+            let (name, _, end) = window[0];
+            let (next_name, next_start, _) = window[1];
+            if end > next_start {
+                panic!("Invalid memory layout: {name} region overlaps {next_name}");
+            }
+        }
+        let mut pinned: BTreeMap<Word, &str> = BTreeMap::new();
+        for (name, &address) in &self.pinned_statics {
+            if let Some(other) = pinned.insert(address, name) {
+                panic!(
+                    "Static variables {other} and {name} are pinned to the same address {address}"
+                );
+            }
+        }
+    }
+}
+
+// A Hack variant: the standard platform unless a target profile is given
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub name: String,
+    pub word_width: u32,
+    pub ram_size: u32,
+    pub rom_size: usize,
+    pub layout: MemoryLayout,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self {
+            name: String::from("hack"),
+            word_width: 16,
+            ram_size: 24577,
+            rom_size: 32768,
+            layout: MemoryLayout::default(),
+        }
+    }
+}
+
+enum ProfileValue {
+    Integer(u64),
+    String(String),
+}
+
+// The line without its comment, if any: from a # outside a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return line[..i].trim(),
+            _ => {}
+        }
+    }
+    line
+}
+
+// Whether the digits of an integer, e.g. 16_384, use underscores only
+// between digits, as TOML requires
+fn valid_separators(digits: &str) -> bool {
+    !digits.starts_with('_') && !digits.ends_with('_') && !digits.contains("__")
+}
+
+// Parses the subset of TOML used by target profiles: `[section]` headers and
+// `key = value` lines with integer or basic string values, without escapes.
+// Keys are returned prefixed with their section, e.g. `mmio.screen`. Anything
+// else, including a key given twice, is an error.
+fn parse_toml(source: &str) -> Vec<(String, ProfileValue)> {
+    let mut section = String::new();
+    let mut entries: Vec<(String, ProfileValue)> = vec![];
+    for (line_num, line) in source.lines().enumerate() {
+        let line = strip_comment(line.trim());
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = header.trim().to_owned();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .unwrap_or_else(|| panic!("Invalid target profile line {}: {line}", line_num + 1));
+        let (key, value) = (key.trim(), value.trim());
+        let invalid_value = || -> ! {
+            panic!(
+                "Invalid target profile value on line {}: {value}",
+                line_num + 1
+            )
+        };
+        let value = if let Some(string) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+        {
+            if string.contains(['"', '\\']) {
+                invalid_value();
+            }
+            ProfileValue::String(string.to_owned())
+        } else {
+            let (digits, radix) = match value.strip_prefix("0x") {
+                Some(hex) => (hex, 16),
+                None => (value, 10),
+            };
+            if !valid_separators(digits) || digits.starts_with('+') {
+                invalid_value();
+            }
+            let integer = u64::from_str_radix(&digits.replace('_', ""), radix);
+            ProfileValue::Integer(integer.unwrap_or_else(|_| invalid_value()))
+        };
+        let key = if section.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{section}.{key}")
+        };
+        if key.is_empty() || entries.iter().any(|(other, _)| *other == key) {
+            panic!("Invalid target profile key on line {}: {key}", line_num + 1);
+        }
+        entries.push((key, value));
+    }
+    entries
+}
+
+fn convert<T: TryFrom<u64>>(key: &str, integer: u64) -> T {
+    T::try_from(integer)
+        .unwrap_or_else(|_| panic!("Invalid target profile value for {key}: {integer}"))
+}
+
+impl Target {
+    // Keys not given in the profile keep their standard Hack values. The
+    // format is documented in the README.
+    pub fn from_profile(source: &str) -> Self {
+        let mut target = Target::default();
+        for (key, value) in parse_toml(source) {
+            let integer = match value {
+                ProfileValue::Integer(integer) => integer,
+                ProfileValue::String(string) if key == "name" => {
+                    target.name = string;
+                    continue;
+                }
+                ProfileValue::String(_) => panic!("Target profile key {key} must be an integer"),
+            };
+            match key.as_str() {
+                "word_width" => target.word_width = convert::<u32>(&key, integer),
+                "ram_size" => target.ram_size = convert::<u32>(&key, integer),
+                "rom_size" => target.rom_size = convert::<usize>(&key, integer),
+                "registers.temp_base"
+                | "registers.static_base"
+                | "registers.stack_base"
+                | "registers.heap_base"
+                | "mmio.screen"
+                | "mmio.keyboard" => {
+                    let (_, name) = key.split_once('.').unwrap();
+                    let address = convert::<Word>(&key, integer);
+                    target.layout.set_address(name, address);
+                }
+                _ if key.starts_with("pinned_statics.") => {
+                    let (_, name) = key.split_once('.').unwrap();
+                    target
+                        .layout
+                        .pin_static(name, convert::<Word>(&key, integer));
+                }
+                _ => panic!("Invalid target profile key: {key}"),
+            }
+        }
+        target
+    }
+
+    pub fn load_profile(path: &Path) -> Self {
+        let source = read_to_string(path)
+            .unwrap_or_else(|_| panic!("Failed to read target profile {}", path.to_str().unwrap()));
+        Self::from_profile(&source)
+    }
+
+    // The largest constant an A-instruction can load, which also bounds the
+    // addressable memory
+    pub fn max_constant(&self) -> Word {
+        (1 << (self.word_width - 1)) - 1
+    }
+
+    pub fn validate(&self) {
+        if self.word_width != 16 && self.word_width != 32 {
+            panic!(
+                "Unsupported word width {} in target {}: only 16 and 32-bit words are supported",
+                self.word_width, self.name
+            );
+        }
+        self.layout.validate();
+        let addressable = u64::from(self.max_constant()) + 1;
+        if u64::from(self.ram_size) > addressable || self.rom_size as u64 > addressable {
+            panic!(
+                "Invalid target {}: {}-bit A-instructions can't address all of its memory",
+                self.name, self.word_width
+            );
+        }
+        for (name, &address) in &self.layout.pinned_statics {
+            if address >= self.ram_size {
+                panic!(
+                    "Invalid target {}: static variable {name} is pinned outside RAM",
+                    self.name
+                );
+            }
+        }
+        if self.layout.keyboard >= self.ram_size {
+            panic!(
+                "Invalid target {}: keyboard address {} is outside RAM",
+                self.name, self.layout.keyboard
+            );
+        }
+    }
+
+    // The program must fit in the target's instruction memory
+    pub fn check_rom(&self, rom_instructions: usize) {
+        if rom_instructions > self.rom_size {
+            panic!(
+                "Program needs {rom_instructions} ROM instructions, but target {} has only {}",
+                self.name, self.rom_size
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryLayout, Target};
+
+    #[test]
+    fn test_set_all() {
+        let mut layout = MemoryLayout::default();
+        layout.set_all("stack_base=512,static_base=32");
+        layout.validate();
+        assert_eq!(layout.stack_base, 512);
+        assert_eq!(layout.static_region_base, Some(32));
+        assert_eq!(layout.temp_base, 5);
+    }
+
+    #[test]
+    fn test_pinned_statics() {
+        let mut layout = MemoryLayout::default();
+        layout.pin_statics("Main.0=17,Screen.3=24577");
+        layout.validate();
+        assert_eq!(layout.pinned_static("Main", 0), Some(17));
+        assert_eq!(layout.pinned_static("Main", 1), None);
+        // Allocated from 16, as the assembler would, but around address 17
+        let addresses: Vec<_> = (0..3).filter_map(|i| layout.static_address(i)).collect();
+        assert_eq!(addresses, [16, 18, 19]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_validate_overlap() {
+        let mut layout = MemoryLayout::default();
+        layout.set_all("temp_base=10");
+        layout.validate();
+    }
+
+    #[test]
+    fn test_from_profile() {
+        let profile = "
+            # A smaller variant
+            name = \"hack-small\"
+            rom_size = 16_384
+
+            [registers]
+            stack_base = 0x200 # grows up from here
+
+            [mmio]
+            keyboard = 24576
+        ";
+        let target = Target::from_profile(profile);
+        target.validate();
+        assert_eq!(target.name, "hack-small");
+        assert_eq!(target.rom_size, 16384);
+        assert_eq!(target.layout.stack_base, 512);
+        assert_eq!(target.layout.temp_base, 5);
+    }
+
+    #[test]
+    fn test_from_profile_malformed() {
+        let malformed = [
+            "word_width",
+            "[registers\nstack_base = 512",
+            "name = \"unterminated",
+            "name = \"escaped \\\" quote\"",
+            "rom_size = 16__384",
+            "rom_size = _16384",
+            "rom_size = +16384",
+            "rom_size = -1",
+            "rom_size = 0x",
+            "rom_size = 1e4",
+            "rom_size = 1\nrom_size = 2",
+            " = 1",
+            "[mmio]\nscreen = \"16384\"",
+            "ram_size = 0x1_0000_0000",
+        ];
+        for profile in malformed {
+            let parsed = std::panic::catch_unwind(|| Target::from_profile(profile));
+            assert!(parsed.is_err(), "{profile:?}");
+        }
+        // A comment may follow a string, and a # in a string isn't one
+        let target = Target::from_profile("name = \"hack#2\" # the second");
+        assert_eq!(target.name, "hack#2");
+    }
+
+    #[test]
+    fn test_word_width() {
+        let target =
+            Target::from_profile("word_width = 32\nram_size = 0x100000\nrom_size = 0x100000");
+        target.validate();
+        assert_eq!(target.max_constant(), 2147483647);
+        assert_eq!(Target::default().max_constant(), 32767);
+    }
+}
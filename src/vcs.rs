@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Asks git which files of the input changed since a revision, so that inputs
+// nothing changed in can be skipped, e.g. by CI in a repository of many
+// projects
+
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .unwrap_or_else(|_| panic!("Failed to run git"));
+    if !output.status.success() {
+        panic!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8(output.stdout).unwrap_or_else(|_| panic!("git wrote invalid UTF-8"))
+}
+
+// The files among the NUL-separated paths git listed relative to the
+// directory, only the .vm ones if vm_only
+fn listed_paths(listing: &str, dir: &Path, vm_only: bool) -> Vec<PathBuf> {
+    listing
+        .split('\0')
+        .filter(|path| !path.is_empty() && (!vm_only || path.ends_with(".vm")))
+        .map(|path| dir.join(path))
+        .collect()
+}
+
+// The .vm files of the input (a file or directory), and the other files it's
+// translated with, such as the header, that were added, modified or deleted
+// since the revision, committed or not, or that git doesn't track
+pub fn changed_since(revision: &str, input: &Path, other_inputs: &[&Path]) -> Vec<PathBuf> {
+    // Anything starting with - would be taken for one of git's options
+    if revision.starts_with('-') {
+        panic!("Invalid revision for --since: {revision}");
+    }
+    let mut paths = changed_files(revision, input, true);
+    for other in other_inputs {
+        paths.extend(changed_files(revision, other, false));
+    }
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+fn changed_files(revision: &str, input: &Path, vm_only: bool) -> Vec<PathBuf> {
+    let (dir, pathspec) = if input.is_dir() {
+        (input, String::from("."))
+    } else {
+        let name = input.file_name().unwrap().to_str().unwrap().to_owned();
+        (
+            input
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+            name,
+        )
+    };
+    let changed = git(
+        dir,
+        &[
+            "diff",
+            "--name-only",
+            "--relative",
+            "-z",
+            revision,
+            "--",
+            &pathspec,
+        ],
+    );
+    let untracked = git(
+        dir,
+        &[
+            "ls-files",
+            "--others",
+            "--exclude-standard",
+            "-z",
+            "--",
+            &pathspec,
+        ],
+    );
+    let mut paths = listed_paths(&changed, dir, vm_only);
+    paths.extend(listed_paths(&untracked, dir, vm_only));
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_since, listed_paths};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_listed_paths() {
+        let dir = Path::new("projects/08");
+        assert_eq!(
+            listed_paths("Main.vm\0README.md\0Sys.vm\0", dir, true),
+            [
+                PathBuf::from("projects/08/Main.vm"),
+                PathBuf::from("projects/08/Sys.vm")
+            ]
+        );
+        assert_eq!(
+            listed_paths("header.txt\0", dir, false),
+            [PathBuf::from("projects/08/header.txt")]
+        );
+        assert!(listed_paths("", dir, true).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid revision for --since: --output=x")]
+    fn test_option_revision() {
+        changed_since("--output=x", Path::new("Main.vm"), &[]);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::emulator::{parse_compute, predefined};
+use crate::intern::Name;
+use crate::symbols::SymbolKind;
+use crate::target::Target;
+use crate::vm_translator::parser::ParsedVMInstruction;
+use crate::vm_translator::{Translation, VMFile};
+
+// Checks the generated assembly against what the translator meant to generate,
+// to catch code generation bugs before a program misbehaves in an emulator:
+// every line assembles, constants fit in an A-instruction, labels are defined
+// once and where the symbol table says, jumps go to labels, every variable is
+// a static, and the translator counted the instructions right. Functions the
+// program calls but doesn't define, such as those of the OS, are left to be
+// linked in, as is Sys.init, which the bootstrap calls.
+
+fn is_symbol(symbol: &str) -> bool {
+    !symbol.starts_with(|c: char| c.is_ascii_digit())
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+// The symbols of the functions the program calls, as they're written in the
+// assembly
+fn called_functions(translation: &Translation, files: &[VMFile]) -> HashSet<Name> {
+    let calls = files.iter().flat_map(|file| &file.instructions);
+    let names = calls
+        .filter_map(|instr| match instr.instruction {
+            ParsedVMInstruction::Call { name, .. } => Some(name),
+            _ => None,
+        })
+        .chain([Name::new("Sys.init")]);
+    names
+        .map(|name| match &translation.mangler {
+            Some(mangler) => mangler.mangled(name).unwrap_or(name),
+            None => name,
+        })
+        .collect()
+}
+
+// Returns a description of every problem, with the line of the assembly it's
+// on, counting from 1
+pub fn verify(translation: &Translation, files: &[VMFile], target: &Target) -> Vec<String> {
+    let mut problems = vec![];
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    // Each instruction with its line
+    let mut code = vec![];
+    for (i, line) in translation.asm.iter().enumerate() {
+        let text = line
+            .split_once("//")
+            .map_or(&**line, |(code, _)| code)
+            .trim();
+        if let Some(label) = text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            if !is_symbol(label) {
+                problems.push(format!("line {}: invalid label {label}", i + 1));
+            } else if labels.insert(label, code.len()).is_some() {
+                problems.push(format!("line {}: label {label} defined again", i + 1));
+            }
+        } else if !text.is_empty() {
+            code.push((i + 1, text));
+        }
+    }
+    if code.len() != translation.rom_instructions {
+        problems.push(format!(
+            "the assembly has {} instructions, but the translator counted {}",
+            code.len(),
+            translation.rom_instructions
+        ));
+    }
+
+    let statics: HashSet<&str> = (translation.symbols.symbols().iter())
+        .filter(|symbol| symbol.kind == SymbolKind::Static)
+        .map(|symbol| symbol.name.as_str())
+        .collect();
+    let called = called_functions(translation, files);
+    let is_code = |symbol: &str| {
+        labels.contains_key(symbol)
+            || Name::lookup(symbol).is_some_and(|name| called.contains(&name))
+    };
+    for (i, &(line, text)) in code.iter().enumerate() {
+        if let Some(value) = text.strip_prefix('@') {
+            if value.starts_with(|c: char| c.is_ascii_digit()) {
+                let fits = value.parse::<u64>().ok();
+                if fits.is_none_or(|value| value > u64::from(target.max_constant())) {
+                    problems.push(format!(
+                        "line {line}: constant {value} doesn't fit in an A-instruction"
+                    ));
+                }
+            } else if !is_symbol(value) {
+                problems.push(format!("line {line}: invalid symbol {value}"));
+            } else if !is_code(value)
+                && predefined(value, &target.layout).is_none()
+                && !statics.contains(&value)
+            {
+                problems.push(format!(
+                    "line {line}: {value} is neither a label, a called function nor a static variable"
+                ));
+            }
+            continue;
+        }
+        let Some(compute) = parse_compute(&text.replace(' ', "")) else {
+            problems.push(format!("line {line}: invalid instruction {text}"));
+            continue;
+        };
+        // A jump's target is loaded by the instruction before it, unless
+        // that computes it
+        let target = match i.checked_sub(1).map(|i| code[i].1) {
+            Some(previous) if compute.jump != 0 => previous.strip_prefix('@'),
+            _ => None,
+        };
+        match target.map(|target| (target, target.parse::<usize>())) {
+            Some((target, Ok(address))) if address >= code.len() => {
+                problems.push(format!(
+                    "line {line}: jump to {target}, past the end of the program"
+                ));
+            }
+            Some((target, Err(_))) if !is_code(target) => {
+                problems.push(format!(
+                    "line {line}: jump to {target}, which isn't a label"
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    for symbol in translation.symbols.symbols() {
+        let (Some(address), false) = (symbol.address, symbol.kind == SymbolKind::Static) else {
+            continue;
+        };
+        match labels.get(symbol.name.as_str()) {
+            Some(&defined) if defined == address as usize => {}
+            Some(defined) => problems.push(format!(
+                "{} {} is at {defined}, but the symbol table has {address}",
+                symbol.kind.name(),
+                symbol.name
+            )),
+            None => problems.push(format!(
+                "{} {} isn't defined",
+                symbol.kind.name(),
+                symbol.name
+            )),
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use crate::target::Target;
+    use crate::vm_translator::{parse_source, translate, AsmLine, TranslateOptions};
+
+    #[test]
+    fn test_verify() {
+        let source = "function Main.main 0
+push constant 1
+pop static 0
+label LOOP
+push static 0
+push constant 2
+lt
+if-goto LOOP
+call Main.main 0
+return
+";
+        let files = vec![parse_source("Main.vm", source)];
+        let options = TranslateOptions {
+            bootstrap: true,
+            end_loop: true,
+            ..Default::default()
+        };
+        let mut translation = translate(&files, &options);
+        let target = Target::default();
+        assert_eq!(verify(&translation, &files, &target), Vec::<String>::new());
+
+        // A mistyped jump target and an extra instruction
+        let jump = (translation.asm.iter())
+            .position(|line| line.starts_with("@Main.main$LOOP"))
+            .unwrap();
+        translation.asm[jump] = AsmLine::from("@Main.main$LOPP");
+        translation.asm.push(AsmLine::from("@32768"));
+        // A jump to just past the last instruction
+        let end = translation.rom_instructions + 3;
+        translation.asm.push(AsmLine::from(format!("@{end}")));
+        translation.asm.push(AsmLine::from("0;JMP"));
+        let problems = verify(&translation, &files, &target);
+        assert!(problems.contains(&format!(
+            "line {}: Main.main$LOPP is neither a label, a called function nor a static variable",
+            jump + 1
+        )));
+        assert!(problems.contains(&format!(
+            "line {}: jump to Main.main$LOPP, which isn't a label",
+            jump + 2
+        )));
+        assert!(problems
+            .iter()
+            .any(|problem| problem.ends_with("constant 32768 doesn't fit in an A-instruction")));
+        assert!(problems.contains(&format!(
+            "line {}: jump to {end}, past the end of the program",
+            translation.asm.len()
+        )));
+        assert!(problems
+            .iter()
+            .any(|problem| problem.starts_with("the assembly has")));
+    }
+}
@@ -1,9 +1,24 @@
-use std::fs::read_to_string;
-use std::path::Path;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::sync_channel;
+use std::thread;
 
+use memchr::memchr_iter;
+use rayon::prelude::*;
+
+use crate::cache::{self, Decoder, Encoder};
+use crate::dialect::Dialect;
+use crate::mangle::Mangler;
+use crate::source_map::SourceMap;
+use crate::symbols::SymbolTable;
+use crate::target::{Target, Word};
+use parser::{Extension, ParsedVMInstruction};
 use translator::Translator;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemorySegment {
     Local,
     Argument,
@@ -16,6 +31,19 @@ pub enum MemorySegment {
 }
 
 impl MemorySegment {
+    pub fn name(&self) -> &str {
+        match *self {
+            MemorySegment::Local => "local",
+            MemorySegment::Argument => "argument",
+            MemorySegment::This => "this",
+            MemorySegment::That => "that",
+            MemorySegment::Constant => "constant",
+            MemorySegment::Static => "static",
+            MemorySegment::Pointer => "pointer",
+            MemorySegment::Temp => "temp",
+        }
+    }
+
     pub fn seg_ptr(&self) -> &str {
         match *self {
             MemorySegment::Local => "LCL",
@@ -27,12 +55,19 @@ impl MemorySegment {
     }
 }
 
-mod parser {
+pub mod parser {
     // Takes a VM instruction and parses it into the type of instruction it is
     // as well as its individual components if necessary
+    use std::collections::HashMap;
+    use std::fmt;
+
     use super::MemorySegment;
+    use crate::intern::Name;
+    use crate::target::Word;
 
-    #[derive(Debug, PartialEq)]
+    // Plain values, with names interned, so that a file's instructions are
+    // stored in a single allocation
+    #[derive(Debug, Clone, Copy, PartialEq)]
     pub enum ParsedVMInstruction {
         Add,
         Sub,
@@ -43,19 +78,197 @@ mod parser {
         And,
         Or,
         Not,
-        Pop { segment: MemorySegment, idx: u16 },
-        Push { segment: MemorySegment, idx: u16 },
-        Label { label: String },
-        Goto { label: String },
-        IfGoto { label: String },
-        Function { name: String, num_local_vars: u16 },
-        Call { name: String, num_args: u16 },
+        ShiftLeft,
+        ShiftRight,
+        Pop { segment: MemorySegment, idx: Word },
+        Push { segment: MemorySegment, idx: Word },
+        Label { label: Name },
+        Goto { label: Name },
+        IfGoto { label: Name },
+        Function { name: Name, num_local_vars: Word },
+        Call { name: Name, num_args: Word },
         Return,
+        // Stores the exit code and stops the program
+        Halt { code: Word },
+        // A breakpoint for the built-in emulator, with no code of its own
+        Break,
+        // Names value for the rest of the file. Uses of the name are
+        // resolved while parsing, so it generates no code.
+        Const { name: Name, value: Word },
+    }
+
+    // Instructions beyond the VM spec, for platforms with extra ALU
+    // operations. They're parsed like any other instruction, but only
+    // translated when enabled. To add one, add a variant here, name it in
+    // from_name and name, and give the instructions it adds to
+    // ParsedVMInstruction, parse_instruction and Translator::translate.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Extension {
+        // shiftleft and shiftright, using the `<<` and `>>` ALU operations
+        Shift,
+        // const declarations
+        Const,
+        // halt, stopping the program with an exit code
+        Halt,
+        // break, stopping the built-in emulator
+        Break,
+    }
+
+    impl Extension {
+        pub fn from_name(name: &str) -> Self {
+            match name {
+                "shift" => Extension::Shift,
+                "const" => Extension::Const,
+                "halt" => Extension::Halt,
+                "break" => Extension::Break,
+                _ => panic!("Invalid extension: {name}"),
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                Extension::Shift => "shift",
+                Extension::Const => "const",
+                Extension::Halt => "halt",
+                Extension::Break => "break",
+            }
+        }
+    }
+
+    impl ParsedVMInstruction {
+        pub fn command(&self) -> &str {
+            match self {
+                ParsedVMInstruction::Add => "add",
+                ParsedVMInstruction::Sub => "sub",
+                ParsedVMInstruction::Neg => "neg",
+                ParsedVMInstruction::Eq => "eq",
+                ParsedVMInstruction::Gt => "gt",
+                ParsedVMInstruction::Lt => "lt",
+                ParsedVMInstruction::And => "and",
+                ParsedVMInstruction::Or => "or",
+                ParsedVMInstruction::Not => "not",
+                ParsedVMInstruction::ShiftLeft => "shiftleft",
+                ParsedVMInstruction::ShiftRight => "shiftright",
+                ParsedVMInstruction::Pop { .. } => "pop",
+                ParsedVMInstruction::Push { .. } => "push",
+                ParsedVMInstruction::Label { .. } => "label",
+                ParsedVMInstruction::Goto { .. } => "goto",
+                ParsedVMInstruction::IfGoto { .. } => "if-goto",
+                ParsedVMInstruction::Function { .. } => "function",
+                ParsedVMInstruction::Call { .. } => "call",
+                ParsedVMInstruction::Return => "return",
+                ParsedVMInstruction::Halt { .. } => "halt",
+                ParsedVMInstruction::Break => "break",
+                ParsedVMInstruction::Const { .. } => "const",
+            }
+        }
+
+        // The extension the instruction belongs to, if it isn't in the spec
+        pub fn extension(&self) -> Option<Extension> {
+            match self {
+                ParsedVMInstruction::ShiftLeft | ParsedVMInstruction::ShiftRight => {
+                    Some(Extension::Shift)
+                }
+                ParsedVMInstruction::Const { .. } => Some(Extension::Const),
+                ParsedVMInstruction::Halt { .. } => Some(Extension::Halt),
+                ParsedVMInstruction::Break => Some(Extension::Break),
+                _ => None,
+            }
+        }
     }
 
+    impl fmt::Display for ParsedVMInstruction {
+        // Writes the instruction back out in its canonical VM source form
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let command = self.command();
+            match self {
+                ParsedVMInstruction::Pop { segment, idx }
+                | ParsedVMInstruction::Push { segment, idx } => {
+                    write!(f, "{command} {} {idx}", segment.name())
+                }
+                ParsedVMInstruction::Label { label }
+                | ParsedVMInstruction::Goto { label }
+                | ParsedVMInstruction::IfGoto { label } => write!(f, "{command} {label}"),
+                ParsedVMInstruction::Function {
+                    name,
+                    num_local_vars: num,
+                }
+                | ParsedVMInstruction::Call {
+                    name,
+                    num_args: num,
+                }
+                | ParsedVMInstruction::Const { name, value: num } => {
+                    write!(f, "{command} {name} {num}")
+                }
+                ParsedVMInstruction::Halt { code } => write!(f, "{command} {code}"),
+                _ => write!(f, "{command}"),
+            }
+        }
+    }
+
+    // The constants declared so far in a file
+    #[derive(Clone, Default)]
+    pub struct Constants {
+        values: HashMap<Name, Word>,
+    }
+
+    impl Constants {
+        // Parses the instruction, resolving the constants it uses and
+        // declaring the one it defines
+        pub fn parse(&mut self, instruction: &str) -> ParsedVMInstruction {
+            let parsed = parse_with(instruction, self);
+            if let ParsedVMInstruction::Const { name, value } = parsed {
+                if self.values.insert(name, value).is_some() {
+                    panic!("Constant {name} is already defined");
+                }
+            }
+            parsed
+        }
+
+        // A segment index or constant value, either a number or a constant
+        fn resolve(&self, operand: &str) -> Word {
+            if let Ok(number) = operand.parse::<Word>() {
+                return number;
+            }
+            if operand.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+                panic!("Invalid number: {operand}");
+            }
+            *(Name::lookup(operand).and_then(|name| self.values.get(&name)))
+                .unwrap_or_else(|| panic!("Undefined constant: {operand}"))
+        }
+    }
+
+    // Parses an instruction on its own, without any constants declared
     pub fn parse_instruction(instruction: &str) -> ParsedVMInstruction {
-        let split_instr: Vec<&str> = instruction.split(" ").collect();
-        match split_instr[0] {
+        parse_with(instruction, &Constants::default())
+    }
+
+    // Label and function names are letters, digits, '_', '.' and ':', not
+    // starting with a digit, as the VM spec has them. The translator's own
+    // labels contain a second '$', so they can't clash with `function$label`
+    fn symbol(kind: &str, name: &str) -> Name {
+        let valid = !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.:".contains(c));
+        if !valid {
+            panic!("Invalid {kind} name: {name}");
+        }
+        Name::new(name)
+    }
+
+    // Works on slices of the instruction; label and function names are
+    // interned
+    fn parse_with(instruction: &str, constants: &Constants) -> ParsedVMInstruction {
+        let mut parts = instruction.split(' ');
+        let command = parts.next().unwrap();
+        let mut operand = || {
+            parts
+                .next()
+                .unwrap_or_else(|| panic!("Missing operand: {instruction}"))
+        };
+        match command {
             "add" => ParsedVMInstruction::Add,
             "sub" => ParsedVMInstruction::Sub,
             "neg" => ParsedVMInstruction::Neg,
@@ -65,91 +278,61 @@ mod parser {
             "and" => ParsedVMInstruction::And,
             "or" => ParsedVMInstruction::Or,
             "not" => ParsedVMInstruction::Not,
-            "pop" => match split_instr[1] {
-                "local" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Local,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "argument" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Argument,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "this" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::This,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "that" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::That,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "static" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Static,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "pointer" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Pointer,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "temp" => ParsedVMInstruction::Pop {
-                    segment: MemorySegment::Temp,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                _ => panic!("Invalid pop memory segment: {}", split_instr[1]),
-            },
-            "push" => match split_instr[1] {
-                "local" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Local,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "argument" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Argument,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "this" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::This,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "that" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::That,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "constant" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Constant,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "static" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Static,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "pointer" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Pointer,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                "temp" => ParsedVMInstruction::Push {
-                    segment: MemorySegment::Temp,
-                    idx: split_instr[2].parse::<u16>().unwrap(),
-                },
-                _ => panic!("Invalid push memory segment: {}", split_instr[1]),
-            },
+            "shiftleft" => ParsedVMInstruction::ShiftLeft,
+            "shiftright" => ParsedVMInstruction::ShiftRight,
+            "pop" | "push" => {
+                let segment = match operand() {
+                    "local" => MemorySegment::Local,
+                    "argument" => MemorySegment::Argument,
+                    "this" => MemorySegment::This,
+                    "that" => MemorySegment::That,
+                    "constant" if command == "push" => MemorySegment::Constant,
+                    "static" => MemorySegment::Static,
+                    "pointer" => MemorySegment::Pointer,
+                    "temp" => MemorySegment::Temp,
+                    segment => panic!("Invalid {command} memory segment: {segment}"),
+                };
+                let idx = constants.resolve(operand());
+                if command == "pop" {
+                    ParsedVMInstruction::Pop { segment, idx }
+                } else {
+                    ParsedVMInstruction::Push { segment, idx }
+                }
+            }
             "label" => ParsedVMInstruction::Label {
-                label: split_instr[1].to_owned(),
+                label: symbol("label", operand()),
             },
             "goto" => ParsedVMInstruction::Goto {
-                label: split_instr[1].to_owned(),
+                label: symbol("label", operand()),
             },
             "if-goto" => ParsedVMInstruction::IfGoto {
-                label: split_instr[1].to_owned(),
+                label: symbol("label", operand()),
             },
             "function" => ParsedVMInstruction::Function {
-                name: split_instr[1].to_owned(),
-                num_local_vars: split_instr[2].parse::<u16>().unwrap(),
+                name: symbol("function", operand()),
+                num_local_vars: operand().parse::<Word>().unwrap(),
             },
             "call" => ParsedVMInstruction::Call {
-                name: split_instr[1].to_owned(),
-                num_args: split_instr[2].parse::<u16>().unwrap(),
+                name: symbol("function", operand()),
+                num_args: operand().parse::<Word>().unwrap(),
             },
             "return" => ParsedVMInstruction::Return,
-            _ => panic!("Invalid instruction type: {}", split_instr[0]),
+            // The exit code is optional, 0 by default
+            "halt" => ParsedVMInstruction::Halt {
+                code: parts.next().map_or(0, |code| constants.resolve(code)),
+            },
+            "break" => ParsedVMInstruction::Break,
+            "const" => {
+                let name = operand();
+                if name.starts_with(|c: char| c.is_ascii_digit()) {
+                    panic!("Invalid constant name: {name}");
+                }
+                ParsedVMInstruction::Const {
+                    name: Name::new(name),
+                    value: constants.resolve(operand()),
+                }
+            }
+            _ => panic!("Invalid instruction type: {command}"),
         }
     }
 }
@@ -157,58 +340,302 @@ mod parser {
 mod translator {
     // Given a parsed VM instruction, translates the instruction into its
     // valid Hack assembly code
-    use super::parser::ParsedVMInstruction;
-    use super::MemorySegment;
+    use std::collections::{HashMap, HashSet};
+    use std::fmt::{self, Write};
 
-    const ADD: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=M+D"];
-    const SUBTRACT: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=M-D"];
-    const NEG: &'static [&str] = &["@SP", "A=M-1", "M=-M"];
-    const AND: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=D&M"];
-    const OR: &'static [&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=D|M"];
-    const NOT: &'static [&str] = &["@SP", "A=M-1", "M=!M"];
-    const RETURN: &'static [&str] = &[
+    use super::parser::{Extension, ParsedVMInstruction};
+    use super::{
+        AsmLine, Instrumentation, MemorySegment, TestHarness, TranslateOptions, VMFile,
+        BAD_POINTER, BREAKPOINT, EXIT_CODE, NULL_POINTER, STACK_OVERFLOW, TRACE_LINE,
+    };
+    use crate::cache::{self, Decoder, Encoder};
+    use crate::intern::Name;
+    use crate::mangle::Mangler;
+    use crate::source_map::SourceMap;
+    use crate::symbols::{SymbolKind, SymbolTable};
+    use crate::target::{Target, Word};
+
+    const ADD: &[&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=M+D"];
+    const SUBTRACT: &[&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=M-D"];
+    const NEG: &[&str] = &["@SP", "A=M-1", "M=-M"];
+    const AND: &[&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=D&M"];
+    const OR: &[&str] = &["@SP", "AM=M-1", "D=M", "A=A-1", "M=D|M"];
+    const NOT: &[&str] = &["@SP", "A=M-1", "M=!M"];
+    const SHIFT_LEFT: &[&str] = &["@SP", "A=M-1", "M=M<<"];
+    const SHIFT_RIGHT: &[&str] = &["@SP", "A=M-1", "M=M>>"];
+    const RETURN: &[&str] = &[
         "@LCL", "D=M", "@7", "M=D", "@5", "D=A", "@7", "A=M-D", "D=M", "@8", "M=D", "@SP", "A=M-1",
         "D=M", "@ARG", "A=M", "M=D", "@ARG", "D=M+1", "@SP", "M=D", "@7", "AM=M-1", "D=M", "@THAT",
         "M=D", "@7", "AM=M-1", "D=M", "@THIS", "M=D", "@7", "AM=M-1", "D=M", "@ARG", "M=D", "@7",
         "AM=M-1", "D=M", "@LCL", "M=D", "@8", "A=M", "0;JMP",
     ];
 
-    const TEMP_OFFSET: u16 = 5;
+    // A rough average of the assembly lines generated per VM instruction, for
+    // reserving the output up front
+    const ASM_LINES_PER_INSTRUCTION: usize = 10;
 
     pub struct Translator {
         pub static_base: String,
-        pub asm: Vec<String>,
-        next_instr: u16,
-        call_counter: u16,
-        cur_function: String,
+        pub asm: Vec<AsmLine>,
+        pub source_map: SourceMap,
+        pub symbols: SymbolTable,
+        // Set to replace function-derived symbol names with opaque ones
+        pub mangler: Option<Mangler>,
+        target: Target,
+        next_instr: Word,
+        // Comparisons in the current file so far, for naming their labels
+        comparisons: Word,
+        // Likewise for halts
+        halts: Word,
+        // Statics allocated by the files forked off so far
+        forked_statics: Word,
+        // Statics allocated before, by this translator or the files preceding
+        // its fork, to allocate the next one after them
+        allocated_statics: Word,
+        call_counter: Word,
+        cur_function: Name,
+        // Derive return addresses from the calling function or file rather
+        // than the global call order
+        pub stable_symbols: bool,
+        scope: Name,
+        scope_calls: HashMap<Name, Word>,
+        // Reused for formatting generated lines and symbols
+        buffer: String,
+        pub instrument: Instrumentation,
+        // Extensions whose instructions may be translated
+        pub extensions: Vec<Extension>,
+    }
+
+    // Formats a generated symbol, such as a label, into the scratch buffer and
+    // interns it. Lines of assembly aren't interned, since the interner keeps
+    // its names for the rest of the process.
+    fn format_name(buffer: &mut String, args: fmt::Arguments) -> Name {
+        buffer.clear();
+        buffer.write_fmt(args).unwrap();
+        Name::new(buffer)
     }
 
     impl Translator {
-        pub fn new(static_base: String) -> Self {
+        pub fn new(static_base: String, target: Target) -> Self {
             Self {
-                static_base: static_base,
+                static_base,
                 asm: vec![],
+                source_map: SourceMap::default(),
+                symbols: SymbolTable::default(),
+                mangler: None,
+                target,
                 next_instr: 0,
+                comparisons: 0,
+                halts: 0,
+                forked_statics: 0,
+                allocated_statics: 0,
                 call_counter: 0,
-                cur_function: String::new(),
+                cur_function: Name::new(""),
+                stable_symbols: false,
+                scope: Name::new(""),
+                scope_calls: HashMap::new(),
+                buffer: String::new(),
+                instrument: Instrumentation::default(),
+                extensions: vec![],
             }
         }
 
-        fn add_instr<Instr: Into<String>>(&mut self, instr: Instr) {
+        fn add_instr<Instr: Into<AsmLine>>(&mut self, instr: Instr) {
             let instr = instr.into();
-            if instr.chars().next().unwrap() != '(' {
+            // Labels and comments don't occupy ROM
+            if instr.starts_with('(') || instr.starts_with("//") {
+                self.source_map.record(None);
+            } else {
+                self.source_map.record(Some(self.next_instr));
                 self.next_instr += 1;
             }
             self.asm.push(instr);
         }
 
-        fn const_instr_to_vec(&mut self, const_instr: &'static [&str]) {
+        fn fmt(&mut self, args: fmt::Arguments) -> Name {
+            format_name(&mut self.buffer, args)
+        }
+
+        fn add_fmt(&mut self, args: fmt::Arguments) {
+            self.add_instr(fmt::format(args));
+        }
+
+        fn const_instr_to_vec(&mut self, const_instr: &[&'static str]) {
             for &instr in const_instr {
                 self.add_instr(instr)
             }
         }
 
-        pub fn translate(&mut self, instruction: &ParsedVMInstruction) {
+        // Returns a translator for the file that starts where this one will be
+        // once the files forked before it are appended, so that files can be
+        // translated independently. The counters that carry over from one
+        // file to the next are advanced past the file here.
+        pub fn fork(&mut self, file: &VMFile) -> Translator {
+            let mut part = Translator::new(String::new(), self.target.clone());
+            part.allocated_statics = self.forked_statics;
+            part.reserve(file.instructions.len());
+            part.call_counter = self.call_counter;
+            part.cur_function = self.cur_function;
+            part.stable_symbols = self.stable_symbols;
+            part.instrument = self.instrument.clone();
+            part.extensions = self.extensions.clone();
+            let mut statics = HashSet::new();
+            for instr in &file.instructions {
+                match &instr.instruction {
+                    ParsedVMInstruction::Call { .. } => self.call_counter += 1,
+                    ParsedVMInstruction::Function { name, .. } => self.cur_function = *name,
+                    ParsedVMInstruction::Pop {
+                        segment: MemorySegment::Static,
+                        idx,
+                    }
+                    | ParsedVMInstruction::Push {
+                        segment: MemorySegment::Static,
+                        idx,
+                    } => {
+                        statics.insert(*idx);
+                    }
+                    _ => {}
+                }
+            }
+            let layout = &self.target.layout;
+            let pinned = statics
+                .iter()
+                .filter(|&&idx| layout.pinned_static(&file.static_base, idx).is_some())
+                .count();
+            self.forked_statics += (statics.len() - pinned) as Word;
+            part
+        }
+
+        pub fn reserve(&mut self, vm_instructions: usize) {
+            let lines = vm_instructions * ASM_LINES_PER_INSTRUCTION;
+            self.asm.reserve(lines);
+            self.source_map.reserve(lines);
+        }
+
+        // Appends the code of a forked translator. The code only refers to
+        // labels, so only the addresses recorded about it need to be moved.
+        pub fn append(&mut self, part: Translator) {
+            let offset = self.next_instr;
+            self.asm.extend(part.asm);
+            self.source_map.append(part.source_map, offset);
+            self.symbols.append(part.symbols, offset);
+            self.next_instr += part.next_instr;
+        }
+
+        // Describes everything the translation of the file by this forked
+        // translator depends on
+        pub fn cache_key(&self, file: &VMFile, options: &TranslateOptions) -> String {
+            let mut inputs = format!(
+                "{} {} {:?} {} {:?} {:?}\n{} {} {} {}\n",
+                file.name,
+                file.static_base,
+                self.target,
+                self.allocated_statics,
+                self.instrument,
+                self.extensions,
+                options.annotate,
+                options.keep_comments,
+                self.stable_symbols,
+                self.cur_function
+            );
+            // Return addresses are numbered across files unless they're stable
+            if !self.stable_symbols {
+                writeln!(inputs, "{}", self.call_counter).unwrap();
+            }
+            for instr in &file.instructions {
+                writeln!(inputs, "{} {}", instr.line, instr.instruction).unwrap();
+            }
+            if options.keep_comments {
+                for (line, comment) in file.comments() {
+                    writeln!(inputs, "{line} // {comment}").unwrap();
+                }
+            }
+            cache::key(&inputs)
+        }
+
+        pub fn encode(&self, encoder: &mut Encoder) {
+            encoder.line(self.next_instr);
+            encoder.count(self.asm.len());
+            for line in &self.asm {
+                encoder.line(line);
+            }
+            self.source_map.encode(encoder);
+            self.symbols.encode(encoder);
+        }
+
+        // A translator holding the translation of this forked translator's
+        // file, as encoded by an earlier run
+        pub fn decode(&self, decoder: &mut Decoder) -> Option<Translator> {
+            let mut part = Translator::new(String::new(), self.target.clone());
+            part.next_instr = decoder.parse()?;
+            for _ in 0..decoder.count()? {
+                part.asm.push(AsmLine::from(decoder.line()?.to_owned()));
+            }
+            part.source_map = SourceMap::decode(decoder)?;
+            part.symbols = SymbolTable::decode(decoder)?;
+            Some(part)
+        }
+
+        // Removes the lines generated so far, forgetting everything recorded
+        // about them, when translating a stream of instructions
+        pub fn drain_lines(&mut self) -> std::vec::Drain<'_, AsmLine> {
+            self.source_map.clear_entries();
+            self.symbols.clear_symbols();
+            self.asm.drain(..)
+        }
+
+        pub fn rom_instructions(&self) -> usize {
+            self.next_instr as usize
+        }
+
+        pub fn start_file(&mut self, static_base: &str) {
+            self.static_base = static_base.to_owned();
+            self.scope = Name::new(static_base);
+            self.comparisons = 0;
+            self.halts = 0;
+        }
+
+        fn symbol(&mut self, name: Name) -> Name {
+            match &mut self.mangler {
+                Some(mangler) => mangler.mangle(name),
+                None => name,
+            }
+        }
+
+        // A label local to the current function
+        fn local_symbol(&mut self, label: Name) -> Name {
+            let name = format_name(
+                &mut self.buffer,
+                format_args!("{}${}", self.cur_function, label),
+            );
+            self.symbol(name)
+        }
+
+        pub fn add_comment(&mut self, comment: &str) {
+            self.add_instr(format!("// {comment}").trim_end().to_owned());
+        }
+
+        // Translates the instruction on the given source line. With trace,
+        // the line is recorded where the instruction's code starts running:
+        // after the label of a function or label, which calls and jumps go to,
+        // and not at all for instructions that generate no code.
+        pub fn translate(&mut self, instruction: &ParsedVMInstruction, line: usize) {
+            if let Some(extension) = instruction.extension() {
+                if !self.extensions.contains(&extension) {
+                    panic!(
+                        "{instruction} is not a standard VM instruction; enable it with \
+                         --extensions={}",
+                        extension.name()
+                    );
+                }
+            }
+            match instruction {
+                ParsedVMInstruction::Label { .. }
+                | ParsedVMInstruction::Function { .. }
+                | ParsedVMInstruction::Break
+                | ParsedVMInstruction::Const { .. } => {}
+                _ => self.trace(line),
+            }
             match instruction {
                 ParsedVMInstruction::Add => self.const_instr_to_vec(ADD),
                 ParsedVMInstruction::Sub => self.const_instr_to_vec(SUBTRACT),
@@ -219,11 +646,15 @@ mod translator {
                 ParsedVMInstruction::And => self.const_instr_to_vec(AND),
                 ParsedVMInstruction::Or => self.const_instr_to_vec(OR),
                 ParsedVMInstruction::Not => self.const_instr_to_vec(NOT),
+                ParsedVMInstruction::ShiftLeft => self.const_instr_to_vec(SHIFT_LEFT),
+                ParsedVMInstruction::ShiftRight => self.const_instr_to_vec(SHIFT_RIGHT),
                 ParsedVMInstruction::Pop { segment, idx } => match segment {
                     MemorySegment::Local => self.basic_pop(segment, idx),
                     MemorySegment::Argument => self.basic_pop(segment, idx),
-                    MemorySegment::This => self.basic_pop(segment, idx),
-                    MemorySegment::That => self.basic_pop(segment, idx),
+                    MemorySegment::This | MemorySegment::That => {
+                        self.check_pointer(segment);
+                        self.basic_pop(segment, idx)
+                    }
                     MemorySegment::Constant => panic!("Invalid instruction: pop constant"),
                     MemorySegment::Static => self.pop_static(idx),
                     MemorySegment::Pointer => self.pop_ptr(idx),
@@ -232,23 +663,93 @@ mod translator {
                 ParsedVMInstruction::Push { segment, idx } => match segment {
                     MemorySegment::Local => self.basic_push(segment, idx),
                     MemorySegment::Argument => self.basic_push(segment, idx),
-                    MemorySegment::This => self.basic_push(segment, idx),
-                    MemorySegment::That => self.basic_push(segment, idx),
+                    MemorySegment::This | MemorySegment::That => {
+                        self.check_pointer(segment);
+                        self.basic_push(segment, idx)
+                    }
                     MemorySegment::Constant => self.push_const(idx),
                     MemorySegment::Static => self.push_static(idx),
                     MemorySegment::Pointer => self.push_ptr(idx),
                     MemorySegment::Temp => self.push_temp(idx),
                 },
-                ParsedVMInstruction::Label { label } => self.label_fn(&label),
-                ParsedVMInstruction::Goto { label } => self.goto(&label),
-                ParsedVMInstruction::IfGoto { label } => self.if_goto(&label),
+                ParsedVMInstruction::Label { label } => {
+                    self.label_fn(*label);
+                    self.trace(line);
+                }
+                ParsedVMInstruction::Goto { label } => self.goto(*label),
+                ParsedVMInstruction::IfGoto { label } => self.if_goto(*label),
                 ParsedVMInstruction::Function {
                     name,
                     num_local_vars,
-                } => self.function(&name, *num_local_vars),
-                ParsedVMInstruction::Call { name, num_args } => self.call(&name, *num_args),
+                } => self.function(*name, *num_local_vars, line),
+                ParsedVMInstruction::Call { name, num_args } => self.call(*name, *num_args),
                 ParsedVMInstruction::Return => self.const_instr_to_vec(RETURN),
+                ParsedVMInstruction::Halt { code } => self.halt(*code),
+                ParsedVMInstruction::Break => self.add_instr(BREAKPOINT),
+                ParsedVMInstruction::Const { .. } => {}
+            }
+            if self.instrument.stack_guard_pushes
+                && matches!(instruction, ParsedVMInstruction::Push { .. })
+            {
+                self.check_stack(0);
+            }
+        }
+
+        // Records the source line of the instruction about to run
+        fn trace(&mut self, line: usize) {
+            if !self.instrument.trace {
+                return;
+            }
+            if line > self.target.max_constant() as usize {
+                panic!(
+                    "Line {line} is too large to trace: A-instructions of {}-bit words load at \
+                     most {}",
+                    self.target.word_width,
+                    self.target.max_constant()
+                );
             }
+            self.add_fmt(format_args!("@{line}"));
+            self.add_instr("D=A");
+            self.add_fmt(format_args!("@{TRACE_LINE}"));
+            self.add_instr("M=D");
+        }
+
+        // Halts if the stack has grown into the heap, or would with room more
+        // words on it
+        fn check_stack(&mut self, room: Word) {
+            let limit = self.target.layout.heap_base.saturating_sub(room);
+            self.add_instr("@SP");
+            self.add_instr("D=M");
+            self.add_fmt(format_args!("@{limit}"));
+            self.add_instr("D=D-A");
+            self.add_fmt(format_args!("@{STACK_OVERFLOW}"));
+            self.add_instr("D;JGT");
+        }
+
+        // Halts if THIS or THAT, about to be dereferenced, is null or points
+        // outside the heap and memory-mapped I/O. The screen and keyboard are
+        // accepted on purpose: the OS's Screen and Keyboard reach them through
+        // `that`, as arrays based at their addresses, so a stray write into
+        // them isn't caught.
+        fn check_pointer(&mut self, segment: &MemorySegment) {
+            if !self.instrument.pointer_guard {
+                return;
+            }
+            let layout = &self.target.layout;
+            let (heap_base, size) = (layout.heap_base, layout.keyboard - layout.heap_base);
+            let seg_ptr = segment.seg_ptr();
+            self.add_fmt(format_args!("@{seg_ptr}"));
+            self.add_instr("D=M");
+            self.add_fmt(format_args!("@{NULL_POINTER}"));
+            self.add_instr("D;JEQ");
+            self.add_fmt(format_args!("@{heap_base}"));
+            self.add_instr("D=D-A");
+            self.add_fmt(format_args!("@{BAD_POINTER}"));
+            self.add_instr("D;JLT");
+            self.add_fmt(format_args!("@{size}"));
+            self.add_instr("D=D-A");
+            self.add_fmt(format_args!("@{BAD_POINTER}"));
+            self.add_instr("D;JGT");
         }
 
         fn logical_comp(&mut self, jmp_instr: &str) {
@@ -258,19 +759,55 @@ mod translator {
             self.add_instr("A=A-1");
             self.add_instr("D=M-D");
             self.add_instr("M=-1");
-            // next_instr + 5 is how many instructions until the end of the current asm block
-            self.add_instr(format!("@{}", self.next_instr + 5));
-            self.add_instr(format!("D;{}", jmp_instr));
+            // Jumps past the code setting the result to false. Labels are
+            // numbered per file, so the code doesn't depend on its address.
+            let label = format_name(
+                &mut self.buffer,
+                format_args!("{}$cmp${}", self.static_base, self.comparisons),
+            );
+            self.comparisons += 1;
+            let end = self.symbol(label);
+            self.add_fmt(format_args!("@{end}"));
+            self.add_fmt(format_args!("D;{}", jmp_instr));
             self.add_instr("@SP");
             self.add_instr("A=M-1");
             self.add_instr("M=0");
+            self.symbols
+                .add_code(SymbolKind::Label, end, None, self.next_instr);
+            self.add_fmt(format_args!("({end})"));
         }
 
-        fn basic_pop(&mut self, segment: &MemorySegment, idx: &u16) {
+        // Leaves the exit code where the halt routines of failed checks leave
+        // theirs, and loops in place, as the emulator expects of a halt
+        fn halt(&mut self, code: Word) {
+            if code > self.target.max_constant() {
+                panic!(
+                    "Exit code {code} is out of range for {}-bit words",
+                    self.target.word_width
+                );
+            }
+            self.add_fmt(format_args!("@{code}"));
+            self.add_instr("D=A");
+            self.add_fmt(format_args!("@{EXIT_CODE}"));
+            self.add_instr("M=D");
+            let label = format_name(
+                &mut self.buffer,
+                format_args!("{}$halt${}", self.static_base, self.halts),
+            );
+            self.halts += 1;
+            let halt = self.symbol(label);
+            self.symbols
+                .add_code(SymbolKind::Label, halt, None, self.next_instr);
+            self.add_fmt(format_args!("({halt})"));
+            self.add_fmt(format_args!("@{halt}"));
+            self.add_instr("0;JMP");
+        }
+
+        fn basic_pop(&mut self, segment: &MemorySegment, idx: &Word) {
             let seg_ptr = segment.seg_ptr();
-            self.add_instr(format!("@{idx}"));
+            self.add_fmt(format_args!("@{idx}"));
             self.add_instr("D=A");
-            self.add_instr(format!("@{seg_ptr}"));
+            self.add_fmt(format_args!("@{seg_ptr}"));
             self.add_instr("D=D+M");
             self.add_instr("@SP");
             self.add_instr("AM=M-1");
@@ -279,16 +816,16 @@ mod translator {
             self.add_instr("M=D-A");
         }
 
-        fn pop_temp(&mut self, idx: &u16) {
-            let mem_addr = TEMP_OFFSET + idx;
+        fn pop_temp(&mut self, idx: &Word) {
+            let mem_addr = self.target.layout.temp_base + idx;
             self.add_instr("@SP");
             self.add_instr("AM=M-1");
             self.add_instr("D=M");
-            self.add_instr(format!("@{mem_addr}"));
+            self.add_fmt(format_args!("@{mem_addr}"));
             self.add_instr("M=D");
         }
 
-        fn pop_ptr(&mut self, idx: &u16) {
+        fn pop_ptr(&mut self, idx: &Word) {
             let seg_ptr = match idx {
                 0 => MemorySegment::This.seg_ptr(),
                 1 => MemorySegment::That.seg_ptr(),
@@ -297,20 +834,51 @@ mod translator {
             self.add_instr("@SP");
             self.add_instr("AM=M-1");
             self.add_instr("D=M");
-            self.add_instr(format!("@{seg_ptr}"));
+            self.add_fmt(format_args!("@{seg_ptr}"));
             self.add_instr("M=D");
         }
 
-        fn pop_static(&mut self, idx: &u16) {
+        fn static_operand(&mut self, idx: &Word) -> AsmLine {
+            let name = format_name(
+                &mut self.buffer,
+                format_args!("{}.{}", self.static_base, idx),
+            );
+            let symbol = self.symbol(name);
+            let layout = &self.target.layout;
+            let pinned = layout.pinned_static(&self.static_base, *idx);
+            let allocated = &mut self.allocated_statics;
+            let address = self.symbols.add_static(symbol, || {
+                pinned.or_else(|| {
+                    *allocated += 1;
+                    layout.static_address(*allocated - 1)
+                })
+            });
+            match address {
+                Some(address) if pinned.is_none() && address >= layout.stack_base => {
+                    panic!("Static variable {name} doesn't fit below the stack")
+                }
+                Some(address) => AsmLine::from(address.to_string()),
+                None => AsmLine::from(symbol.as_str()),
+            }
+        }
+
+        fn pop_static(&mut self, idx: &Word) {
             self.add_instr("@SP");
             self.add_instr("AM=M-1");
             self.add_instr("D=M");
-            self.add_instr(format!("@{}.{}", self.static_base, idx));
+            let operand = self.static_operand(idx);
+            self.add_fmt(format_args!("@{operand}"));
             self.add_instr("M=D");
         }
 
-        fn push_const(&mut self, idx: &u16) {
-            self.add_instr(format!("@{idx}"));
+        fn push_const(&mut self, idx: &Word) {
+            if *idx > self.target.max_constant() {
+                panic!(
+                    "Constant {idx} is out of range for {}-bit words",
+                    self.target.word_width
+                );
+            }
+            self.add_fmt(format_args!("@{idx}"));
             self.add_instr("D=A");
             self.add_instr("@SP");
             self.add_instr("M=M+1");
@@ -318,11 +886,11 @@ mod translator {
             self.add_instr("M=D");
         }
 
-        fn basic_push(&mut self, segment: &MemorySegment, idx: &u16) {
+        fn basic_push(&mut self, segment: &MemorySegment, idx: &Word) {
             let seg_ptr = segment.seg_ptr();
-            self.add_instr(format!("@{idx}"));
+            self.add_fmt(format_args!("@{idx}"));
             self.add_instr("D=A");
-            self.add_instr(format!("@{seg_ptr}"));
+            self.add_fmt(format_args!("@{seg_ptr}"));
             self.add_instr("A=D+M");
             self.add_instr("D=M");
             self.add_instr("@SP");
@@ -331,9 +899,9 @@ mod translator {
             self.add_instr("M=D");
         }
 
-        fn push_temp(&mut self, idx: &u16) {
-            let mem_addr = TEMP_OFFSET + idx;
-            self.add_instr(format!("@{mem_addr}"));
+        fn push_temp(&mut self, idx: &Word) {
+            let mem_addr = self.target.layout.temp_base + idx;
+            self.add_fmt(format_args!("@{mem_addr}"));
             self.add_instr("D=M");
             self.add_instr("@SP");
             self.add_instr("M=M+1");
@@ -341,13 +909,13 @@ mod translator {
             self.add_instr("M=D");
         }
 
-        fn push_ptr(&mut self, idx: &u16) {
+        fn push_ptr(&mut self, idx: &Word) {
             let seg_ptr = match idx {
                 0 => MemorySegment::This.seg_ptr(),
                 1 => MemorySegment::That.seg_ptr(),
                 _ => panic!("push pointer instruction must have index 0 or 1"),
             };
-            self.add_instr(format!("@{seg_ptr}"));
+            self.add_fmt(format_args!("@{seg_ptr}"));
             self.add_instr("D=M");
             self.add_instr("@SP");
             self.add_instr("M=M+1");
@@ -355,8 +923,9 @@ mod translator {
             self.add_instr("M=D");
         }
 
-        fn push_static(&mut self, idx: &u16) {
-            self.add_instr(format!("@{}.{}", self.static_base, idx));
+        fn push_static(&mut self, idx: &Word) {
+            let operand = self.static_operand(idx);
+            self.add_fmt(format_args!("@{operand}"));
             self.add_instr("D=M");
             self.add_instr("@SP");
             self.add_instr("M=M+1");
@@ -364,26 +933,41 @@ mod translator {
             self.add_instr("M=D");
         }
 
-        fn label_fn(&mut self, label: &str) {
-            self.add_instr(format!("({}${})", self.cur_function, label));
+        fn label_fn(&mut self, label: Name) {
+            let symbol = self.local_symbol(label);
+            self.symbols
+                .add_code(SymbolKind::Label, symbol, Some(label), self.next_instr);
+            self.add_fmt(format_args!("({symbol})"));
         }
 
-        fn goto(&mut self, label: &str) {
-            self.add_instr(format!("@{}${}", self.cur_function, label));
+        fn goto(&mut self, label: Name) {
+            let symbol = self.local_symbol(label);
+            self.add_fmt(format_args!("@{symbol}"));
             self.add_instr("0;JMP");
         }
 
-        fn if_goto(&mut self, label: &str) {
+        fn if_goto(&mut self, label: Name) {
             self.add_instr("@SP");
             self.add_instr("AM=M-1");
             self.add_instr("D=M");
-            self.add_instr(format!("@{}${}", self.cur_function, label));
+            let symbol = self.local_symbol(label);
+            self.add_fmt(format_args!("@{symbol}"));
             self.add_instr("D;JNE");
         }
 
-        fn function(&mut self, name: &str, num_local_vars: u16) {
-            self.cur_function = name.to_owned();
-            self.add_instr(format!("({name})"));
+        fn function(&mut self, name: Name, num_local_vars: Word, line: usize) {
+            self.cur_function = name;
+            self.scope = name;
+            let symbol = self.symbol(name);
+            self.symbols
+                .add_code(SymbolKind::Function, symbol, None, self.next_instr);
+            self.add_fmt(format_args!("({symbol})"));
+            self.trace(line);
+            // Checked before the locals are written, so an overflow doesn't
+            // clobber the heap first
+            if self.instrument.stack_guard {
+                self.check_stack(num_local_vars);
+            }
             for _ in 0..num_local_vars {
                 self.add_instr("@SP");
                 self.add_instr("M=M+1");
@@ -392,10 +976,11 @@ mod translator {
             }
         }
 
-        fn call(&mut self, name: &str, num_args: u16) {
-            let return_addr_label = format!("{}$ret.{}", name, self.call_counter);
+        fn call(&mut self, name: Name, num_args: Word) {
+            let return_addr_label = self.return_label(name);
+            let return_addr_label = self.symbol(return_addr_label);
             let arg_offset = 5 + num_args;
-            self.add_instr(format!("@{return_addr_label}"));
+            self.add_fmt(format_args!("@{return_addr_label}"));
             self.add_instr("D=A");
             self.add_instr("@SP");
             self.add_instr("M=M+1");
@@ -425,7 +1010,7 @@ mod translator {
             self.add_instr("M=M+1");
             self.add_instr("A=M-1");
             self.add_instr("M=D");
-            self.add_instr(format!("@{arg_offset}"));
+            self.add_fmt(format_args!("@{arg_offset}"));
             self.add_instr("D=A");
             self.add_instr("@SP");
             self.add_instr("D=M-D");
@@ -435,37 +1020,197 @@ mod translator {
             self.add_instr("D=M");
             self.add_instr("@LCL");
             self.add_instr("M=D");
-            self.add_instr(format!("@{name}"));
+            let function = self.symbol(name);
+            self.add_fmt(format_args!("@{function}"));
             self.add_instr("0;JMP");
-            self.add_instr(format!("({return_addr_label})"));
-            self.call_counter += 1;
+            self.symbols.add_code(
+                SymbolKind::ReturnAddress,
+                return_addr_label,
+                None,
+                self.next_instr,
+            );
+            self.add_fmt(format_args!("({return_addr_label})"));
+        }
+
+        fn return_label(&mut self, name: Name) -> Name {
+            if self.stable_symbols {
+                // Numbered per calling function (or file, for code outside
+                // functions), so they don't depend on any other file
+                let scope = if self.scope.is_empty() {
+                    Name::new("bootstrap")
+                } else {
+                    self.scope
+                };
+                let counter = self.scope_calls.entry(scope).or_default();
+                let index = *counter;
+                *counter += 1;
+                self.fmt(format_args!("{scope}$ret.{index}"))
+            } else {
+                let index = self.call_counter;
+                self.call_counter += 1;
+                self.fmt(format_args!("{name}$ret.{index}"))
+            }
+        }
+
+        pub fn set_test_harness(&mut self, harness: &TestHarness) {
+            for (register, value) in harness.registers() {
+                self.add_fmt(format_args!("@{value}"));
+                self.add_instr("D=A");
+                self.add_fmt(format_args!("@{register}"));
+                self.add_instr("M=D");
+            }
         }
 
         pub fn set_bootstrap(&mut self) {
-            self.add_instr("@256");
+            let stack_base = self.target.layout.stack_base;
+            self.add_fmt(format_args!("@{stack_base}"));
             self.add_instr("D=A");
             self.add_instr("@SP");
             self.add_instr("M=D");
-            self.call("Sys.init", 0);
+            self.call(Name::new("Sys.init"), 0);
+        }
+
+        // The code failed checks jump to, which halts with the failure's code
+        // in R15
+        pub fn set_halt_routines(&mut self) {
+            let failures = self.instrument.failures();
+            if failures.is_empty() {
+                return;
+            }
+            for (label, code) in failures {
+                self.symbols
+                    .add_code(SymbolKind::Label, Name::new(label), None, self.next_instr);
+                self.add_fmt(format_args!("({label})"));
+                self.add_fmt(format_args!("@{code}"));
+                self.add_instr("D=A");
+                self.add_instr("@VM_HALT");
+                self.add_instr("0;JMP");
+            }
+            self.symbols.add_code(
+                SymbolKind::Label,
+                Name::new("VM_HALT"),
+                None,
+                self.next_instr,
+            );
+            self.add_instr("(VM_HALT)");
+            self.add_fmt(format_args!("@{EXIT_CODE}"));
+            self.add_instr("M=D");
+            self.add_instr("(VM_HALTED)");
+            self.add_instr("@VM_HALTED");
+            self.add_instr("0;JMP");
+        }
+
+        pub fn set_end_loop(&mut self) {
+            self.symbols
+                .add_code(SymbolKind::Label, Name::new("END"), None, self.next_instr);
+            self.add_instr("(END)");
+            self.add_instr("@END");
+            self.add_instr("0;JMP");
         }
     }
 }
 
-fn read_lines(infile: &Path) -> Vec<String> {
-    // Reads the lines of the infile, while ignoring comments and whitespace.
-    read_to_string(infile)
-        .unwrap()
-        .lines()
-        .filter_map(|line| strip_comment_and_whitespace(line))
-        .collect()
+// Labels of the code halting on failed checks
+const STACK_OVERFLOW: &str = "VM_STACK_OVERFLOW";
+const NULL_POINTER: &str = "VM_NULL_POINTER";
+const BAD_POINTER: &str = "VM_BAD_POINTER";
+
+// Register holding the source line traced last
+const TRACE_LINE: &str = "R14";
+
+// Register holding the exit code of a halted program
+const EXIT_CODE: &str = "R15";
+
+// The line `break` generates: a comment, so nothing to the hardware, but a
+// breakpoint to the built-in emulator
+pub const BREAKPOINT: &str = "// @break";
+
+// Runtime checks added to the generated code. A failed check halts the program
+// with the code of the failure in R15.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Instrumentation {
+    // At function entry, check that the stack hasn't grown into the heap
+    // (code 1)
+    pub stack_guard: bool,
+    // Check the stack after every push as well
+    pub stack_guard_pushes: bool,
+    // Before every this/that access, check that the segment's pointer isn't
+    // null (code 2) and points into the heap, screen or keyboard (code 3)
+    pub pointer_guard: bool,
+    // Before every VM instruction, store its source line in R14, so a halted
+    // or misbehaving program shows where it got to
+    pub trace: bool,
 }
 
-fn strip_comment_and_whitespace(line: &str) -> Option<String> {
-    let line = line.split("//").next().unwrap().trim();
-    if line.is_empty() {
-        return None;
-    } else {
-        return Some(line.to_owned());
+impl Instrumentation {
+    // Parses a comma-separated list of checks
+    pub fn set_all(&mut self, checks: &str) {
+        for check in checks.split(',') {
+            match check {
+                "stack-guard" => self.stack_guard = true,
+                "stack-guard-pushes" => {
+                    self.stack_guard = true;
+                    self.stack_guard_pushes = true;
+                }
+                "pointer-guard" => self.pointer_guard = true,
+                "trace" => self.trace = true,
+                _ => panic!("Invalid instrumentation: {check}"),
+            }
+        }
+    }
+
+    // The labels enabled checks jump to on failure, with the codes the
+    // program halts with
+    pub fn failures(&self) -> Vec<(&'static str, Word)> {
+        let mut failures = vec![];
+        if self.stack_guard {
+            failures.push((STACK_OVERFLOW, 1));
+        }
+        if self.pointer_guard {
+            failures.push((NULL_POINTER, 2));
+            failures.push((BAD_POINTER, 3));
+        }
+        failures
+    }
+}
+
+// A line of generated assembly. Most lines are fixed instructions like `@SP`,
+// which are borrowed rather than allocated for every use.
+pub type AsmLine = Cow<'static, str>;
+
+pub struct Translation {
+    pub asm: Vec<AsmLine>,
+    // As counted by the translator while generating the assembly
+    pub rom_instructions: usize,
+    pub source_map: SourceMap,
+    pub symbols: SymbolTable,
+    pub mangler: Option<Mangler>,
+}
+
+impl From<Translator> for Translation {
+    fn from(translator: Translator) -> Self {
+        Self {
+            rom_instructions: translator.rom_instructions(),
+            asm: translator.asm,
+            source_map: translator.source_map,
+            symbols: translator.symbols,
+            mangler: translator.mangler,
+        }
+    }
+}
+
+fn non_empty(text: &str) -> Option<&str> {
+    Some(text.trim()).filter(|text| !text.is_empty())
+}
+
+// Splits a line into its code and the text of its `//` comment, either of
+// which may be missing. The line is scanned once, by memchr.
+fn split_comment(line: &str) -> (Option<&str>, Option<&str>) {
+    let bytes = line.as_bytes();
+    let start = memchr_iter(b'/', bytes).find(|&i| bytes.get(i + 1) == Some(&b'/'));
+    match start {
+        Some(start) => (non_empty(&line[..start]), non_empty(&line[start + 2..])),
+        None => (non_empty(line), None),
     }
 }
 
@@ -474,45 +1219,583 @@ fn get_static_base(file: &Path) -> String {
     static_base.to_owned()
 }
 
-pub fn translate_file(infile: &Path) -> Vec<String> {
-    let static_base = get_static_base(infile);
-    let mut translator = Translator::new(static_base);
-    let lines = read_lines(infile);
+#[derive(Debug, Clone, Copy)]
+pub struct SourceInstruction {
+    pub line: usize,
+    pub instruction: ParsedVMInstruction,
+}
+
+// A translation unit. Everything parsed from the file is held in a few
+// buffers, released together when the file is dropped.
+#[derive(Clone, Default)]
+pub struct VMFile {
+    pub name: String,
+    pub static_base: String,
+    pub instructions: Vec<SourceInstruction>,
+    // User-written comments with their line numbers, as ranges of
+    // comment_text, which holds all of them
+    pub comment_spans: Vec<(usize, Range<usize>)>,
+    pub comment_text: String,
+    // The constants declared so far, while the file is parsed
+    pub constants: parser::Constants,
+}
+
+impl VMFile {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            static_base: get_static_base(Path::new(name)),
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self, encoder: &mut Encoder) {
+        encoder.line(&self.name);
+        encoder.count(self.instructions.len());
+        for instr in &self.instructions {
+            encoder.line(format!("{} {}", instr.line, instr.instruction));
+        }
+        encoder.count(self.comment_spans.len());
+        for (line, comment) in self.comments() {
+            encoder.line(format!("{line} {comment}"));
+        }
+    }
+
+    // Instructions are stored in their canonical form, which parses quickly
+    pub fn decode(decoder: &mut Decoder) -> Option<Self> {
+        let mut file = VMFile::new(decoder.line()?);
+        for _ in 0..decoder.count()? {
+            let (line, text) = decoder.line()?.split_once(' ')?;
+            file.instructions.push(SourceInstruction {
+                line: line.parse().ok()?,
+                instruction: parser::parse_instruction(text),
+            });
+        }
+        for _ in 0..decoder.count()? {
+            let (line, comment) = decoder.line()?.split_once(' ')?;
+            let start = file.comment_text.len();
+            file.comment_text.push_str(comment);
+            file.comment_spans
+                .push((line.parse().ok()?, start..file.comment_text.len()));
+        }
+        Some(file)
+    }
+
+    pub fn comments(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.comment_spans
+            .iter()
+            .map(|(line, span)| (*line, &self.comment_text[span.clone()]))
+    }
+
+    // Adds a line of source, ignoring whitespace and keeping any comment
+    // aside. line_num is the 1-based line number in the file.
+    fn add_line(&mut self, line_num: usize, line: &str) {
+        let (code, comment) = split_comment(line);
+        if let Some(text) = code {
+            self.instructions.push(SourceInstruction {
+                line: line_num,
+                instruction: self.constants.parse(text),
+            });
+        }
+        if let Some(comment) = comment {
+            let start = self.comment_text.len();
+            self.comment_text.push_str(comment);
+            self.comment_spans
+                .push((line_num, start..self.comment_text.len()));
+        }
+    }
+}
+
+// A bundle is several VM files concatenated into one, each preceded by a
+// `// FILE: Name.vm` marker line
+pub const BUNDLE_MARKER: &str = "// FILE:";
+
+pub fn bundle_marker(line: &str) -> Option<&str> {
+    line.trim().strip_prefix(BUNDLE_MARKER).map(str::trim)
+}
+
+// Parses the source a line at a time, so that huge files are never held in
+// memory whole. If bundles are allowed and the source has file markers, it is
+// split into the files it holds, with line numbers relative to each section
+// so that they match the original files.
+fn parse_lines<R: BufRead>(name: &str, mut reader: R, allow_bundle: bool) -> (Vec<VMFile>, bool) {
+    let mut files = vec![VMFile::new(name)];
+    let mut is_bundle = false;
+    let mut line_num = 0;
+    // Every line is read into the same buffer
+    let mut buffer = String::new();
+    loop {
+        buffer.clear();
+        let read = reader
+            .read_line(&mut buffer)
+            .unwrap_or_else(|_| panic!("Failed to read {name}"));
+        if read == 0 {
+            break;
+        }
+        let line = buffer.trim_end_matches(['\n', '\r']);
+        if let Some(section) = bundle_marker(line).filter(|_| allow_bundle) {
+            if !is_bundle {
+                if let Some(instr) = files[0].instructions.first() {
+                    panic!(
+                        "Bundle line {}: instruction before the first {} marker",
+                        instr.line, BUNDLE_MARKER
+                    );
+                }
+                files.clear();
+                is_bundle = true;
+            }
+            // Sections are translated as files, and two files of the same
+            // name would share their statics and labels
+            if files.iter().any(|file| file.name == section) {
+                panic!("Bundle has two sections named {section}");
+            }
+            files.push(VMFile::new(section));
+            line_num = 0;
+            continue;
+        }
+        line_num += 1;
+        files.last_mut().unwrap().add_line(line_num, line);
+    }
+    (files, is_bundle)
+}
+
+fn open(infile: &Path) -> BufReader<File> {
+    let file = File::open(infile)
+        .unwrap_or_else(|_| panic!("Failed to open {}", infile.to_str().unwrap()));
+    BufReader::new(file)
+}
+
+pub fn parse_file(infile: &Path) -> VMFile {
+    let name = infile.file_name().unwrap().to_str().unwrap();
+    let (mut files, _) = parse_lines(name, open(infile), false);
+    files.pop().unwrap()
+}
+
+// Loads the file's parsed program from the cache if the file hasn't been
+// modified since it was stored
+fn parse_file_cached(infile: &Path, dir: &Path) -> VMFile {
+    let Some(key) = cache::source_key(infile) else {
+        return parse_file(infile);
+    };
+    if let Some(file) = cache::load(dir, &key, VMFile::decode) {
+        return file;
+    }
+    let file = parse_file(infile);
+    cache::store(dir, &key, |encoder| file.encode(encoder));
+    file
+}
+
+// Parses a file's source held in memory
+pub fn parse_source(name: &str, source: &str) -> VMFile {
+    let (mut files, _) = parse_lines(name, source.as_bytes(), false);
+    files.pop().unwrap()
+}
+
+// Parses an infile given on its own, which may be a bundle. Returns the files
+// it holds and whether it was a bundle.
+pub fn parse_input(infile: &Path) -> (Vec<VMFile>, bool) {
+    let name = infile.file_name().unwrap().to_str().unwrap();
+    parse_lines(name, open(infile), true)
+}
+
+// Writes lines separated by newlines, continuing the output written so far
+fn write_lines<W: Write>(
+    writer: &mut W,
+    lines: impl Iterator<Item = AsmLine>,
+    dialect: &Dialect,
+    first: &mut bool,
+) -> io::Result<()> {
     for line in lines {
-        let instruction = parser::parse_instruction(&line);
-        translator.translate(&instruction);
+        if !*first {
+            writer.write_all(b"\n")?;
+        }
+        *first = false;
+        writer.write_all(dialect.apply(&line).as_bytes())?;
     }
-    translator.asm
+    Ok(())
+}
+
+// Translates a single file as it is read, writing the code of each
+// instruction as soon as it is generated, so that memory use doesn't grow
+// with the size of the file, in the given assembly dialect. Returns the number
+// of ROM instructions written.
+pub fn translate_stream<W: Write>(
+    infile: &Path,
+    writer: &mut W,
+    options: &TranslateOptions,
+    dialect: &Dialect,
+) -> io::Result<usize> {
+    let name = infile.file_name().unwrap().to_str().unwrap();
+    translate_reader(name, open(infile), writer, options, dialect)
 }
 
-pub fn translate_directory(directory: &Path) -> Vec<String> {
+fn translate_reader<R: BufRead, W: Write>(
+    name: &str,
+    mut reader: R,
+    writer: &mut W,
+    options: &TranslateOptions,
+    dialect: &Dialect,
+) -> io::Result<usize> {
+    let mut translator = start_translation(options);
+    translator.start_file(&VMFile::new(name).static_base);
+    let mut first = true;
+    let mut constants = parser::Constants::default();
+    let mut line_num = 0;
+    let mut buffer = String::new();
+    loop {
+        write_lines(writer, translator.drain_lines(), dialect, &mut first)?;
+        buffer.clear();
+        let read = reader
+            .read_line(&mut buffer)
+            .unwrap_or_else(|_| panic!("Failed to read {name}"));
+        if read == 0 {
+            break;
+        }
+        line_num += 1;
+        let line = buffer.trim_end_matches(['\n', '\r']);
+        if bundle_marker(line).is_some() {
+            panic!("Line {line_num}: bundles can't be translated as a stream");
+        }
+        let (code, comment) = split_comment(line);
+        if options.keep_comments {
+            if let Some(comment) = comment {
+                translator.add_comment(comment);
+            }
+        }
+        if let Some(text) = code {
+            let instruction = constants.parse(text);
+            if options.annotate {
+                translator.add_comment(&format!("@vm {name}:{line_num} {instruction}"));
+            }
+            translator.translate(&instruction, line_num);
+        }
+    }
+    if options.end_loop {
+        translator.set_end_loop();
+    }
+    translator.set_halt_routines();
+    write_lines(writer, translator.drain_lines(), dialect, &mut first)?;
+    Ok(translator.rom_instructions())
+}
+
+// The directory's .vm files, sorted by name, so that the output doesn't depend
+// on the order the filesystem lists them in
+pub fn list_directory(directory: &Path) -> Vec<PathBuf> {
     let mut vm_files = vec![];
-    for entry in directory.read_dir().unwrap() {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-            if path.extension().unwrap() == "vm" {
-                vm_files.push(path);
+    for entry in directory.read_dir().unwrap().flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "vm") {
+            vm_files.push(path);
+        }
+    }
+    vm_files.sort();
+    vm_files
+}
+
+// Initial values of the segment pointers, as set by the preamble of the
+// course's tests of single functions
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestHarness {
+    pub sp: Word,
+    pub lcl: Word,
+    pub arg: Word,
+    pub this: Word,
+    pub that: Word,
+}
+
+impl Default for TestHarness {
+    fn default() -> Self {
+        Self {
+            sp: 256,
+            lcl: 300,
+            arg: 400,
+            this: 3000,
+            that: 3010,
+        }
+    }
+}
+
+impl TestHarness {
+    // Parses a comma-separated list of register=value settings
+    pub fn set_all(&mut self, settings: &str) {
+        for setting in settings.split(',') {
+            let (register, value) = setting
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid test harness setting: {setting}"));
+            let value = value
+                .parse::<Word>()
+                .unwrap_or_else(|_| panic!("Invalid value for {register}: {value}"));
+            match register {
+                "SP" => self.sp = value,
+                "LCL" => self.lcl = value,
+                "ARG" => self.arg = value,
+                "THIS" => self.this = value,
+                "THAT" => self.that = value,
+                _ => panic!("Invalid test harness register: {register}"),
             }
         }
     }
-    let mut translator = Translator::new(String::from(""));
-    translator.set_bootstrap();
-    for file in vm_files {
-        let static_base = get_static_base(&file);
-        translator.static_base = static_base;
-        let lines = read_lines(&file);
-        for line in lines {
-            let instruction = parser::parse_instruction(&line);
-            translator.translate(&instruction);
+
+    pub fn registers(&self) -> [(&str, Word); 5] {
+        [
+            ("SP", self.sp),
+            ("LCL", self.lcl),
+            ("ARG", self.arg),
+            ("THIS", self.this),
+            ("THAT", self.that),
+        ]
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct TranslateOptions {
+    // Set the segment pointers before any other code
+    pub test_harness: Option<TestHarness>,
+    // Lines of text to put at the top of the assembly as comments
+    pub header: Vec<String>,
+    // Initialize the stack pointer and call Sys.init before any other code
+    pub bootstrap: bool,
+    // Halt in an infinite loop after the last instruction
+    pub end_loop: bool,
+    // Precede the code of every VM instruction with an `// @vm` comment
+    pub annotate: bool,
+    // Carry the comments of the VM source into the assembly
+    pub keep_comments: bool,
+    // Replace function, label and static names with opaque symbols
+    pub mangle: bool,
+    // Number return addresses per calling function, so they don't change
+    // when other files are added or removed
+    pub stable_symbols: bool,
+    pub target: Target,
+    pub instrument: Instrumentation,
+    // Extensions whose instructions are translated rather than rejected
+    pub extensions: Vec<Extension>,
+    // Reuse per-file translations stored in this directory by earlier runs.
+    // Not used when mangling, since mangled names depend on every file.
+    pub cache: Option<PathBuf>,
+}
+
+fn translate_file(translator: &mut Translator, file: &VMFile, options: &TranslateOptions) {
+    translator.start_file(&file.static_base);
+    translator.source_map.set_source(&file.name);
+    let mut comments = file.comments().peekable();
+    for instr in &file.instructions {
+        translator.source_map.set_line(Some(instr.line));
+        if let ParsedVMInstruction::Function { name, .. } = &instr.instruction {
+            // Everything generated for a declaration belongs to the new function
+            translator.source_map.set_function(*name);
+        }
+        if options.keep_comments {
+            // Comments up to and including the instruction's own line
+            // precede its code
+            while let Some((line, comment)) = comments.next_if(|(line, _)| *line <= instr.line) {
+                translator.source_map.set_line(Some(line));
+                translator.add_comment(comment);
+            }
+            translator.source_map.set_line(Some(instr.line));
+        }
+        if options.annotate {
+            translator.add_comment(&format!(
+                "@vm {}:{} {}",
+                file.name, instr.line, instr.instruction
+            ));
+        }
+        translator.translate(&instr.instruction, instr.line);
+    }
+    if options.keep_comments {
+        for (line, comment) in comments {
+            translator.source_map.set_line(Some(line));
+            translator.add_comment(comment);
+        }
+    }
+}
+
+// Reuses the file's translation from the cache if nothing it depends on has
+// changed since it was stored
+fn translate_cached(
+    dir: &Path,
+    file: &VMFile,
+    mut part: Translator,
+    options: &TranslateOptions,
+) -> Translator {
+    let key = part.cache_key(file, options);
+    if let Some(cached) = cache::load(dir, &key, |decoder| part.decode(decoder)) {
+        return cached;
+    }
+    translate_file(&mut part, file, options);
+    cache::store(dir, &key, |encoder| part.encode(encoder));
+    part
+}
+
+// A translator that has generated the code preceding the files
+fn start_translation(options: &TranslateOptions) -> Translator {
+    let mut translator = Translator::new(String::from(""), options.target.clone());
+    if options.mangle {
+        translator.mangler = Some(Mangler::default());
+    }
+    translator.stable_symbols = options.stable_symbols;
+    translator.instrument = options.instrument.clone();
+    translator.extensions = options.extensions.clone();
+    for line in &options.header {
+        translator.add_comment(line);
+    }
+    if let Some(harness) = &options.test_harness {
+        translator.set_test_harness(harness);
+    }
+    if options.bootstrap {
+        translator.set_bootstrap();
+    }
+    translator
+}
+
+fn finish_translation(mut translator: Translator, options: &TranslateOptions) -> Translation {
+    translator.source_map.clear_source();
+    if options.end_loop {
+        translator.set_end_loop();
+    }
+    translator.set_halt_routines();
+    translator.into()
+}
+
+fn translate_part(file: &VMFile, mut part: Translator, options: &TranslateOptions) -> Translator {
+    match &options.cache {
+        Some(dir) => translate_cached(dir, file, part, options),
+        None => {
+            translate_file(&mut part, file, options);
+            part
+        }
+    }
+}
+
+// Files waiting to be translated while the next ones are read
+const PIPELINE_DEPTH: usize = 4;
+
+// Reads and translates the files at once: a reader thread parses them in turn
+// and hands them over through a bounded channel, and each file is translated
+// as soon as it arrives, while the next ones are read. Returns the parsed files
+// along with their translation, which is the same as that of translate().
+pub fn translate_paths(
+    paths: &[PathBuf],
+    options: &TranslateOptions,
+) -> (Vec<VMFile>, Translation) {
+    let mut translator = start_translation(options);
+    let (sender, receiver) = sync_channel(PIPELINE_DEPTH);
+    let files = thread::scope(|scope| {
+        scope.spawn(move || {
+            for path in paths {
+                // The receiver only hangs up if translation failed
+                let file = match &options.cache {
+                    Some(dir) => parse_file_cached(path, dir),
+                    None => parse_file(path),
+                };
+                if sender.send(file).is_err() {
+                    break;
+                }
+            }
+        });
+        if translator.mangler.is_some() {
+            // See translate()
+            let mut files = vec![];
+            for file in receiver {
+                let mut part = translator.fork(&file);
+                part.mangler = translator.mangler.take();
+                translate_file(&mut part, &file, options);
+                translator.mangler = part.mangler.take();
+                translator.append(part);
+                files.push(file);
+            }
+            return files;
+        }
+        // Files are forked in order, as the channel yields them, but
+        // translated in any order
+        let mut parts: Vec<(usize, VMFile, Translator)> = receiver
+            .into_iter()
+            .enumerate()
+            .map(|(i, file)| {
+                let part = translator.fork(&file);
+                (i, file, part)
+            })
+            .par_bridge()
+            .map(|(i, file, part)| {
+                let part = translate_part(&file, part, options);
+                (i, file, part)
+            })
+            .collect();
+        parts.sort_by_key(|(i, _, _)| *i);
+        translator.reserve(
+            parts
+                .iter()
+                .map(|(_, file, _)| file.instructions.len())
+                .sum(),
+        );
+        parts
+            .into_iter()
+            .map(|(_, file, part)| {
+                translator.append(part);
+                file
+            })
+            .collect()
+    });
+    (files, finish_translation(translator, options))
+}
+
+// Rejects instructions of extensions that aren't enabled. The translator
+// checks each instruction too, but the IR, backends and analyses see the
+// parsed program before it does.
+pub fn check_extensions(files: &[VMFile], extensions: &[Extension]) {
+    for file in files {
+        for instr in &file.instructions {
+            let extension = instr.instruction.extension();
+            if let Some(extension) = extension.filter(|extension| !extensions.contains(extension)) {
+                panic!(
+                    "{}:{}: {} is not a standard VM instruction; enable it with --extensions={}",
+                    file.name,
+                    instr.line,
+                    instr.instruction,
+                    extension.name()
+                );
+            }
         }
     }
-    translator.asm
+}
+
+pub fn translate(files: &[VMFile], options: &TranslateOptions) -> Translation {
+    let mut translator = start_translation(options);
+    translator.reserve(files.iter().map(|file| file.instructions.len()).sum());
+    let parts: Vec<Translator> = files.iter().map(|file| translator.fork(file)).collect();
+    if translator.mangler.is_some() {
+        // Mangled names are assigned in order of first use, so the files must
+        // be translated one at a time
+        for (file, mut part) in files.iter().zip(parts) {
+            part.mangler = translator.mangler.take();
+            translate_file(&mut part, file, options);
+            translator.mangler = part.mangler.take();
+            translator.append(part);
+        }
+    } else {
+        let parts: Vec<Translator> = files
+            .par_iter()
+            .zip(parts)
+            .map(|(file, part)| translate_part(file, part, options))
+            .collect();
+        for part in parts {
+            translator.append(part);
+        }
+    }
+    finish_translation(translator, options)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parser::{parse_instruction, ParsedVMInstruction};
-    use super::MemorySegment;
+    use super::parser::{parse_instruction, Constants, Extension, ParsedVMInstruction};
+    use super::Dialect;
+    use super::{
+        check_extensions, list_directory, parse_lines, parse_source, split_comment, translate,
+        translate_reader, AsmLine, MemorySegment, TranslateOptions, VMFile,
+    };
+    use crate::cache::{Decoder, Encoder};
+    use crate::emulator::Emulator;
+    use crate::target::Target;
 
     #[test]
     fn test_parse_valid_instruction() {
@@ -552,4 +1835,472 @@ mod tests {
     fn test_parse_invalid_push_instruction() {
         let _parsed_instruction = parse_instruction("push constant");
     }
+
+    #[test]
+    #[should_panic(expected = "Invalid label name: cmp$0")]
+    fn test_parse_invalid_label() {
+        let _parsed_instruction = parse_instruction("label cmp$0");
+    }
+
+    #[test]
+    fn test_parse_bundle() {
+        let bundle = "// shared notes\n// FILE: Main.vm\npush constant 1\n// FILE: Sys.vm\n\nadd\n";
+        let (files, is_bundle) = parse_lines("All.vm", bundle.as_bytes(), true);
+        assert!(is_bundle);
+        let sections: Vec<(&str, usize)> = files
+            .iter()
+            .map(|file| (file.name.as_str(), file.instructions[0].line))
+            .collect();
+        assert_eq!(sections, vec![("Main.vm", 1), ("Sys.vm", 2)]);
+        let (files, is_bundle) = parse_lines("Main.vm", "push constant 1\nadd\n".as_bytes(), true);
+        assert!(!is_bundle);
+        assert_eq!(files[0].instructions.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Bundle has two sections named Main.vm")]
+    fn test_parse_bundle_duplicate_section() {
+        let bundle = "// FILE: Main.vm\npush constant 1\n// FILE: Main.vm\nadd\n";
+        parse_lines("All.vm", bundle.as_bytes(), true);
+    }
+
+    #[test]
+    fn test_static_region_base() {
+        let mut target = Target::default();
+        target.layout.set_all("static_base=100");
+        let files = vec![
+            parse_source("A.vm", "push static 3\npop static 0\npush static 3\n"),
+            parse_source("B.vm", "push static 0\n"),
+        ];
+        let options = TranslateOptions {
+            target,
+            ..Default::default()
+        };
+        let asm = translate(&files, &options).asm;
+        let addresses: Vec<&str> = asm
+            .iter()
+            .filter(|line| line.starts_with("@1"))
+            .map(|line| line.as_ref())
+            .collect();
+        assert_eq!(addresses, vec!["@100", "@101", "@100", "@102"]);
+    }
+
+    #[test]
+    fn test_pinned_statics() {
+        let mut target = Target::default();
+        target.layout.pin_statics("A.3=17,B.0=24577");
+        let files = vec![
+            parse_source("A.vm", "push static 0\npop static 3\npush static 1\n"),
+            parse_source("B.vm", "push static 0\npush static 1\n"),
+        ];
+        let options = TranslateOptions {
+            target,
+            ..Default::default()
+        };
+        let asm = translate(&files, &options).asm;
+        let addresses: Vec<&str> = asm
+            .iter()
+            .filter(|line| line.starts_with("@1") || line.starts_with("@2"))
+            .map(|line| line.as_ref())
+            .collect();
+        // The others are allocated from 16, as by the assembler, skipping 17
+        assert_eq!(addresses, vec!["@16", "@17", "@18", "@24577", "@19"]);
+    }
+
+    #[test]
+    fn test_stable_symbols() {
+        let options = TranslateOptions {
+            stable_symbols: true,
+            ..Default::default()
+        };
+        let a = "function A.f 0\ncall A.g 0\nfunction A.g 0\ncall A.f 0\n";
+        let b = "function B.h 0\ncall A.f 0\n";
+        for (files, expected) in [
+            (vec![parse_source("B.vm", b)], vec!["(B.h$ret.0)"]),
+            (
+                vec![parse_source("A.vm", a), parse_source("B.vm", b)],
+                vec!["(A.f$ret.0)", "(A.g$ret.0)", "(B.h$ret.0)"],
+            ),
+        ] {
+            let return_labels: Vec<AsmLine> = translate(&files, &options)
+                .asm
+                .into_iter()
+                .filter(|line| line.starts_with('(') && line.contains("$ret."))
+                .collect();
+            assert_eq!(return_labels, expected);
+        }
+    }
+
+    #[test]
+    fn test_keep_comments() {
+        let file = parse_source(
+            "Main.vm",
+            "// Adds two numbers\npush constant 1 // first\npush constant 2\nadd\n// done\n",
+        );
+        let options = TranslateOptions {
+            keep_comments: true,
+            ..Default::default()
+        };
+        let asm = translate(&[file], &options).asm;
+        let comments: Vec<(usize, &str)> = asm
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.starts_with("//"))
+            .map(|(i, line)| (i, line.as_ref()))
+            .collect();
+        assert_eq!(
+            comments,
+            vec![(0, "// Adds two numbers"), (1, "// first"), (19, "// done")]
+        );
+        assert_eq!(asm.len(), 20);
+    }
+
+    #[test]
+    fn test_comparison_labels() {
+        let source = "push constant 1\npush constant 1\neq\npush constant 2\nlt\n";
+        let files = vec![parse_source("A.vm", source), parse_source("B.vm", source)];
+        let asm = translate(&files, &TranslateOptions::default()).asm;
+        let jump_targets: Vec<&str> = asm
+            .windows(2)
+            .filter(|lines| lines[1].starts_with("D;J"))
+            .map(|lines| lines[0].as_ref())
+            .collect();
+        assert_eq!(
+            jump_targets,
+            vec!["@A$cmp$0", "@A$cmp$1", "@B$cmp$0", "@B$cmp$1"]
+        );
+        assert!(asm.iter().any(|line| line == "(B$cmp$1)"));
+
+        // A function named after its file can have a label like the
+        // comparison's
+        let source = "function A 0\nlabel cmp.0\npush constant 1\npush constant 1\neq\n";
+        let asm = translate(
+            &[parse_source("A.vm", source)],
+            &TranslateOptions::default(),
+        )
+        .asm;
+        assert!(asm.iter().any(|line| line == "(A$cmp.0)"));
+        assert!(asm.iter().any(|line| line == "(A$cmp$0)"));
+    }
+
+    #[test]
+    fn test_stack_guard() {
+        let mut options = TranslateOptions::default();
+        options.instrument.set_all("stack-guard");
+        let files = [parse_source(
+            "Main.vm",
+            "function Main.f 3\npush constant 1\nreturn\n",
+        )];
+        let asm = translate(&files, &options).asm;
+        let checks = asm
+            .iter()
+            .filter(|line| *line == "@VM_STACK_OVERFLOW")
+            .count();
+        assert_eq!(checks, 1);
+        // The room for the locals is checked before they're pushed
+        let position = |line: &str| asm.iter().position(|l| l == line).unwrap();
+        assert!(position("@2045") < position("M=0"));
+        assert!(asm.ends_with(&[
+            AsmLine::from("(VM_HALTED)"),
+            AsmLine::from("@VM_HALTED"),
+            AsmLine::from("0;JMP")
+        ]));
+        options.instrument.set_all("stack-guard-pushes");
+        let asm = translate(&files, &options).asm;
+        let checks = asm
+            .iter()
+            .filter(|line| *line == "@VM_STACK_OVERFLOW")
+            .count();
+        assert_eq!(checks, 2);
+    }
+
+    #[test]
+    fn test_pointer_guard() {
+        let mut options = TranslateOptions::default();
+        options.instrument.set_all("pointer-guard");
+        let files = [parse_source(
+            "Main.vm",
+            "push this 1\npop that 0\npush local 0\n",
+        )];
+        let asm = translate(&files, &options).asm;
+        let checks = asm
+            .iter()
+            .filter(|line| *line == "@VM_NULL_POINTER")
+            .count();
+        assert_eq!(checks, 2);
+        assert!(asm.contains(&AsmLine::from("(VM_BAD_POINTER)")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Line 32768 is too large to trace")]
+    fn test_trace_line_out_of_range() {
+        let mut options = TranslateOptions::default();
+        options.instrument.set_all("trace");
+        let source = format!("{}push constant 1\n", "\n".repeat(32767));
+        translate(&[parse_source("Main.vm", &source)], &options);
+    }
+
+    #[test]
+    fn test_shift_extension() {
+        let files = [parse_source(
+            "Main.vm",
+            "push constant 3\nshiftleft\nshiftright\n",
+        )];
+        let mut options = TranslateOptions::default();
+        options.extensions.push(Extension::Shift);
+        let asm = translate(&files, &options).asm;
+        assert!(asm.ends_with(&[
+            AsmLine::from("@SP"),
+            AsmLine::from("A=M-1"),
+            AsmLine::from("M=M<<"),
+            AsmLine::from("@SP"),
+            AsmLine::from("A=M-1"),
+            AsmLine::from("M=M>>")
+        ]));
+    }
+
+    #[test]
+    fn test_trace_call() {
+        let files = [
+            parse_source("Sys.vm", "function Sys.init 0\ncall Main.f 0\n"),
+            parse_source(
+                "Main.vm",
+                "function Main.f 1\nlabel LOOP\npush constant 1\nbreak\ngoto LOOP\n",
+            ),
+        ];
+        let mut options = TranslateOptions {
+            bootstrap: true,
+            extensions: vec![Extension::Break],
+            ..Default::default()
+        };
+        options.instrument.set_all("trace");
+        let translation = translate(&files, &options);
+        let address = |name: &str| {
+            let symbols = translation.symbols.symbols();
+            let symbol = symbols
+                .iter()
+                .find(|symbol| symbol.name.as_str() == name)
+                .unwrap();
+            symbol.address.unwrap() as usize
+        };
+        // Each label is followed by the trace of its line, which runs when
+        // it's jumped to
+        let mut emulator = Emulator::new(&translation.asm, &Target::default());
+        for (label, line) in [("Main.f", 1), ("Main.f$LOOP", 2), ("Main.f$LOOP", 2)] {
+            let start = emulator.steps;
+            while emulator.pc != address(label) {
+                emulator.step();
+                assert!(emulator.steps < start + 1000);
+            }
+            emulator.run(4);
+            assert_eq!(emulator.ram[14], line);
+        }
+        // break generates no code to trace
+        let traces = (translation.asm.iter())
+            .filter(|line| *line == "@R14")
+            .count();
+        assert_eq!(traces, 6);
+    }
+
+    #[test]
+    fn test_halt_and_break() {
+        let files = [parse_source("Main.vm", "break\nhalt\nhalt 2\n")];
+        let options = TranslateOptions {
+            extensions: vec![Extension::Halt, Extension::Break],
+            ..Default::default()
+        };
+        let asm = translate(&files, &options).asm;
+        let asm: Vec<&str> = asm.iter().map(|line| line.as_ref()).collect();
+        assert_eq!(
+            asm,
+            [
+                "// @break",
+                "@0",
+                "D=A",
+                "@R15",
+                "M=D",
+                "(Main$halt$0)",
+                "@Main$halt$0",
+                "0;JMP",
+                "@2",
+                "D=A",
+                "@R15",
+                "M=D",
+                "(Main$halt$1)",
+                "@Main$halt$1",
+                "0;JMP"
+            ]
+        );
+        assert_eq!(files[0].instructions[1].instruction.to_string(), "halt 0");
+
+        // A label in a function named after its file doesn't clash with it
+        let files = [parse_source(
+            "Main.vm",
+            "function Main 0\nlabel halt.0\nhalt\n",
+        )];
+        let asm = translate(&files, &options).asm;
+        assert!(asm.iter().any(|line| line == "(Main$halt.0)"));
+        assert!(asm.iter().any(|line| line == "(Main$halt$0)"));
+    }
+
+    #[test]
+    #[should_panic(expected = "enable it with --extensions=shift")]
+    fn test_shift_rejected_by_default() {
+        let files = [parse_source("Main.vm", "push constant 3\nshiftleft\n")];
+        translate(&files, &TranslateOptions::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Main.vm:2: const WIDTH 32 is not a standard VM instruction")]
+    fn test_check_extensions() {
+        let files = [parse_source(
+            "Main.vm",
+            "push constant 1\nconst WIDTH 32\npush constant WIDTH\n",
+        )];
+        check_extensions(&files, &[Extension::Const]);
+        check_extensions(&files, &[]);
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut constants = Constants::default();
+        let source = [
+            "const WIDTH 32",
+            "const LAST WIDTH",
+            "push constant WIDTH",
+            "pop temp 7",
+        ];
+        let parsed: Vec<String> = source
+            .iter()
+            .map(|text| constants.parse(text).to_string())
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                "const WIDTH 32",
+                "const LAST 32",
+                "push constant 32",
+                "pop temp 7"
+            ]
+        );
+        assert_eq!(
+            constants.parse("push local LAST"),
+            ParsedVMInstruction::Push {
+                segment: MemorySegment::Local,
+                idx: 32
+            }
+        );
+        let files = [parse_source("Main.vm", "const ONE 1\npush constant ONE\n")];
+        let mut options = TranslateOptions::default();
+        options.extensions.push(Extension::Const);
+        assert_eq!(translate(&files, &options).asm[0], "@1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Constant WIDTH is already defined")]
+    fn test_constant_redefined() {
+        parse_source("Main.vm", "const WIDTH 32\nconst WIDTH 64\n");
+    }
+
+    #[test]
+    fn test_split_comment() {
+        assert_eq!(
+            split_comment("  push local 1 // a/b "),
+            (Some("push local 1"), Some("a/b"))
+        );
+        assert_eq!(split_comment("add/"), (Some("add/"), None));
+        assert_eq!(split_comment("//"), (None, None));
+        assert_eq!(split_comment(" \t"), (None, None));
+    }
+
+    #[test]
+    fn test_translate_stream() {
+        let source =
+            "// Adds\nfunction Main.f 1 // one local\npush static 0\npush constant 2\nlt\n\
+                      label L\nif-goto L\ncall Main.f 0\nreturn\n// done\n";
+        let options = TranslateOptions {
+            annotate: true,
+            keep_comments: true,
+            end_loop: true,
+            ..Default::default()
+        };
+        let mut output = vec![];
+        let dialect = Dialect::default();
+        translate_reader(
+            "Main.vm",
+            source.as_bytes(),
+            &mut output,
+            &options,
+            &dialect,
+        )
+        .unwrap();
+        let translation = translate(&[parse_source("Main.vm", source)], &options);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            translation.asm.join("\n")
+        );
+    }
+
+    #[test]
+    fn test_encode_file() {
+        let file = parse_source(
+            "Main.vm",
+            "// Main\nfunction Main.f 0 // f\n\nlabel L\nreturn\n",
+        );
+        let mut encoder = Encoder::default();
+        file.encode(&mut encoder);
+        let text = encoder.to_string();
+        let decoded = VMFile::decode(&mut Decoder::new(&text)).unwrap();
+        assert_eq!(decoded.static_base, "Main");
+        assert_eq!(decoded.instructions.len(), 3);
+        assert_eq!(decoded.instructions[1].line, 4);
+        assert_eq!(
+            decoded.comments().collect::<Vec<_>>(),
+            file.comments().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = std::env::temp_dir().join(format!("vm_translator_cache_{}", std::process::id()));
+        let files = vec![
+            parse_source("A.vm", "function A.f 1\npush static 0\ncall B.g 0\nreturn\n"),
+            parse_source(
+                "B.vm",
+                "function B.g 0\nlabel L\npush constant 1\npush constant 2\neq\npop static 1\ngoto L\n",
+            ),
+        ];
+        let uncached = translate(&files, &TranslateOptions::default());
+        let options = TranslateOptions {
+            cache: Some(dir.clone()),
+            ..Default::default()
+        };
+        // The first run fills the cache and the second reads from it
+        for _ in 0..2 {
+            let cached = translate(&files, &options);
+            assert_eq!(cached.asm, uncached.asm);
+            assert_eq!(cached.symbols.render(), uncached.symbols.render());
+            assert_eq!(
+                cached.source_map.to_json("A.asm").to_string(),
+                uncached.source_map.to_json("A.asm").to_string()
+            );
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_directory() {
+        let dir = std::env::temp_dir().join(format!("vm_translator_list_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["Sys.vm", "Main.vm", "notes.txt", "Array.vm"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let names: Vec<_> = list_directory(&dir)
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(names, ["Array.vm", "Main.vm", "Sys.vm"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }